@@ -1,40 +1,200 @@
+use crate::cli_tool::ToolError;
+use crate::session::SessionInfo;
 use chrono::{DateTime, Local, Utc};
+use std::collections::BTreeMap;
 use std::fs::{self, OpenOptions};
 use std::io::Write;
 use std::path::PathBuf;
+use std::time::Duration;
 use tracing::{debug, error, info};
 
 #[derive(Clone, Debug)]
 pub struct AuditJournal {
     directory: Option<PathBuf>,
+    // Fail closed: refuse to run tools that cannot be audited
+    required: bool,
 }
 
-#[derive(Debug, serde::Serialize)]
-struct AuditEntry {
-    timestamp: DateTime<Utc>,
-    tool_name: String,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    user: Option<String>,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    hostname: Option<String>,
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AuditStatus {
+    Success,
+    Failed,
+    // Refused by access control before running
+    Denied,
+    // Stopped because the client cancelled the call or its job
+    Cancelled,
+    // About to run; written first in required mode, and followed by the
+    // outcome in another entry with the same timestamp
+    Started,
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct AuditEntry {
+    pub timestamp: DateTime<Utc>,
+    pub tool_name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hostname: Option<String>,
+    // Older journals predate these fields, so they stay optional when reading
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub status: Option<AuditStatus>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub duration_ms: Option<u64>,
+    // MCP session that issued the call
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub session_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client_name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client_version: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub protocol_version: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub principal: Option<String>,
+    // Why the call was denied or stopped, or which rule the user confirmed it under
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+    // Set when the call was a pipeline step: "pipeline/step", or "pipeline/step[n]" in a foreach
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pipeline: Option<String>,
+    // Background job the call ran as
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub job_id: Option<String>,
+    // URI read, for a resources/read rather than a tool call
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub resource: Option<String>,
+    // Answered from the result cache without running the tool
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub cache_hit: bool,
+    // Values replaced in the output, by detector or pattern name
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub redactions: BTreeMap<String, usize>,
+}
+
+impl AuditEntry {
+    /// Start an entry for a tool call; the timestamp marks when the call began.
+    pub fn new(tool_name: &str) -> Self {
+        Self {
+            timestamp: Utc::now(),
+            tool_name: tool_name.to_string(),
+            user: std::env::var("USER").ok(),
+            hostname: hostname::get()
+                .ok()
+                .and_then(|h| h.to_str().map(|s| s.to_string())),
+            status: None,
+            duration_ms: None,
+            session_id: None,
+            client_name: None,
+            client_version: None,
+            protocol_version: None,
+            principal: None,
+            reason: None,
+            pipeline: None,
+            job_id: None,
+            resource: None,
+            cache_hit: false,
+            redactions: BTreeMap::new(),
+        }
+    }
+
+    pub fn with_session(mut self, session: &SessionInfo) -> Self {
+        self.session_id = Some(session.session_id.clone());
+        self.client_name = session.client_name.clone();
+        self.client_version = session.client_version.clone();
+        self.protocol_version = session.protocol_version.clone();
+        self.principal = session.principal_name().map(String::from);
+        self
+    }
+
+    pub fn with_reason(mut self, reason: &str) -> Self {
+        self.reason = Some(reason.to_string());
+        self
+    }
+
+    pub fn with_pipeline(mut self, step: &str) -> Self {
+        self.pipeline = Some(step.to_string());
+        self
+    }
+
+    pub fn with_job(mut self, job_id: &str) -> Self {
+        self.job_id = Some(job_id.to_string());
+        self
+    }
+
+    pub fn with_resource(mut self, uri: &str) -> Self {
+        self.resource = Some(uri.to_string());
+        self
+    }
+
+    pub fn with_cache_hit(mut self) -> Self {
+        self.cache_hit = true;
+        self
+    }
+
+    pub fn with_redactions(mut self, redactions: BTreeMap<String, usize>) -> Self {
+        self.redactions = redactions;
+        self
+    }
+
+    pub fn finish(mut self, status: AuditStatus, duration: Duration) -> Self {
+        self.status = Some(status);
+        self.duration_ms = Some(duration.as_millis() as u64);
+        self
+    }
+
+    /// Finish with the status a call's result implies, noting which limit
+    /// stopped it or why it was denied.
+    pub fn finish_result(self, result: &Result<String, ToolError>, duration: Duration) -> Self {
+        let (status, entry) = match result {
+            Ok(_) => (AuditStatus::Success, self),
+            Err(ToolError::LimitExceeded { limit, .. }) => (
+                AuditStatus::Failed,
+                self.with_reason(&format!("exceeded {} limit", limit)),
+            ),
+            Err(ToolError::Cancelled) => (AuditStatus::Cancelled, self),
+            Err(ToolError::Denied(denial)) => (AuditStatus::Denied, self.with_reason(&denial.reason)),
+            Err(ToolError::Failed(_)) => (AuditStatus::Failed, self),
+        };
+        entry.finish(status, duration)
+    }
 }
 
 impl AuditJournal {
-    pub fn new(directory: Option<PathBuf>) -> Self {
+    /// Open the journal. With `required` set, a missing directory or one that
+    /// cannot be created is an error instead of silently disabling auditing.
+    pub fn new(directory: Option<PathBuf>, required: bool) -> Result<Self, String> {
         if let Some(ref dir) = directory {
             // Create directory if it doesn't exist
             if let Err(e) = fs::create_dir_all(dir) {
-                error!("Failed to create audit directory {:?}: {}", dir, e);
-                return Self { directory: None };
+                let msg = format!("Failed to create audit directory {:?}: {}", dir, e);
+                if required {
+                    return Err(msg);
+                }
+                error!("{}", msg);
+                return Ok(Self {
+                    directory: None,
+                    required,
+                });
             }
             info!("Audit journal enabled in directory: {:?}", dir);
+        } else if required {
+            return Err("Audit logging is required but no audit directory was given".to_string());
         } else {
             debug!("Audit journal disabled");
         }
 
-        Self { directory }
+        let journal = Self {
+            directory,
+            required,
+        };
+        if required {
+            journal.check_writable()?;
+        }
+        Ok(journal)
     }
-    
+
     fn get_daily_file_path(&self) -> Option<PathBuf> {
         self.directory.as_ref().map(|dir| {
             let date = Local::now().format("%Y-%m-%d");
@@ -42,45 +202,125 @@ impl AuditJournal {
         })
     }
 
-    pub async fn log_tool_invocation(&self, tool_name: &str) {
+    /// Verify today's journal can be opened for appending, so a required
+    /// journal that can't be written fails at startup.
+    fn check_writable(&self) -> Result<(), String> {
         if let Some(file_path) = self.get_daily_file_path() {
-            let entry = AuditEntry {
-                timestamp: Utc::now(),
-                tool_name: tool_name.to_string(),
-                user: std::env::var("USER").ok(),
-                hostname: hostname::get()
-                    .ok()
-                    .and_then(|h| h.to_str().map(|s| s.to_string())),
-            };
+            OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&file_path)
+                .map_err(|e| format!("Failed to open audit file {:?}: {}", file_path, e))?;
+        }
+        Ok(())
+    }
+
+    /// In required mode, record that a call is about to run, so even a call
+    /// that never finishes leaves a trace. `Err` means it must not run.
+    pub async fn log_start(&self, entry: &AuditEntry) -> Result<(), String> {
+        if !self.required {
+            return Ok(());
+        }
+        let mut started = entry.clone();
+        started.status = Some(AuditStatus::Started);
+        self.log_tool_invocation(&started).await
+    }
 
+    pub async fn log_tool_invocation(&self, entry: &AuditEntry) -> Result<(), String> {
+        let result = self.write_entry(entry);
+        if let Err(ref e) = result {
+            error!("{}", e);
+        }
+        result
+    }
+
+    fn write_entry(&self, entry: &AuditEntry) -> Result<(), String> {
+        if let Some(file_path) = self.get_daily_file_path() {
             // Open file in append mode for each write (safer for concurrent access)
-            match OpenOptions::new()
+            let mut file = OpenOptions::new()
                 .create(true)
                 .append(true)
                 .open(&file_path)
-            {
-                Ok(mut file) => {
-                    match serde_json::to_string(&entry) {
-                        Ok(json) => {
-                            if let Err(e) = writeln!(file, "{}", json) {
-                                error!("Failed to write audit entry: {}", e);
-                            } else if let Err(e) = file.flush() {
-                                error!("Failed to flush audit journal: {}", e);
-                            }
-                        }
-                        Err(e) => {
-                            error!("Failed to serialize audit entry: {}", e);
-                        }
-                    }
-                }
-                Err(e) => {
-                    error!("Failed to open audit file {:?}: {}", file_path, e);
-                }
-            }
+                .map_err(|e| format!("Failed to open audit file {:?}: {}", file_path, e))?;
+            let json = serde_json::to_string(entry)
+                .map_err(|e| format!("Failed to serialize audit entry: {}", e))?;
+            writeln!(file, "{}", json).map_err(|e| format!("Failed to write audit entry: {}", e))?;
+            file.flush()
+                .map_err(|e| format!("Failed to flush audit journal: {}", e))?;
         }
+        Ok(())
     }
 
     pub fn is_enabled(&self) -> bool {
         self.directory.is_some()
     }
-}
\ No newline at end of file
+
+    pub fn is_required(&self) -> bool {
+        self.required
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_optional_journal_disables_on_bad_directory() {
+        let temp_dir = TempDir::new().unwrap();
+        let file = temp_dir.path().join("not-a-dir");
+        fs::write(&file, "").unwrap();
+
+        let journal = AuditJournal::new(Some(file.join("audit")), false).unwrap();
+        assert!(!journal.is_enabled());
+    }
+
+    #[test]
+    fn test_required_journal_refuses_bad_directory() {
+        let temp_dir = TempDir::new().unwrap();
+        let file = temp_dir.path().join("not-a-dir");
+        fs::write(&file, "").unwrap();
+
+        let result = AuditJournal::new(Some(file.join("audit")), true);
+        assert!(result.unwrap_err().contains("Failed to create audit directory"));
+
+        assert!(AuditJournal::new(None, true).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_required_journal_reports_write_failures() {
+        let temp_dir = TempDir::new().unwrap();
+        let dir = temp_dir.path().join("audit");
+        let journal = AuditJournal::new(Some(dir.clone()), true).unwrap();
+
+        let entry = AuditEntry::new("add").finish(AuditStatus::Success, Duration::from_millis(2));
+        assert!(journal.log_tool_invocation(&entry).await.is_ok());
+
+        // Replace the directory with a file so opening the journal fails
+        fs::remove_dir_all(&dir).unwrap();
+        fs::write(&dir, "").unwrap();
+        assert!(journal.check_writable().is_err());
+        assert!(journal.log_tool_invocation(&entry).await.is_err());
+        assert!(journal.log_start(&AuditEntry::new("add")).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_required_journal_records_calls_before_they_run() {
+        let temp_dir = TempDir::new().unwrap();
+        let entry = AuditEntry::new("add");
+
+        let journal = AuditJournal::new(Some(temp_dir.path().to_path_buf()), true).unwrap();
+        journal.log_start(&entry).await.unwrap();
+        let path = journal.get_daily_file_path().unwrap();
+        let logged: AuditEntry =
+            serde_json::from_str(fs::read_to_string(&path).unwrap().trim()).unwrap();
+        assert_eq!(logged.status, Some(AuditStatus::Started));
+        assert_eq!(logged.timestamp, entry.timestamp);
+
+        // Best-effort journals only record outcomes
+        fs::remove_file(&path).unwrap();
+        let journal = AuditJournal::new(Some(temp_dir.path().to_path_buf()), false).unwrap();
+        journal.log_start(&entry).await.unwrap();
+        assert!(!path.exists());
+    }
+}
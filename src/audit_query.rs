@@ -0,0 +1,479 @@
+use crate::audit::{AuditEntry, AuditStatus};
+use chrono::{DateTime, Duration, Local, NaiveDate, TimeZone, Utc};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+use tracing::warn;
+
+#[derive(Debug, Default)]
+pub struct AuditFilter {
+    pub since: Option<DateTime<Utc>>,
+    pub until: Option<DateTime<Utc>>,
+    pub tool: Option<String>,
+    pub user: Option<String>,
+    pub client: Option<String>,
+    pub session_id: Option<String>,
+    pub status: Option<AuditStatus>,
+}
+
+impl AuditFilter {
+    fn matches(&self, entry: &AuditEntry) -> bool {
+        if self.since.is_some_and(|since| entry.timestamp < since) {
+            return false;
+        }
+        if self.until.is_some_and(|until| entry.timestamp >= until) {
+            return false;
+        }
+        if self.tool.as_ref().is_some_and(|t| *t != entry.tool_name) {
+            return false;
+        }
+        if self
+            .user
+            .as_ref()
+            .is_some_and(|u| entry.user.as_ref() != Some(u))
+        {
+            return false;
+        }
+        if self
+            .client
+            .as_ref()
+            .is_some_and(|c| entry.client_name.as_ref() != Some(c))
+        {
+            return false;
+        }
+        if self
+            .session_id
+            .as_ref()
+            .is_some_and(|s| entry.session_id.as_ref() != Some(s))
+        {
+            return false;
+        }
+        if self.status.is_some() && entry.status != self.status {
+            return false;
+        }
+        true
+    }
+}
+
+#[derive(Debug, PartialEq, serde::Serialize)]
+pub struct ToolStats {
+    pub tool_name: String,
+    pub calls: usize,
+    pub failures: usize,
+    pub failure_rate: f64,
+    pub denied: usize,
+    pub cancelled: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub p50_ms: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub p95_ms: Option<u64>,
+}
+
+/// Parse a `--since`/`--until` value: RFC 3339, a local date (`2024-01-25`),
+/// `today`, `yesterday`, or a relative age such as `30m`, `12h` or `7d`.
+pub fn parse_time(value: &str) -> Result<DateTime<Utc>, String> {
+    let value = value.trim();
+    let today = Local::now().date_naive();
+
+    match value {
+        "today" => return local_midnight(today),
+        "yesterday" => return local_midnight(today - Duration::days(1)),
+        _ => {}
+    }
+
+    if let Ok(ts) = DateTime::parse_from_rfc3339(value) {
+        return Ok(ts.with_timezone(&Utc));
+    }
+    if let Ok(date) = NaiveDate::parse_from_str(value, "%Y-%m-%d") {
+        return local_midnight(date);
+    }
+
+    let age = parse_age(value).map_err(|_| format!("Invalid time '{}'", value))?;
+    Utc::now()
+        .checked_sub_signed(age)
+        .ok_or_else(|| format!("Invalid time '{}'", value))
+}
+
+/// Parse an age such as `30s`, `30m`, `12h` or `7d`.
+pub fn parse_age(value: &str) -> Result<Duration, String> {
+    let value = value.trim();
+    let invalid = || format!("Invalid duration '{}'", value);
+    let (split, unit) = value.char_indices().last().ok_or_else(invalid)?;
+    let amount: i64 = value[..split].parse().map_err(|_| invalid())?;
+    if amount < 0 {
+        return Err(invalid());
+    }
+    let age = match unit {
+        's' => Duration::try_seconds(amount),
+        'm' => Duration::try_minutes(amount),
+        'h' => Duration::try_hours(amount),
+        'd' => Duration::try_days(amount),
+        _ => None,
+    };
+    age.ok_or_else(invalid)
+}
+
+fn local_midnight(date: NaiveDate) -> Result<DateTime<Utc>, String> {
+    Local
+        .from_local_datetime(&date.and_hms_opt(0, 0, 0).unwrap())
+        .earliest()
+        .map(|ts| ts.with_timezone(&Utc))
+        .ok_or_else(|| format!("Invalid local date {}", date))
+}
+
+pub fn parse_status(value: &str) -> Result<AuditStatus, String> {
+    match value {
+        "success" | "ok" => Ok(AuditStatus::Success),
+        "failed" | "failure" | "error" => Ok(AuditStatus::Failed),
+        "denied" => Ok(AuditStatus::Denied),
+        "cancelled" | "canceled" => Ok(AuditStatus::Cancelled),
+        "started" => Ok(AuditStatus::Started),
+        _ => Err(format!(
+            "Unknown status '{}' (expected success, failed, denied, cancelled or started)",
+            value
+        )),
+    }
+}
+
+/// Read every matching entry from the daily journals in `dir`, oldest first.
+pub fn read_entries(dir: &Path, filter: &AuditFilter) -> Result<Vec<AuditEntry>, String> {
+    let read_dir = fs::read_dir(dir)
+        .map_err(|e| format!("Failed to read audit directory {:?}: {}", dir, e))?;
+
+    let mut files: Vec<(NaiveDate, std::path::PathBuf)> = read_dir
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| {
+            let name = entry.file_name();
+            let date = name
+                .to_str()?
+                .strip_prefix("audit-")?
+                .strip_suffix(".jsonl")?
+                .to_string();
+            let date = NaiveDate::parse_from_str(&date, "%Y-%m-%d").ok()?;
+            Some((date, entry.path()))
+        })
+        .collect();
+    files.sort();
+
+    // Files are named by local date, so allow a day of slack around the bounds
+    let first_day = filter.since.map(|t| t.date_naive() - Duration::days(1));
+    let last_day = filter.until.map(|t| t.date_naive() + Duration::days(1));
+
+    let mut entries = Vec::new();
+    for (date, path) in files {
+        if first_day.is_some_and(|d| date < d) || last_day.is_some_and(|d| date > d) {
+            continue;
+        }
+
+        let content = fs::read_to_string(&path)
+            .map_err(|e| format!("Failed to read audit file {:?}: {}", path, e))?;
+        for (line_no, line) in content.lines().enumerate() {
+            if line.trim().is_empty() {
+                continue;
+            }
+            match serde_json::from_str::<AuditEntry>(line) {
+                Ok(entry) if filter.matches(&entry) => entries.push(entry),
+                Ok(_) => {}
+                Err(e) => warn!("Skipping malformed line {:?}:{}: {}", path, line_no + 1, e),
+            }
+        }
+    }
+
+    entries.sort_by_key(|e| e.timestamp);
+    Ok(entries)
+}
+
+pub fn compute_stats(entries: &[AuditEntry]) -> Vec<ToolStats> {
+    let mut by_tool: BTreeMap<&str, Vec<&AuditEntry>> = BTreeMap::new();
+    // A started call is counted by its outcome entry
+    for entry in entries.iter().filter(|e| e.status != Some(AuditStatus::Started)) {
+        by_tool.entry(&entry.tool_name).or_default().push(entry);
+    }
+
+    by_tool
+        .into_iter()
+        .map(|(tool_name, calls)| {
+            let failures = calls
+                .iter()
+                .filter(|e| e.status == Some(AuditStatus::Failed))
+                .count();
+            let denied = calls
+                .iter()
+                .filter(|e| e.status == Some(AuditStatus::Denied))
+                .count();
+            let cancelled = calls
+                .iter()
+                .filter(|e| e.status == Some(AuditStatus::Cancelled))
+                .count();
+            // Denied calls never ran and cancelled ones were cut short, so
+            // they'd skew the latencies
+            let mut durations: Vec<u64> = calls
+                .iter()
+                .filter(|e| !matches!(e.status, Some(AuditStatus::Denied | AuditStatus::Cancelled)))
+                .filter_map(|e| e.duration_ms)
+                .collect();
+            durations.sort_unstable();
+
+            ToolStats {
+                tool_name: tool_name.to_string(),
+                calls: calls.len(),
+                failures,
+                failure_rate: failures as f64 / calls.len() as f64,
+                denied,
+                cancelled,
+                p50_ms: percentile(&durations, 50.0),
+                p95_ms: percentile(&durations, 95.0),
+            }
+        })
+        .collect()
+}
+
+// Nearest-rank percentile over an already sorted slice
+fn percentile(sorted: &[u64], pct: f64) -> Option<u64> {
+    if sorted.is_empty() {
+        return None;
+    }
+    let rank = ((pct / 100.0) * sorted.len() as f64).ceil() as usize;
+    Some(sorted[rank.clamp(1, sorted.len()) - 1])
+}
+
+pub fn render_entries_table(entries: &[AuditEntry]) -> String {
+    let rows: Vec<Vec<String>> = entries
+        .iter()
+        .map(|e| {
+            vec![
+                e.timestamp
+                    .with_timezone(&Local)
+                    .format("%Y-%m-%d %H:%M:%S")
+                    .to_string(),
+                e.tool_name.clone(),
+                e.status.map(status_label).unwrap_or("-").to_string(),
+                e.duration_ms
+                    .map(|d| d.to_string())
+                    .unwrap_or_else(|| "-".to_string()),
+                e.user.clone().unwrap_or_else(|| "-".to_string()),
+                e.hostname.clone().unwrap_or_else(|| "-".to_string()),
+                e.client_name.clone().unwrap_or_else(|| "-".to_string()),
+                e.session_id
+                    .as_deref()
+                    .map(|s| s.chars().take(8).collect())
+                    .unwrap_or_else(|| "-".to_string()),
+            ]
+        })
+        .collect();
+
+    render_table(
+        &[
+            "TIME",
+            "TOOL",
+            "STATUS",
+            "DURATION_MS",
+            "USER",
+            "HOST",
+            "CLIENT",
+            "SESSION",
+        ],
+        &rows,
+    )
+}
+
+pub fn render_stats_table(stats: &[ToolStats]) -> String {
+    let rows: Vec<Vec<String>> = stats
+        .iter()
+        .map(|s| {
+            vec![
+                s.tool_name.clone(),
+                s.calls.to_string(),
+                s.failures.to_string(),
+                format!("{:.1}%", s.failure_rate * 100.0),
+                s.denied.to_string(),
+                s.cancelled.to_string(),
+                s.p50_ms
+                    .map(|d| d.to_string())
+                    .unwrap_or_else(|| "-".to_string()),
+                s.p95_ms
+                    .map(|d| d.to_string())
+                    .unwrap_or_else(|| "-".to_string()),
+            ]
+        })
+        .collect();
+
+    render_table(
+        &["TOOL", "CALLS", "FAILED", "FAIL_RATE", "DENIED", "CANCELLED", "P50_MS", "P95_MS"],
+        &rows,
+    )
+}
+
+fn status_label(status: AuditStatus) -> &'static str {
+    match status {
+        AuditStatus::Success => "success",
+        AuditStatus::Failed => "failed",
+        AuditStatus::Denied => "denied",
+        AuditStatus::Cancelled => "cancelled",
+        AuditStatus::Started => "started",
+    }
+}
+
+fn render_table(headers: &[&str], rows: &[Vec<String>]) -> String {
+    let mut widths: Vec<usize> = headers.iter().map(|h| h.len()).collect();
+    for row in rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.len());
+        }
+    }
+
+    let format_row = |cells: Vec<&str>| {
+        cells
+            .iter()
+            .zip(&widths)
+            .map(|(cell, width)| format!("{:<width$}", cell, width = width))
+            .collect::<Vec<_>>()
+            .join("  ")
+            .trim_end()
+            .to_string()
+    };
+
+    let mut lines = vec![format_row(headers.to_vec())];
+    for row in rows {
+        lines.push(format_row(row.iter().map(|c| c.as_str()).collect()));
+    }
+    lines.join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use tempfile::TempDir;
+
+    fn entry(ts: &str, tool: &str, status: AuditStatus, duration_ms: u64) -> AuditEntry {
+        AuditEntry {
+            timestamp: DateTime::parse_from_rfc3339(ts).unwrap().with_timezone(&Utc),
+            user: Some("jsmith".to_string()),
+            hostname: Some("lab-desktop-03".to_string()),
+            status: Some(status),
+            duration_ms: Some(duration_ms),
+            ..AuditEntry::new(tool)
+        }
+    }
+
+    fn write_journal(dir: &Path, date: &str, entries: &[AuditEntry]) {
+        let mut file = fs::File::create(dir.join(format!("audit-{}.jsonl", date))).unwrap();
+        for e in entries {
+            writeln!(file, "{}", serde_json::to_string(e).unwrap()).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_read_entries_filters() {
+        let temp_dir = TempDir::new().unwrap();
+        write_journal(
+            temp_dir.path(),
+            "2024-01-25",
+            &[
+                entry("2024-01-25T10:00:00Z", "add", AuditStatus::Success, 3),
+                entry("2024-01-25T11:00:00Z", "list_files", AuditStatus::Failed, 12),
+            ],
+        );
+        write_journal(
+            temp_dir.path(),
+            "2024-01-26",
+            &[entry("2024-01-26T09:00:00Z", "add", AuditStatus::Failed, 5)],
+        );
+        // Lines written before status/duration were recorded still parse
+        fs::OpenOptions::new()
+            .append(true)
+            .open(temp_dir.path().join("audit-2024-01-26.jsonl"))
+            .unwrap()
+            .write_all(b"{\"timestamp\":\"2024-01-26T10:00:00Z\",\"tool_name\":\"add\"}\nnot json\n")
+            .unwrap();
+
+        let all = read_entries(temp_dir.path(), &AuditFilter::default()).unwrap();
+        assert_eq!(all.len(), 4);
+
+        let filter = AuditFilter {
+            tool: Some("add".to_string()),
+            status: Some(AuditStatus::Failed),
+            ..Default::default()
+        };
+        let failed_adds = read_entries(temp_dir.path(), &filter).unwrap();
+        assert_eq!(failed_adds.len(), 1);
+        assert_eq!(failed_adds[0].duration_ms, Some(5));
+
+        let filter = AuditFilter {
+            client: Some("claude-code".to_string()),
+            ..Default::default()
+        };
+        assert!(read_entries(temp_dir.path(), &filter).unwrap().is_empty());
+
+        let filter = AuditFilter {
+            since: Some(parse_time("2024-01-25T10:30:00Z").unwrap()),
+            until: Some(parse_time("2024-01-26T09:30:00Z").unwrap()),
+            ..Default::default()
+        };
+        let window = read_entries(temp_dir.path(), &filter).unwrap();
+        let tools: Vec<&str> = window.iter().map(|e| e.tool_name.as_str()).collect();
+        assert_eq!(tools, vec!["list_files", "add"]);
+    }
+
+    #[test]
+    fn test_compute_stats() {
+        let mut entries: Vec<AuditEntry> = (1..=20)
+            .map(|ms| entry("2024-01-25T10:00:00Z", "add", AuditStatus::Success, ms))
+            .collect();
+        entries[0].status = Some(AuditStatus::Failed);
+        entries.push(entry(
+            "2024-01-25T10:00:00Z",
+            "list_files",
+            AuditStatus::Failed,
+            40,
+        ));
+
+        let stats = compute_stats(&entries);
+        assert_eq!(stats.len(), 2);
+        assert_eq!(stats[0].tool_name, "add");
+        assert_eq!(stats[0].calls, 20);
+        assert_eq!(stats[0].failures, 1);
+        assert_eq!(stats[0].failure_rate, 0.05);
+        assert_eq!(stats[0].p50_ms, Some(10));
+        assert_eq!(stats[0].p95_ms, Some(19));
+        assert_eq!(stats[1].failure_rate, 1.0);
+        assert_eq!(stats[1].p95_ms, Some(40));
+    }
+
+    #[test]
+    fn test_parse_time() {
+        assert!(parse_time("2024-01-25T10:30:00Z").is_ok());
+        assert!(parse_time("2024-01-25").is_ok());
+        assert!(parse_time("yesterday").unwrap() < parse_time("today").unwrap());
+
+        let hour_ago = parse_time("1h").unwrap();
+        let age = Utc::now() - hour_ago;
+        assert!(age >= Duration::minutes(59) && age <= Duration::minutes(61));
+
+        assert!(parse_time("soon").is_err());
+        assert!(parse_time("5w").is_err());
+        assert!(parse_time("5µ").is_err());
+        assert!(parse_time("-1h").is_err());
+        assert!(parse_time("99999999999999d").is_err());
+        assert!(parse_time("100000000d").is_err());
+    }
+
+    #[test]
+    fn test_render_stats_table() {
+        let stats = vec![ToolStats {
+            tool_name: "add".to_string(),
+            calls: 2,
+            failures: 1,
+            failure_rate: 0.5,
+            denied: 0,
+            cancelled: 0,
+            p50_ms: Some(3),
+            p95_ms: None,
+        }];
+        let table = render_stats_table(&stats);
+        let lines: Vec<&str> = table.lines().collect();
+        assert_eq!(lines[0], "TOOL  CALLS  FAILED  FAIL_RATE  DENIED  CANCELLED  P50_MS  P95_MS");
+        assert_eq!(lines[1], "add   2      1       50.0%      0       0          3       -");
+    }
+}
@@ -0,0 +1,437 @@
+use crate::tls::TlsPeer;
+use axum::extract::{ConnectInfo, Request, State};
+use axum::http::{HeaderMap, StatusCode, header};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use jsonwebtoken::jwk::{AlgorithmParameters, Jwk, JwkSet};
+use jsonwebtoken::{Algorithm, DecodingKey, Validation};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tracing::{debug, warn};
+
+/// Authenticated caller of a network transport.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Principal {
+    pub name: String,
+    pub method: AuthMethod,
+    pub groups: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuthMethod {
+    Token,
+    Mtls,
+    Jwt,
+}
+
+/// Contents of the `--auth-config` file.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AuthConfig {
+    #[serde(default)]
+    pub tokens: Vec<TokenEntry>,
+    pub tls: Option<TlsSettings>,
+    pub jwt: Option<JwtSettings>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TokenEntry {
+    pub principal: String,
+    // Hex SHA-256 of the token; the token itself is never stored
+    pub sha256: String,
+    #[serde(default)]
+    pub groups: Vec<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TlsSettings {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+    // CA bundle for verifying client certificates; enables mTLS
+    pub client_ca: Option<PathBuf>,
+    // Reject TLS handshakes without a client certificate
+    #[serde(default)]
+    pub require_client_cert: bool,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct JwtSettings {
+    pub jwks: PathBuf,
+    pub issuer: Option<String>,
+    pub audience: Option<String>,
+    #[serde(default = "default_principal_claim")]
+    pub principal_claim: String,
+    pub groups_claim: Option<String>,
+    #[serde(default = "default_leeway")]
+    pub leeway_secs: u64,
+}
+
+fn default_principal_claim() -> String {
+    "sub".to_string()
+}
+
+fn default_leeway() -> u64 {
+    60
+}
+
+impl AuthConfig {
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read auth config {:?}: {}", path, e))?;
+        serde_yaml::from_str(&content)
+            .map_err(|e| format!("Failed to parse auth config {:?}: {}", path, e))
+    }
+}
+
+/// Checks the credentials of incoming HTTP requests.
+pub struct Authenticator {
+    tokens: Vec<([u8; 32], Principal)>,
+    jwt: Option<JwtValidator>,
+}
+
+struct JwtValidator {
+    keys: JwkSet,
+    settings: JwtSettings,
+}
+
+impl Authenticator {
+    pub fn new(config: AuthConfig) -> Result<Self, String> {
+        let mut tokens = Vec::new();
+        for entry in config.tokens {
+            let hash = hex::decode(entry.sha256.trim())
+                .ok()
+                .and_then(|h| <[u8; 32]>::try_from(h).ok())
+                .ok_or_else(|| {
+                    format!(
+                        "Token for '{}' is not a hex-encoded SHA-256 hash",
+                        entry.principal
+                    )
+                })?;
+            tokens.push((
+                hash,
+                Principal {
+                    name: entry.principal,
+                    method: AuthMethod::Token,
+                    groups: entry.groups,
+                },
+            ));
+        }
+
+        let jwt = match config.jwt {
+            Some(settings) => {
+                let content = std::fs::read_to_string(&settings.jwks)
+                    .map_err(|e| format!("Failed to read JWKS {:?}: {}", settings.jwks, e))?;
+                let keys: JwkSet = serde_json::from_str(&content)
+                    .map_err(|e| format!("Failed to parse JWKS {:?}: {}", settings.jwks, e))?;
+                if keys.keys.is_empty() {
+                    return Err(format!("JWKS {:?} contains no keys", settings.jwks));
+                }
+                Some(JwtValidator { keys, settings })
+            }
+            None => None,
+        };
+
+        Ok(Self { tokens, jwt })
+    }
+
+    /// Identify the caller from a verified client certificate or a bearer
+    /// token, which may be a static token or a JWT.
+    pub fn authenticate(
+        &self,
+        headers: &HeaderMap,
+        client_cert: Option<&Principal>,
+    ) -> Result<Principal, String> {
+        if let Some(principal) = client_cert {
+            return Ok(principal.clone());
+        }
+
+        let token = headers
+            .get(header::AUTHORIZATION)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.strip_prefix("Bearer "))
+            .map(str::trim)
+            .ok_or("missing credentials")?;
+
+        if let Some(principal) = self.check_token(token) {
+            return Ok(principal);
+        }
+        match &self.jwt {
+            Some(jwt) if token.split('.').count() == 3 => jwt.validate(token),
+            _ => Err("unknown bearer token".to_string()),
+        }
+    }
+
+    fn check_token(&self, token: &str) -> Option<Principal> {
+        let hash: [u8; 32] = Sha256::digest(token.as_bytes()).into();
+        // Compare every entry without short-circuiting on the bytes
+        let mut found = None;
+        for (expected, principal) in &self.tokens {
+            let diff = expected
+                .iter()
+                .zip(hash.iter())
+                .fold(0u8, |acc, (a, b)| acc | (a ^ b));
+            if diff == 0 {
+                found = Some(principal.clone());
+            }
+        }
+        found
+    }
+}
+
+impl JwtValidator {
+    fn validate(&self, token: &str) -> Result<Principal, String> {
+        let header =
+            jsonwebtoken::decode_header(token).map_err(|e| format!("invalid JWT: {}", e))?;
+        let jwk = match &header.kid {
+            Some(kid) => self
+                .keys
+                .find(kid)
+                .ok_or_else(|| format!("JWT key id '{}' is not in the JWKS", kid))?,
+            None if self.keys.keys.len() == 1 => &self.keys.keys[0],
+            None => return Err("JWT has no key id and the JWKS has several keys".to_string()),
+        };
+        if !allowed_algorithms(jwk).contains(&header.alg) {
+            return Err(format!("JWT algorithm {:?} does not match its key", header.alg));
+        }
+
+        let key = DecodingKey::from_jwk(jwk).map_err(|e| format!("unusable JWK: {}", e))?;
+        let mut validation = Validation::new(header.alg);
+        validation.leeway = self.settings.leeway_secs;
+        validation.set_required_spec_claims(&["exp"]);
+        if let Some(issuer) = &self.settings.issuer {
+            validation.set_issuer(&[issuer]);
+        }
+        match &self.settings.audience {
+            Some(audience) => validation.set_audience(&[audience]),
+            None => validation.validate_aud = false,
+        }
+
+        let claims = jsonwebtoken::decode::<serde_json::Value>(token, &key, &validation)
+            .map_err(|e| format!("JWT rejected: {}", e))?
+            .claims;
+        let name = claims
+            .get(&self.settings.principal_claim)
+            .and_then(|v| v.as_str())
+            .ok_or_else(|| format!("JWT has no '{}' claim", self.settings.principal_claim))?;
+        let groups = match self
+            .settings
+            .groups_claim
+            .as_ref()
+            .and_then(|c| claims.get(c))
+        {
+            Some(serde_json::Value::Array(values)) => values
+                .iter()
+                .filter_map(|v| v.as_str().map(String::from))
+                .collect(),
+            // OAuth scopes are a space-separated string
+            Some(serde_json::Value::String(s)) => {
+                s.split_whitespace().map(String::from).collect()
+            }
+            _ => Vec::new(),
+        };
+
+        Ok(Principal {
+            name: name.to_string(),
+            method: AuthMethod::Jwt,
+            groups,
+        })
+    }
+}
+
+/// Algorithms a JWK may verify. A key's own `alg` wins; otherwise anything in
+/// its family, so an RSA public key can never be used as an HMAC secret.
+fn allowed_algorithms(jwk: &Jwk) -> Vec<Algorithm> {
+    if let Some(alg) = jwk.common.key_algorithm {
+        // Encryption algorithms like RSA-OAEP don't parse and allow nothing
+        return alg.to_string().parse::<Algorithm>().into_iter().collect();
+    }
+    match &jwk.algorithm {
+        AlgorithmParameters::EllipticCurve(_) => vec![Algorithm::ES256, Algorithm::ES384],
+        AlgorithmParameters::RSA(_) => vec![
+            Algorithm::RS256,
+            Algorithm::RS384,
+            Algorithm::RS512,
+            Algorithm::PS256,
+            Algorithm::PS384,
+            Algorithm::PS512,
+        ],
+        AlgorithmParameters::OctetKeyPair(_) => vec![Algorithm::EdDSA],
+        AlgorithmParameters::OctetKey(_) => {
+            vec![Algorithm::HS256, Algorithm::HS384, Algorithm::HS512]
+        }
+    }
+}
+
+/// Hex SHA-256 of a bearer token, as stored in the auth config.
+pub fn hash_token(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+/// A new random bearer token.
+pub fn generate_token() -> String {
+    let bytes: [u8; 32] = rand::random();
+    format!("gcm_{}", hex::encode(bytes))
+}
+
+/// Axum middleware rejecting unauthenticated requests. The principal is
+/// stored in the request extensions, where rmcp hands it to the server.
+pub async fn require_auth(
+    State(auth): State<Arc<Authenticator>>,
+    mut req: Request,
+    next: Next,
+) -> Response {
+    let client_cert = req
+        .extensions()
+        .get::<ConnectInfo<TlsPeer>>()
+        .and_then(|info| info.0.principal.clone());
+    match auth.authenticate(req.headers(), client_cert.as_ref()) {
+        Ok(principal) => {
+            debug!("Authenticated {} via {:?}", principal.name, principal.method);
+            req.extensions_mut().insert(principal);
+            next.run(req).await
+        }
+        Err(e) => {
+            warn!("Rejected {} {}: {}", req.method(), req.uri().path(), e);
+            (
+                StatusCode::UNAUTHORIZED,
+                [(header::WWW_AUTHENTICATE, "Bearer")],
+                "Unauthorized\n",
+            )
+                .into_response()
+        }
+    }
+}
+
+/// Principal attached to an MCP request that arrived over HTTP.
+pub fn request_principal(extensions: &rmcp::model::Extensions) -> Option<Principal> {
+    extensions
+        .get::<axum::http::request::Parts>()
+        .and_then(|parts| parts.extensions.get::<Principal>())
+        .cloned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::Router;
+    use axum::body::Body;
+    use axum::routing::get;
+    use tower::ServiceExt;
+
+    fn whoami_app(auth: Authenticator) -> Router {
+        Router::new()
+            .route(
+                "/whoami",
+                get(|axum::Extension(p): axum::Extension<Principal>| async move {
+                    format!("{} {}", p.name, p.groups.join(","))
+                }),
+            )
+            .layer(axum::middleware::from_fn_with_state(
+                Arc::new(auth),
+                require_auth,
+            ))
+    }
+
+    async fn get_whoami(app: &Router, bearer: Option<&str>) -> (StatusCode, String) {
+        let mut req = Request::builder().uri("/whoami");
+        if let Some(token) = bearer {
+            req = req.header(header::AUTHORIZATION, format!("Bearer {}", token));
+        }
+        let res = app
+            .clone()
+            .oneshot(req.body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        let status = res.status();
+        let body = axum::body::to_bytes(res.into_body(), 1024).await.unwrap();
+        (status, String::from_utf8_lossy(&body).to_string())
+    }
+
+    #[tokio::test]
+    async fn test_static_bearer_tokens() {
+        let token = generate_token();
+        let config: AuthConfig = serde_yaml::from_str(&format!(
+            "tokens:\n  - principal: ci-bot\n    sha256: {}\n    groups: [ci]\n",
+            hash_token(&token)
+        ))
+        .unwrap();
+        let app = whoami_app(Authenticator::new(config).unwrap());
+
+        assert_eq!(
+            get_whoami(&app, Some(&token)).await,
+            (StatusCode::OK, "ci-bot ci".to_string())
+        );
+        assert_eq!(get_whoami(&app, Some("nope")).await.0, StatusCode::UNAUTHORIZED);
+        assert_eq!(get_whoami(&app, None).await.0, StatusCode::UNAUTHORIZED);
+    }
+
+    #[test]
+    fn test_rejects_malformed_token_hash() {
+        let config: AuthConfig =
+            serde_yaml::from_str("tokens:\n  - principal: x\n    sha256: secret\n").unwrap();
+        assert!(Authenticator::new(config).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_jwt_against_local_jwks() {
+        use base64::Engine;
+        use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+        use jsonwebtoken::{EncodingKey, Header};
+
+        let key_pair = rcgen::KeyPair::generate_for(&rcgen::PKCS_ECDSA_P256_SHA256).unwrap();
+        // Uncompressed point: 0x04 || x || y
+        let point = key_pair.public_key_raw();
+        let jwks = serde_json::json!({"keys": [{
+            "kty": "EC",
+            "crv": "P-256",
+            "kid": "test-key",
+            "x": URL_SAFE_NO_PAD.encode(&point[1..33]),
+            "y": URL_SAFE_NO_PAD.encode(&point[33..]),
+        }]});
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let jwks_path = temp_dir.path().join("jwks.json");
+        std::fs::write(&jwks_path, jwks.to_string()).unwrap();
+
+        let config: AuthConfig = serde_yaml::from_str(&format!(
+            "jwt:\n  jwks: {}\n  issuer: https://idp.test\n  audience: gamecode\n  groups_claim: groups\n",
+            jwks_path.display()
+        ))
+        .unwrap();
+        let app = whoami_app(Authenticator::new(config).unwrap());
+
+        let signing_key = EncodingKey::from_ec_der(&key_pair.serialize_der());
+        let sign = |claims: serde_json::Value| {
+            let mut header = Header::new(Algorithm::ES256);
+            header.kid = Some("test-key".to_string());
+            jsonwebtoken::encode(&header, &claims, &signing_key).unwrap()
+        };
+        let exp = chrono::Utc::now().timestamp() + 300;
+
+        let valid = sign(serde_json::json!({
+            "sub": "alice", "iss": "https://idp.test", "aud": "gamecode",
+            "exp": exp, "groups": ["dev", "ops"],
+        }));
+        assert_eq!(
+            get_whoami(&app, Some(&valid)).await,
+            (StatusCode::OK, "alice dev,ops".to_string())
+        );
+
+        let wrong_audience = sign(serde_json::json!({
+            "sub": "alice", "iss": "https://idp.test", "aud": "other", "exp": exp,
+        }));
+        assert_eq!(
+            get_whoami(&app, Some(&wrong_audience)).await.0,
+            StatusCode::UNAUTHORIZED
+        );
+
+        let expired = sign(serde_json::json!({
+            "sub": "alice", "iss": "https://idp.test", "aud": "gamecode", "exp": exp - 3600,
+        }));
+        assert_eq!(get_whoami(&app, Some(&expired)).await.0, StatusCode::UNAUTHORIZED);
+    }
+}
@@ -0,0 +1,258 @@
+//! Cached results for idempotent tools.
+//!
+//! A tool marked `idempotent: true` with a `cache:` block returns its last
+//! result for the same parameters until the TTL runs out. Results are kept
+//! in memory, least recently used first out, and with a cache directory
+//! also on disk so they survive a restart. What is stored is the output as
+//! returned to the client, after redaction.
+
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Deserializer, Serialize};
+use serde_json::{Value, json};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use tracing::warn;
+
+// Entries kept in memory before the least recently used is dropped
+const DEFAULT_CAPACITY: usize = 1024;
+
+/// The parameter that skips the cache for one call.
+pub const BYPASS_PARAM: &str = "cache_bypass";
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CacheConfig {
+    #[serde(deserialize_with = "ttl")]
+    pub ttl: Duration,
+    // Server environment variables that change the result, e.g. KUBECONFIG
+    #[serde(default)]
+    pub env: Vec<String>,
+    // Whether the server's working directory changes the result
+    #[serde(default)]
+    pub cwd: bool,
+}
+
+fn ttl<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Ttl {
+        Seconds(i64),
+        Text(String),
+    }
+    let ttl = match Ttl::deserialize(deserializer)? {
+        Ttl::Seconds(secs) => Duration::try_seconds(secs)
+            .ok_or_else(|| serde::de::Error::custom("cache ttl is out of range"))?,
+        Ttl::Text(text) => {
+            crate::audit_query::parse_age(&text).map_err(serde::de::Error::custom)?
+        }
+    };
+    if ttl <= Duration::zero() {
+        return Err(serde::de::Error::custom("cache ttl must be positive"));
+    }
+    Ok(ttl)
+}
+
+impl CacheConfig {
+    /// The cache key for a call: the tool, its parameters and whatever
+    /// server state the config says the result depends on.
+    pub fn key(&self, tool: &str, params: &HashMap<String, Value>) -> String {
+        // serde_json objects keep keys sorted, which makes this canonical
+        let env: serde_json::Map<String, Value> = self
+            .env
+            .iter()
+            .map(|name| (name.clone(), json!(std::env::var(name).ok())))
+            .collect();
+        let cwd = self.cwd.then(|| std::env::current_dir().ok()).flatten();
+        let identity = json!({ "tool": tool, "params": params, "env": env, "cwd": cwd });
+        hex::encode(Sha256::digest(identity.to_string()))
+    }
+}
+
+/// A cached result.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Cached {
+    pub tool: String,
+    pub output: String,
+    pub stored: DateTime<Utc>,
+    pub expires: DateTime<Utc>,
+}
+
+struct Slot {
+    cached: Cached,
+    // Tick of the last lookup, for eviction
+    used: u64,
+}
+
+#[derive(Default)]
+struct Lru {
+    slots: HashMap<String, Slot>,
+    tick: u64,
+}
+
+impl Lru {
+    fn insert(&mut self, key: &str, cached: Cached, capacity: usize) {
+        self.tick += 1;
+        if !self.slots.contains_key(key) && self.slots.len() >= capacity {
+            let oldest = self
+                .slots
+                .iter()
+                .min_by_key(|(_, slot)| slot.used)
+                .map(|(key, _)| key.clone());
+            if let Some(oldest) = oldest {
+                self.slots.remove(&oldest);
+            }
+        }
+        let used = self.tick;
+        self.slots.insert(key.to_string(), Slot { cached, used });
+    }
+}
+
+pub struct ResultCache {
+    lru: Mutex<Lru>,
+    capacity: usize,
+    dir: Option<PathBuf>,
+}
+
+impl Default for ResultCache {
+    fn default() -> Self {
+        Self {
+            lru: Mutex::new(Lru::default()),
+            capacity: DEFAULT_CAPACITY,
+            dir: None,
+        }
+    }
+}
+
+impl ResultCache {
+    /// A cache that also keeps results in `dir`. Expired files are removed.
+    pub fn with_dir(dir: PathBuf) -> Result<Self, String> {
+        // Results can hold whatever the redactor missed, so only we may read them
+        crate::secrets::create_private_dir(&dir)?;
+        let entries = fs::read_dir(&dir).map_err(|e| format!("Failed to read {:?}: {}", dir, e))?;
+        let now = Utc::now();
+        for entry in entries.flatten() {
+            let path = entry.path();
+            if read(&path).is_none_or(|cached| cached.expires <= now) {
+                let _ = fs::remove_file(&path);
+            }
+        }
+        Ok(Self {
+            dir: Some(dir),
+            ..Self::default()
+        })
+    }
+
+    pub fn get(&self, key: &str) -> Option<Cached> {
+        let now = Utc::now();
+        let mut lru = self.lru.lock().unwrap();
+        lru.tick += 1;
+        let tick = lru.tick;
+        if let Some(slot) = lru.slots.get_mut(key) {
+            if slot.cached.expires > now {
+                slot.used = tick;
+                return Some(slot.cached.clone());
+            }
+            lru.slots.remove(key);
+        }
+
+        let dir = self.dir.as_ref()?;
+        let path = entry_path(dir, key);
+        let cached = read(&path)?;
+        if cached.expires <= now {
+            let _ = fs::remove_file(&path);
+            return None;
+        }
+        lru.insert(key, cached.clone(), self.capacity);
+        Some(cached)
+    }
+
+    pub fn put(&self, key: &str, tool: &str, output: String, ttl: Duration) {
+        let now = Utc::now();
+        let cached = Cached {
+            tool: tool.to_string(),
+            output,
+            stored: now,
+            // A ttl of centuries is allowed; it just never expires
+            expires: now.checked_add_signed(ttl).unwrap_or(DateTime::<Utc>::MAX_UTC),
+        };
+        if let Some(dir) = &self.dir {
+            let path = entry_path(dir, key);
+            let written = serde_json::to_string(&cached)
+                .map_err(|e| e.to_string())
+                .and_then(|content| crate::secrets::write_private(&path, content.as_bytes()));
+            if let Err(e) = written {
+                warn!("Failed to store cached result for {}: {}", tool, e);
+            }
+        }
+        self.lru.lock().unwrap().insert(key, cached, self.capacity);
+    }
+}
+
+fn entry_path(dir: &Path, key: &str) -> PathBuf {
+    dir.join(format!("{}.json", key))
+}
+
+fn read(path: &Path) -> Option<Cached> {
+    let content = fs::read_to_string(path).ok()?;
+    serde_json::from_str(&content).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(yaml: &str) -> CacheConfig {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    #[test]
+    fn test_key_is_canonical_and_varies_with_env() {
+        let plain = config("ttl: 60s\n");
+        let a = HashMap::from([("x".to_string(), json!(1)), ("y".to_string(), json!("b"))]);
+        let b = HashMap::from([("y".to_string(), json!("b")), ("x".to_string(), json!(1))]);
+        assert_eq!(plain.key("t", &a), plain.key("t", &b));
+        assert_ne!(plain.key("t", &a), plain.key("u", &a));
+        assert_ne!(plain.key("t", &a), config("ttl: 60s\nenv: [PATH]\n").key("t", &a));
+
+        assert_eq!(config("ttl: 5m\n").ttl, Duration::minutes(5));
+        assert_eq!(config("ttl: 30\n").ttl, Duration::seconds(30));
+        assert!(serde_yaml::from_str::<CacheConfig>("ttl: 0s\n").is_err());
+        assert!(serde_yaml::from_str::<CacheConfig>("ttl: soon\n").is_err());
+    }
+
+    #[test]
+    fn test_lru_expiry_and_disk() {
+        let cache = ResultCache {
+            capacity: 2,
+            ..ResultCache::default()
+        };
+        let minute = Duration::minutes(1);
+        cache.put("a", "t", "1".to_string(), minute);
+        cache.put("b", "t", "2".to_string(), minute);
+        assert!(cache.get("a").is_some());
+        cache.put("c", "t", "3".to_string(), minute);
+        // "b" was the least recently used
+        assert!(cache.get("b").is_none());
+        assert_eq!(cache.get("a").unwrap().output, "1");
+        cache.put("d", "t", "4".to_string(), Duration::milliseconds(-1));
+        assert!(cache.get("d").is_none());
+
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let dir = temp_dir.path().join("cache");
+        let cache = ResultCache::with_dir(dir.clone()).unwrap();
+        cache.put("k", "t", "{\"n\":1}".to_string(), minute);
+        cache.put("gone", "t", "{}".to_string(), Duration::milliseconds(-1));
+        let reopened = ResultCache::with_dir(dir.clone()).unwrap();
+        assert_eq!(reopened.get("k").unwrap().output, "{\"n\":1}");
+        assert!(reopened.get("gone").is_none());
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+
+        use std::os::unix::fs::PermissionsExt;
+        let mode = |path: &Path| fs::metadata(path).unwrap().permissions().mode() & 0o777;
+        assert_eq!(mode(&dir), 0o700);
+        assert_eq!(mode(&entry_path(&dir, "k")), 0o600);
+    }
+}
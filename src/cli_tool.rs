@@ -1,24 +1,78 @@
+use crate::cache::CacheConfig;
+use crate::content::{ContentConfig, OUTPUT_DIR_ENV};
+use crate::handlers::Handler;
+use crate::limits::ResourceLimits;
+use crate::output_filter::{OutputFilter, SELECT_PARAM};
+use crate::output_schema::OutputSchema;
+use crate::policy::{CallIdentity, Denial};
+use crate::progress::{LineProgress, ProgressParser, ProgressSender};
+use crate::rbac::ToolAccess;
+use crate::redact::Redactor;
+use crate::sandbox::SandboxConfig;
+use crate::secrets::{EnvValue, Scrubber, SecretSource};
+use crate::pipeline::PipelineConfig;
+use crate::script::ScriptTool;
+use crate::wasm::WasmTool;
 use serde_json::Value;
 use std::collections::HashMap;
-use std::process::Command;
+use std::fmt;
+use std::future::Future;
+use std::os::unix::process::CommandExt;
+use std::process::{Command, Stdio};
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncRead, BufReader};
+use tokio_util::sync::CancellationToken;
 
-#[derive(Debug, Clone)]
+// How long a cancelled command gets to exit after SIGTERM before SIGKILL
+const CANCEL_GRACE: Duration = Duration::from_secs(2);
+
+#[derive(Debug, Clone, Default)]
 pub struct CliTool {
     pub name: String,
     pub description: String,
     pub command: String,
     pub args: Vec<CliArg>,
-    pub internal_handler: Option<String>,
+    pub internal_handler: Option<Handler>,
+    // Runs in-process instead of `command`
+    pub wasm: Option<WasmTool>,
+    // Run by `DynamicToolManager`, as scripts can call other tools
+    pub script: Option<ScriptTool>,
+    // Also run by `DynamicToolManager`
+    pub pipeline: Option<PipelineConfig>,
+    pub access: ToolAccess,
+    // Not applied to internal handlers, which run in-process
+    pub sandbox: Option<SandboxConfig>,
+    pub limits: Option<ResourceLimits>,
+    // Extra environment; secret values are resolved per call
+    pub env: Vec<(String, EnvValue)>,
+    // Applied to the output after execution
+    pub redactor: Redactor,
+    // Called as a background job
+    pub background: bool,
+    // Turns output lines into progress; line counts without one
+    pub progress: Option<ProgressParser>,
+    // Only set on tools marked idempotent
+    pub cache: Option<CacheConfig>,
+    // Applied to a command's parsed JSON output
+    pub output: Option<OutputFilter>,
+    // Checked against every result
+    pub output_schema: Option<OutputSchema>,
+    pub example_output: Option<Value>,
+    // Makes a command's output MCP content instead of JSON
+    pub content: Option<ContentConfig>,
+    // The definition as loaded, with the command resolved
+    pub definition: Value,
 }
 
 #[derive(Debug, Clone)]
 pub struct CliArg {
     pub name: String,
-    #[allow(dead_code)]
     pub description: String,
     pub required: bool,
     pub arg_type: ArgType,
     pub cli_flag: Option<String>, // e.g., "--input" or "-i"
+    // Filled in by the server from a secret, never by the caller
+    pub secret: Option<SecretSource>,
 }
 
 #[derive(Debug, Clone)]
@@ -29,18 +83,141 @@ pub enum ArgType {
     Array,
 }
 
+impl ArgType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ArgType::String => "string",
+            ArgType::Number => "number",
+            ArgType::Boolean => "boolean",
+            ArgType::Array => "array",
+        }
+    }
+}
+
+/// Why a tool call failed.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ToolError {
+    Failed(String),
+    // Stopped by one of the tool's `limits`; `limit` names the setting
+    LimitExceeded { limit: &'static str, message: String },
+    // The client cancelled the call
+    Cancelled,
+    // Refused by access control or a policy rule, including a call the tool
+    // made on the caller's behalf
+    Denied(Denial),
+}
+
+impl ToolError {
+    /// The error as returned to the client.
+    pub fn to_json(&self) -> String {
+        match self {
+            ToolError::Failed(e) => serde_json::json!({ "error": e }).to_string(),
+            ToolError::LimitExceeded { limit, message } => serde_json::json!({
+                "error": message,
+                "kind": "limit_exceeded",
+                "limit": limit
+            })
+            .to_string(),
+            ToolError::Cancelled => serde_json::json!({
+                "error": "Call cancelled",
+                "kind": "cancelled"
+            })
+            .to_string(),
+            ToolError::Denied(denial) => denial.to_json(),
+        }
+    }
+}
+
+impl From<String> for ToolError {
+    fn from(e: String) -> Self {
+        ToolError::Failed(e)
+    }
+}
+
+impl fmt::Display for ToolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ToolError::Failed(message) | ToolError::LimitExceeded { message, .. } => {
+                f.write_str(message)
+            }
+            ToolError::Cancelled => f.write_str("Call cancelled"),
+            ToolError::Denied(denial) => f.write_str(&denial.message),
+        }
+    }
+}
+
+/// What the caller can see of and do to a running call.
+#[derive(Debug, Clone, Default)]
+pub struct CallControl {
+    // Holds the latest update from the lines a command writes
+    pub progress: Option<ProgressSender>,
+    // Cancelled when the client gives up on the call
+    pub cancel: CancellationToken,
+    // Who the call is for; nested calls are checked as this caller
+    pub identity: CallIdentity,
+}
+
+impl CallControl {
+    /// Run in-process work unless the call is cancelled first, which drops it.
+    pub async fn or_cancel(
+        &self,
+        work: impl Future<Output = Result<String, ToolError>>,
+    ) -> Result<String, ToolError> {
+        tokio::select! {
+            result = work => result,
+            _ = self.cancel.cancelled() => Err(ToolError::Cancelled),
+        }
+    }
+}
+
 impl CliTool {
-    pub async fn execute(&self, params: HashMap<String, Value>) -> Result<String, String> {
+    #[cfg(test)]
+    pub async fn execute(&self, params: HashMap<String, Value>) -> Result<String, ToolError> {
+        self.execute_with(params, &CallControl::default()).await
+    }
+
+    /// Run the tool, sending progress for each line a command writes and
+    /// stopping it if the call is cancelled.
+    pub async fn execute_with(
+        &self,
+        params: HashMap<String, Value>,
+        control: &CallControl,
+    ) -> Result<String, ToolError> {
         // Handle internal commands
         if let Some(handler) = &self.internal_handler {
-            return self.execute_internal(handler, params).await;
+            return control.or_cancel(async { Ok(handler.call(params).await?) }).await;
+        }
+        if let Some(wasm) = &self.wasm {
+            self.check_required(&params)?;
+            return control.or_cancel(wasm.run(&self.name, &params, &control.cancel)).await;
         }
 
         let mut cmd = Command::new(&self.command);
+        let mut scrubber = Scrubber::default();
 
         // Map MCP parameters to CLI arguments
         for arg in &self.args {
-            if let Some(value) = params.get(&arg.name) {
+            if let Some(source) = &arg.secret {
+                if params.contains_key(&arg.name) {
+                    return Err(format!(
+                        "Argument '{}' is supplied by the server and cannot be set",
+                        arg.name
+                    )
+                    .into());
+                }
+                let value = control
+                    .or_cancel(async {
+                        source.resolve().await.map_err(|e| {
+                            format!("Secret for argument '{}': {}", arg.name, e).into()
+                        })
+                    })
+                    .await?;
+                if let Some(flag) = &arg.cli_flag {
+                    cmd.arg(flag);
+                }
+                cmd.arg(&value);
+                scrubber.add(&arg.name, &value);
+            } else if let Some(value) = params.get(&arg.name) {
                 match &arg.cli_flag {
                     Some(flag) => {
                         cmd.arg(flag);
@@ -52,91 +229,176 @@ impl CliTool {
                     }
                 }
             } else if arg.required {
-                return Err(format!("Missing required argument: {}", arg.name));
+                return Err(format!("Missing required argument: {}", arg.name).into());
+            }
+        }
+
+        for (name, value) in &self.env {
+            match value {
+                EnvValue::Plain(value) => {
+                    cmd.env(name, value);
+                }
+                EnvValue::Secret { secret } => {
+                    let value = control
+                        .or_cancel(async {
+                            secret.resolve().await.map_err(|e| {
+                                format!("Secret for environment variable {}: {}", name, e).into()
+                            })
+                        })
+                        .await?;
+                    cmd.env(name, &value);
+                    scrubber.add(name, &value);
+                }
             }
         }
 
-        // Execute command
-        let output = cmd
-            .output()
+        // A private directory for files the result names; removed after the call
+        let output_dir = match &self.content {
+            Some(content) if content.reads_files() => {
+                let dir = tempfile::Builder::new()
+                    .prefix("gamecode-output-")
+                    .tempdir()
+                    .map_err(|e| format!("Failed to create an output directory: {}", e))?;
+                cmd.env(OUTPUT_DIR_ENV, dir.path());
+                Some(dir)
+            }
+            _ => None,
+        };
+
+        // Before the sandbox, which would block the cgroup filesystem
+        let limits = match &self.limits {
+            Some(limits) => Some(limits.apply(&self.name, &mut cmd)?),
+            None => None,
+        };
+        if let Some(sandbox) = &self.sandbox {
+            match &output_dir {
+                Some(dir) => sandbox.with_writable(dir.path()).apply(&self.name, &self.command, &mut cmd)?,
+                None => sandbox.apply(&self.name, &self.command, &mut cmd)?,
+            }
+        }
+
+        // Execute command; stdin is the MCP transport, so the tool gets none.
+        // Its own process group lets a cancel reach whatever it starts.
+        cmd.process_group(0);
+        let mut child = tokio::process::Command::from(cmd)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()
             .map_err(|e| format!("Failed to execute {}: {}", self.command, e))?;
+        let mut group = ProcessGroup(child.id());
+        let (stdout, stderr) = (child.stdout.take().unwrap(), child.stderr.take().unwrap());
 
-        if !output.status.success() {
-            let stderr = String::from_utf8_lossy(&output.stderr);
-            return Err(format!("Command failed: {}", stderr));
+        let run = async {
+            let output = self.read_output(stdout, stderr, &scrubber, control.progress.as_ref());
+            let (stdout, stderr) = output
+                .await
+                .map_err(|e| format!("Failed to read output of {}: {}", self.command, e))?;
+            let status = child
+                .wait()
+                .await
+                .map_err(|e| format!("Failed to execute {}: {}", self.command, e))?;
+            Ok::<_, ToolError>((status, stdout, stderr))
+        };
+        let finished = tokio::select! {
+            finished = run => Some(finished),
+            _ = control.cancel.cancelled() => None,
+        };
+        let Some(finished) = finished else {
+            group.terminate(&mut child).await;
+            return Err(ToolError::Cancelled);
+        };
+        let (status, stdout, stderr) = finished?;
+        group.reaped();
+
+        if !status.success() {
+            let stderr = scrubber.scrub(&String::from_utf8_lossy(&stderr));
+            if let Some(err) = limits.and_then(|l| l.violation(&status, &stderr)) {
+                return Err(err);
+            }
+            return Err(format!("Command failed: {}", stderr).into());
         }
 
-        let stdout = String::from_utf8_lossy(&output.stdout);
+        if let Some(content) = &self.content {
+            let call_dir = output_dir.as_ref().map(|dir| dir.path());
+            return Ok(content.render(&self.name, &stdout, &scrubber, call_dir)?);
+        }
+        let stdout = scrubber.scrub(&String::from_utf8_lossy(&stdout));
 
         // Validate JSON output
         let json_result: Value =
             serde_json::from_str(&stdout).map_err(|e| format!("Invalid JSON output: {}", e))?;
+        let json_result = match &self.output {
+            Some(output) => {
+                let select = params.get(SELECT_PARAM).filter(|_| output.has_select());
+                output.apply(json_result, select)?
+            }
+            None => json_result,
+        };
 
         Ok(json_result.to_string())
     }
 
-    async fn execute_internal(
+    // Read both pipes to the end, reporting each complete line as it arrives
+    async fn read_output(
         &self,
-        handler: &str,
-        params: HashMap<String, Value>,
-    ) -> Result<String, String> {
-        match handler {
-            "add" => {
-                let a = params
-                    .get("a")
-                    .and_then(|v| v.as_f64())
-                    .ok_or("Missing or invalid parameter 'a'")?;
-                let b = params
-                    .get("b")
-                    .and_then(|v| v.as_f64())
-                    .ok_or("Missing or invalid parameter 'b'")?;
-                Ok(format!(
-                    r#"{{"result": {}, "operation": "addition"}}"#,
-                    a + b
-                ))
-            }
-            "multiply" => {
-                let a = params
-                    .get("a")
-                    .and_then(|v| v.as_f64())
-                    .ok_or("Missing or invalid parameter 'a'")?;
-                let b = params
-                    .get("b")
-                    .and_then(|v| v.as_f64())
-                    .ok_or("Missing or invalid parameter 'b'")?;
-                Ok(format!(
-                    r#"{{"result": {}, "operation": "multiplication"}}"#,
-                    a * b
-                ))
-            }
-            "list_files" => {
-                let path = params.get("path").and_then(|v| v.as_str()).unwrap_or(".");
-
-                match std::fs::read_dir(path) {
-                    Ok(entries) => {
-                        let mut files = Vec::new();
-                        entries.into_iter().for_each(|entry| {
-                            if let Ok(entry) = entry {
-                                if let Ok(metadata) = entry.metadata() {
-                                    files.push(format!(
-                                        r#"{{"name": "{}", "is_dir": {}, "size": {}}}"#,
-                                        entry.file_name().to_string_lossy(),
-                                        metadata.is_dir(),
-                                        metadata.len()
-                                    ));
-                                }
-                            }
-                        });
-                        Ok(format!(
-                            r#"{{"path": "{}", "files": [{}]}}"#,
-                            path,
-                            files.join(", ")
-                        ))
+        stdout: impl AsyncRead + Unpin,
+        stderr: impl AsyncRead + Unpin,
+        scrubber: &Scrubber,
+        progress: Option<&ProgressSender>,
+    ) -> std::io::Result<(Vec<u8>, Vec<u8>)> {
+        let (mut stdout, mut stderr) = (BufReader::new(stdout), BufReader::new(stderr));
+        let (mut out, mut err) = (Vec::new(), Vec::new());
+        // Where the line being read starts; a read cut short by the other
+        // pipe leaves its partial line in the buffer to be continued
+        let (mut out_line, mut err_line) = (0, 0);
+        let (mut out_done, mut err_done) = (false, false);
+        let mut lines = LineProgress::new(self.progress.as_ref());
+
+        while !(out_done && err_done) {
+            let update = tokio::select! {
+                read = stdout.read_until(b'\n', &mut out), if !out_done => {
+                    if read? == 0 {
+                        out_done = true;
+                        continue;
                     }
-                    Err(e) => Ok(format!(r#"{{"error": "{}"}}"#, e)),
+                    let line = String::from_utf8_lossy(&out[out_line..]).into_owned();
+                    out_line = out.len();
+                    lines.line(&line, None)
                 }
+                read = stderr.read_until(b'\n', &mut err), if !err_done => {
+                    if read? == 0 {
+                        err_done = true;
+                        continue;
+                    }
+                    let line = String::from_utf8_lossy(&err[err_line..]).into_owned();
+                    err_line = err.len();
+                    let message = self.redactor.redact_line(&scrubber.scrub(line.trim_end()));
+                    lines.line(&line, Some(message))
+                }
+            };
+            if let (Some(sender), Some(update)) = (progress, update) {
+                // Even with the client gone, the tool still runs to the end
+                sender.send_replace(Some(update));
             }
-            _ => Err(format!("Unknown internal handler: {}", handler)),
+        }
+        Ok((out, err))
+    }
+
+    /// For tools that take their parameters as JSON rather than as flags.
+    pub fn check_required(&self, params: &HashMap<String, Value>) -> Result<(), String> {
+        match self.args.iter().find(|a| a.required && !params.contains_key(&a.name)) {
+            Some(arg) => Err(format!("Missing required argument: {}", arg.name)),
+            None => Ok(()),
+        }
+    }
+
+    /// Check a result against the tool's output schema, if it has one.
+    pub fn check_output(&self, output: &str) -> Result<(), String> {
+        match &self.output_schema {
+            Some(schema) => schema.check(&self.name, output),
+            None => Ok(()),
         }
     }
 
@@ -180,17 +442,55 @@ macro_rules! define_cli_tool {
                         required: $required,
                         arg_type: $arg_type,
                         cli_flag: $cli_flag,
+                        secret: None,
                     }
                 ),*
             ],
-            internal_handler: None,
+            ..Default::default()
         }
     };
 }
 
+// A running command's process group, signalled as a whole. Dropping it
+// before the command was reaped, as when a caller abandons the call, kills it.
+struct ProcessGroup(Option<u32>);
+
+impl ProcessGroup {
+    fn signal(&self, signal: libc::c_int) {
+        if let Some(pgid) = self.0 {
+            // SAFETY: kill has no memory effects; a negative pid names the group
+            unsafe { libc::kill(-(pgid as libc::pid_t), signal) };
+        }
+    }
+
+    // SIGTERM, then SIGKILL for whatever hasn't exited after the grace period
+    async fn terminate(&mut self, child: &mut tokio::process::Child) {
+        self.signal(libc::SIGTERM);
+        let exited = tokio::time::timeout(CANCEL_GRACE, child.wait()).await.is_ok();
+        self.signal(libc::SIGKILL);
+        if !exited {
+            let _ = child.wait().await;
+        }
+        self.reaped();
+    }
+
+    // Once the command has exited, its pgid may be reused
+    fn reaped(&mut self) {
+        self.0 = None;
+    }
+}
+
+impl Drop for ProcessGroup {
+    fn drop(&mut self) {
+        self.signal(libc::SIGKILL);
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::handlers::HandlerRegistry;
+    use rmcp::model::ResourceContents;
     use serde_json::json;
 
     #[test]
@@ -201,6 +501,7 @@ mod tests {
             command: "echo".to_string(),
             args: vec![],
             internal_handler: None,
+            ..Default::default()
         };
         
         let value = json!("hello world");
@@ -216,6 +517,7 @@ mod tests {
             command: "echo".to_string(),
             args: vec![],
             internal_handler: None,
+            ..Default::default()
         };
         
         let value = json!(42.5);
@@ -231,6 +533,7 @@ mod tests {
             command: "echo".to_string(),
             args: vec![],
             internal_handler: None,
+            ..Default::default()
         };
         
         let value = json!(true);
@@ -246,6 +549,7 @@ mod tests {
             command: "echo".to_string(),
             args: vec![],
             internal_handler: None,
+            ..Default::default()
         };
         
         let value = json!(["item1", "item2"]);
@@ -253,6 +557,24 @@ mod tests {
         assert_eq!(result.unwrap(), r#"["item1","item2"]"#);
     }
 
+    #[test]
+    fn test_define_cli_tool() {
+        let tool = crate::define_cli_tool!(
+            name: "grep",
+            description: "Search files",
+            command: "grep",
+            args: [{
+                name: "pattern",
+                description: "What to look for",
+                required: true,
+                arg_type: ArgType::String,
+                cli_flag: None
+            }]
+        );
+        assert_eq!(tool.args[0].name, "pattern");
+        assert!(tool.internal_handler.is_none() && tool.cache.is_none());
+    }
+
     #[tokio::test]
     async fn test_internal_handler_add() {
         let tool = CliTool {
@@ -260,7 +582,8 @@ mod tests {
             description: "Add two numbers".to_string(),
             command: "internal".to_string(),
             args: vec![],
-            internal_handler: Some("add".to_string()),
+            internal_handler: HandlerRegistry::default().get("add"),
+            ..Default::default()
         };
 
         let mut params = HashMap::new();
@@ -281,7 +604,8 @@ mod tests {
             description: "Multiply two numbers".to_string(),
             command: "internal".to_string(),
             args: vec![],
-            internal_handler: Some("multiply".to_string()),
+            internal_handler: HandlerRegistry::default().get("multiply"),
+            ..Default::default()
         };
 
         let mut params = HashMap::new();
@@ -308,32 +632,195 @@ mod tests {
                     required: true,
                     arg_type: ArgType::String,
                     cli_flag: Some("--input".to_string()),
+                    secret: None,
                 }
             ],
             internal_handler: None,
+            ..Default::default()
         };
 
         let params = HashMap::new(); // Empty params
         let result = tool.execute(params).await;
         
         assert!(result.is_err());
-        assert!(result.unwrap_err().contains("Missing required argument: required_arg"));
+        assert!(result.unwrap_err().to_string().contains("Missing required argument: required_arg"));
     }
 
     #[tokio::test]
-    async fn test_unknown_internal_handler() {
+    async fn test_secrets_are_injected_and_scrubbed() {
+        // SAFETY: no other test reads this variable
+        unsafe { std::env::set_var("GAMECODE_TEST_TOKEN", "tok-12345") };
         let tool = CliTool {
-            name: "test".to_string(),
-            description: "test tool".to_string(),
-            command: "internal".to_string(),
-            args: vec![],
-            internal_handler: Some("unknown_handler".to_string()),
+            name: "leaky".to_string(),
+            command: "sh".to_string(),
+            args: vec![
+                CliArg {
+                    name: "script".to_string(),
+                    description: String::new(),
+                    required: true,
+                    arg_type: ArgType::String,
+                    cli_flag: Some("-c".to_string()),
+                    secret: None,
+                },
+                CliArg {
+                    name: "password".to_string(),
+                    description: String::new(),
+                    required: false,
+                    arg_type: ArgType::String,
+                    cli_flag: None,
+                    secret: Some(SecretSource::Command(vec![
+                        "echo".to_string(),
+                        "pw-67890".to_string(),
+                    ])),
+                },
+            ],
+            env: vec![(
+                "API_TOKEN".to_string(),
+                EnvValue::Secret {
+                    secret: SecretSource::Env("GAMECODE_TEST_TOKEN".to_string()),
+                },
+            )],
+            ..Default::default()
         };
 
-        let params = HashMap::new();
-        let result = tool.execute(params).await;
-        
-        assert!(result.is_err());
-        assert!(result.unwrap_err().contains("Unknown internal handler: unknown_handler"));
+        let mut params = HashMap::new();
+        params.insert(
+            "script".to_string(),
+            json!(r#"echo "{\"token\": \"$API_TOKEN\", \"arg\": \"$0\"}""#),
+        );
+        let result: Value = serde_json::from_str(&tool.execute(params.clone()).await.unwrap()).unwrap();
+        assert_eq!(result["token"], "[secret:API_TOKEN]");
+        assert_eq!(result["arg"], "[secret:password]");
+
+        // The caller can't pick the value of a secret argument
+        params.insert("password".to_string(), json!("guess"));
+        let err = tool.execute(params).await.unwrap_err();
+        assert!(err.to_string().contains("supplied by the server"), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn test_output_lines_stream_as_progress() {
+        let tool = CliTool {
+            name: "build".to_string(),
+            command: "sh".to_string(),
+            args: vec![CliArg {
+                name: "script".to_string(),
+                description: String::new(),
+                required: true,
+                arg_type: ArgType::String,
+                cli_flag: Some("-c".to_string()),
+                secret: None,
+            }],
+            ..Default::default()
+        };
+        let params = HashMap::from([(
+            "script".to_string(),
+            json!("echo compiling >&2; echo '{\"ok\":'; echo 'true}'"),
+        )]);
+
+        let (sender, mut updates) = tokio::sync::watch::channel(None);
+        let control = CallControl {
+            progress: Some(sender),
+            ..Default::default()
+        };
+        let result = tool.execute_with(params, &control).await.unwrap();
+        assert_eq!(result, r#"{"ok":true}"#);
+
+        // Lines from either pipe count, in whichever order they were read
+        let update = updates.borrow_and_update().clone().unwrap();
+        assert_eq!(update.progress, 3.0);
+
+        // Stderr lines are the message, redacted; one pipe keeps the order fixed
+        let (sender, updates) = tokio::sync::watch::channel(None);
+        let stderr = &b"compiling\npassword=hunter22"[..];
+        let (_, err) = tool
+            .read_output(&b""[..], stderr, &Scrubber::default(), Some(&sender))
+            .await
+            .unwrap();
+        assert_eq!(err, stderr);
+        let update = updates.borrow().clone().unwrap();
+        assert_eq!(update.progress, 2.0);
+        assert_eq!(update.message.as_deref(), Some("password=[redacted:password]"));
+    }
+
+    #[tokio::test]
+    async fn test_output_files_come_from_the_call_dir() {
+        let tool = CliTool {
+            name: "render".to_string(),
+            command: "sh".to_string(),
+            args: vec![CliArg {
+                name: "script".to_string(),
+                description: String::new(),
+                required: true,
+                arg_type: ArgType::String,
+                cli_flag: Some("-c".to_string()),
+                secret: None,
+            }],
+            content: Some(
+                serde_yaml::from_str("type: blob\nmime_type: text/plain\nsource: file\n").unwrap(),
+            ),
+            ..Default::default()
+        };
+        let run = |script: &str| {
+            tool.execute(HashMap::from([("script".to_string(), json!(script))]))
+        };
+
+        let result = run("echo hi > $GAMECODE_OUTPUT_DIR/out; echo $GAMECODE_OUTPUT_DIR/out")
+            .await
+            .unwrap();
+        let content = crate::content::parse(&result).unwrap();
+        let Some(ResourceContents::BlobResourceContents { uri, .. }) =
+            content[0].as_resource().map(|r| &r.resource)
+        else {
+            panic!("expected a blob, got {:?}", content[0]);
+        };
+        let path = uri.strip_prefix("file://").unwrap();
+        assert!(path.contains("gamecode-output-"), "{}", path);
+        // Gone once the call is over
+        assert!(!std::path::Path::new(path).exists());
+
+        let err = run("echo /etc/hostname").await.unwrap_err();
+        assert!(err.to_string().contains("is not in $GAMECODE_OUTPUT_DIR"), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn test_cancel_kills_the_process_group() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let pidfile = dir.path().join("pid");
+        let tool = CliTool {
+            name: "hang".to_string(),
+            command: "sh".to_string(),
+            args: vec![CliArg {
+                name: "script".to_string(),
+                description: String::new(),
+                required: true,
+                arg_type: ArgType::String,
+                cli_flag: Some("-c".to_string()),
+                secret: None,
+            }],
+            ..Default::default()
+        };
+        // The shell ignores SIGTERM, so only the SIGKILL after the grace period stops it
+        let script = format!("trap '' TERM; sleep 60 & echo $! > {}; wait", pidfile.display());
+        let params = HashMap::from([("script".to_string(), json!(script))]);
+
+        let control = CallControl::default();
+        let cancel = control.cancel.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(300)).await;
+            cancel.cancel();
+        });
+        let started = std::time::Instant::now();
+        let err = tool.execute_with(params, &control).await.unwrap_err();
+        assert_eq!(err, ToolError::Cancelled);
+        assert!(started.elapsed() < Duration::from_secs(10));
+
+        // The backgrounded sleep was in the group too; it may linger as a
+        // zombie until init reaps it, but it isn't running
+        let pid = std::fs::read_to_string(&pidfile).unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let stat = std::fs::read_to_string(format!("/proc/{}/stat", pid.trim())).unwrap_or_default();
+        let state = stat.rsplit(") ").next().and_then(|rest| rest.chars().next());
+        assert!(matches!(state, None | Some('Z' | 'X')), "sleep {} survived: {}", pid.trim(), stat);
     }
 }
@@ -0,0 +1,307 @@
+//! Rich results: images, binary files and several content parts.
+//!
+//! A command's stdout is normally JSON, returned as text. A tool with a
+//! `content:` block produces MCP content instead: an image, or any other
+//! binary as a blob resource, read from stdout or from a file the command
+//! wrote; or, with `type: parts`, a list of text, image and blob parts the
+//! command prints as JSON. Inside the server the result stays a string, the
+//! content list as JSON, until it is returned to the client.
+//!
+//! Files are read by the server, outside any sandbox, so only paths in the
+//! call's own output directory (`$GAMECODE_OUTPUT_DIR`) or the tool's
+//! configured `output_dir` are accepted.
+
+use crate::sandbox::expand_path;
+use crate::secrets::Scrubber;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use rmcp::model::{Content, ResourceContents};
+use serde::Deserialize;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+const DEFAULT_MAX_BYTES: u64 = 10 * 1024 * 1024;
+
+/// Names the per-call directory a command writes its output files to.
+pub const OUTPUT_DIR_ENV: &str = "GAMECODE_OUTPUT_DIR";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ContentType {
+    Image,
+    // Any binary, as an embedded resource
+    Blob,
+    // A JSON list of parts on stdout
+    Parts,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Source {
+    // The bytes themselves
+    #[default]
+    Stdout,
+    // The path of a file holding them
+    File,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ContentConfig {
+    #[serde(rename = "type")]
+    pub kind: ContentType,
+    // Required for image and blob; parts carry their own
+    pub mime_type: Option<String>,
+    #[serde(default)]
+    pub source: Source,
+    // Largest output or file accepted
+    #[serde(default = "default_max_bytes")]
+    pub max_bytes: u64,
+    // Another directory output files may be read from
+    pub output_dir: Option<String>,
+}
+
+fn default_max_bytes() -> u64 {
+    DEFAULT_MAX_BYTES
+}
+
+// One entry of a `type: parts` list; binary parts give a path or base64 data
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+enum Part {
+    Text {
+        text: String,
+    },
+    Image {
+        mime_type: String,
+        path: Option<String>,
+        data: Option<String>,
+    },
+    Blob {
+        mime_type: String,
+        path: Option<String>,
+        data: Option<String>,
+    },
+}
+
+impl ContentConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        match (self.kind, self.mime_type.as_deref()) {
+            (ContentType::Parts, Some(_)) => {
+                Err("content: parts give their own mime_type".to_string())
+            }
+            (ContentType::Parts, None) if self.source == Source::File => {
+                Err("content: parts are always read from stdout".to_string())
+            }
+            (ContentType::Image | ContentType::Blob, None) => {
+                Err("content: image and blob need a mime_type".to_string())
+            }
+            (ContentType::Image, Some(mime)) if !mime.starts_with("image/") => {
+                Err(format!("content: '{}' is not an image mime_type", mime))
+            }
+            _ if self.output_dir.is_some() && !self.reads_files() => {
+                Err("content: output_dir needs source: file or type: parts".to_string())
+            }
+            _ => match self.output_dir.as_deref().map(expand_path) {
+                Some(Some(dir)) if !dir.is_absolute() => {
+                    Err(format!("content: output_dir '{}' is not absolute", dir.display()))
+                }
+                _ => Ok(()),
+            },
+        }
+    }
+
+    /// Whether the result may name files for the server to read.
+    pub fn reads_files(&self) -> bool {
+        self.kind == ContentType::Parts || self.source == Source::File
+    }
+
+    /// Turn a command's stdout into content, returned as JSON. Files are read
+    /// only from `call_dir`, the call's own output directory, or `output_dir`.
+    pub fn render(
+        &self,
+        tool: &str,
+        stdout: &[u8],
+        scrubber: &Scrubber,
+        call_dir: Option<&Path>,
+    ) -> Result<String, String> {
+        if stdout.len() as u64 > self.max_bytes {
+            return Err(format!("Output is over the {} byte limit", self.max_bytes));
+        }
+        let mime = self.mime_type.clone().unwrap_or_default();
+        // Canonical, so a link can't lead out of them
+        let dirs: Vec<PathBuf> = call_dir
+            .map(Path::to_path_buf)
+            .into_iter()
+            .chain(self.output_dir.as_deref().and_then(expand_path))
+            .filter_map(|dir| fs::canonicalize(dir).ok())
+            .collect();
+        let content = match (self.kind, self.source) {
+            (ContentType::Parts, _) => {
+                let stdout = scrubber.scrub(&String::from_utf8_lossy(stdout));
+                let parts: Vec<Part> = serde_json::from_str(&stdout)
+                    .map_err(|e| format!("Invalid content parts: {}", e))?;
+                parts
+                    .into_iter()
+                    .map(|part| self.part(tool, part, &dirs))
+                    .collect::<Result<_, _>>()?
+            }
+            (kind, Source::Stdout) => vec![binary(kind, tool, None, mime, STANDARD.encode(stdout))],
+            (kind, Source::File) => {
+                let path = String::from_utf8_lossy(stdout).trim().to_string();
+                let data = self.read_file(&path, &dirs)?;
+                vec![binary(kind, tool, Some(&path), mime, data)]
+            }
+        };
+        serde_json::to_string(&content).map_err(|e| e.to_string())
+    }
+
+    fn part(&self, tool: &str, part: Part, dirs: &[PathBuf]) -> Result<Content, String> {
+        let (kind, mime_type, path, data) = match part {
+            Part::Text { text } => return Ok(Content::text(text)),
+            Part::Image { mime_type, path, data } => (ContentType::Image, mime_type, path, data),
+            Part::Blob { mime_type, path, data } => (ContentType::Blob, mime_type, path, data),
+        };
+        let data = match (&path, data) {
+            (Some(path), None) => self.read_file(path, dirs)?,
+            (None, Some(data)) => {
+                STANDARD
+                    .decode(&data)
+                    .map_err(|e| format!("Invalid base64 in content part: {}", e))?;
+                data
+            }
+            _ => return Err("A binary content part needs either path or data".to_string()),
+        };
+        Ok(binary(kind, tool, path.as_deref(), mime_type, data))
+    }
+
+    // Base64 of a file the command wrote into one of `dirs`
+    fn read_file(&self, path: &str, dirs: &[PathBuf]) -> Result<String, String> {
+        let resolved = fs::canonicalize(path)
+            .map_err(|e| format!("Failed to read output file {}: {}", path, e))?;
+        if !dirs.iter().any(|dir| resolved.starts_with(dir)) || !resolved.is_file() {
+            return Err(format!(
+                "Output file {} is not in ${} or the tool's output_dir",
+                path, OUTPUT_DIR_ENV
+            ));
+        }
+        let size = fs::metadata(&resolved)
+            .map_err(|e| format!("Failed to read output file {}: {}", path, e))?
+            .len();
+        if size > self.max_bytes {
+            return Err(format!("Output file {} is over the {} byte limit", path, self.max_bytes));
+        }
+        let bytes =
+            fs::read(&resolved).map_err(|e| format!("Failed to read output file {}: {}", path, e))?;
+        Ok(STANDARD.encode(bytes))
+    }
+}
+
+fn binary(kind: ContentType, tool: &str, path: Option<&str>, mime_type: String, data: String) -> Content {
+    if kind == ContentType::Image {
+        return Content::image(data, mime_type);
+    }
+    let uri = match path {
+        Some(path) => format!("file://{}", Path::new(path).display()),
+        None => format!("gamecode://output/{}", tool),
+    };
+    Content::resource(ResourceContents::BlobResourceContents {
+        uri,
+        mime_type: Some(mime_type),
+        blob: data,
+        meta: None,
+    })
+}
+
+/// The content of a rich result, as rendered by `ContentConfig::render`.
+pub fn parse(output: &str) -> Option<Vec<Content>> {
+    serde_json::from_str(output).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rmcp::model::RawContent;
+
+    fn config(yaml: &str) -> ContentConfig {
+        let config: ContentConfig = serde_yaml::from_str(yaml).unwrap();
+        config.validate().unwrap();
+        config
+    }
+
+    #[test]
+    fn test_render_image_file_and_parts() {
+        let png = b"\x89PNG\r\n\x1a\nnot really";
+        let image = config("type: image\nmime_type: image/png\n");
+        let content = parse(&image.render("plot", png, &Scrubber::default(), None).unwrap()).unwrap();
+        match &content[0].raw {
+            RawContent::Image(image) => {
+                assert_eq!(image.mime_type, "image/png");
+                assert_eq!(STANDARD.decode(&image.data).unwrap(), png);
+            }
+            other => panic!("expected an image, got {:?}", other),
+        }
+
+        let dir = tempfile::TempDir::new().unwrap();
+        let pdf = dir.path().join("report.pdf");
+        fs::write(&pdf, b"%PDF-1.7").unwrap();
+        let blob = config("type: blob\nmime_type: application/pdf\nsource: file\nmax_bytes: 100\n");
+        let stdout = format!("{}\n", pdf.display());
+        let content = parse(&blob.render("report", stdout.as_bytes(), &Scrubber::default(), Some(dir.path())).unwrap()).unwrap();
+        match &content[0].raw {
+            RawContent::Resource(resource) => match &resource.resource {
+                ResourceContents::BlobResourceContents { uri, blob, .. } => {
+                    assert_eq!(uri, &format!("file://{}", pdf.display()));
+                    assert_eq!(STANDARD.decode(blob).unwrap(), b"%PDF-1.7");
+                }
+                other => panic!("expected a blob, got {:?}", other),
+            },
+            other => panic!("expected a resource, got {:?}", other),
+        }
+        fs::write(&pdf, [0u8; 101]).unwrap();
+        assert!(blob.render("report", stdout.as_bytes(), &Scrubber::default(), Some(dir.path())).unwrap_err().contains("limit"));
+
+        let parts = config("type: parts\n");
+        let stdout = format!(
+            r#"[{{"type": "text", "text": "2 charts"}}, {{"type": "image", "mime_type": "image/png", "data": "{}"}}]"#,
+            STANDARD.encode(png)
+        );
+        let content = parse(&parts.render("charts", stdout.as_bytes(), &Scrubber::default(), None).unwrap()).unwrap();
+        assert_eq!(content.len(), 2);
+        assert_eq!(content[0].as_text().unwrap().text, "2 charts");
+        assert!(parts.render("charts", br#"[{"type": "image", "mime_type": "image/png"}]"#, &Scrubber::default(), None).is_err());
+
+        let bad: ContentConfig = serde_yaml::from_str("type: image\nmime_type: text/plain\n").unwrap();
+        assert!(bad.validate().is_err());
+        let bad: ContentConfig =
+            serde_yaml::from_str("type: parts\noutput_dir: out\n").unwrap();
+        assert!(bad.validate().is_err());
+    }
+
+    #[test]
+    fn test_files_outside_output_dirs_are_refused() {
+        let (call_dir, other) = (tempfile::TempDir::new().unwrap(), tempfile::TempDir::new().unwrap());
+        let secret = other.path().join("id_ed25519");
+        fs::write(&secret, b"private").unwrap();
+        std::os::unix::fs::symlink(&secret, call_dir.path().join("link")).unwrap();
+
+        let blob = config("type: blob\nmime_type: application/octet-stream\nsource: file\n");
+        let render = |blob: &ContentConfig, path: &Path| {
+            let stdout = path.display().to_string();
+            blob.render("t", stdout.as_bytes(), &Scrubber::default(), Some(call_dir.path()))
+        };
+        for path in [secret.clone(), call_dir.path().join("link")] {
+            let err = render(&blob, &path).unwrap_err();
+            assert!(err.contains("is not in $GAMECODE_OUTPUT_DIR"), "{}", err);
+        }
+        let parts = config("type: parts\n");
+        let stdout = format!(r#"[{{"type": "blob", "mime_type": "a/b", "path": "{}"}}]"#, secret.display());
+        assert!(parts.render("t", stdout.as_bytes(), &Scrubber::default(), Some(call_dir.path())).is_err());
+
+        let allowed = config(&format!(
+            "type: blob\nmime_type: application/octet-stream\nsource: file\noutput_dir: {}\n",
+            other.path().display()
+        ));
+        assert!(render(&allowed, &secret).is_ok());
+    }
+}
@@ -1,10 +1,30 @@
-use crate::cli_tool::{ArgType, CliArg, CliTool};
+use crate::audit::AuditEntry;
+use crate::cache::CacheConfig;
+use crate::cli_tool::{ArgType, CallControl, CliArg, CliTool, ToolError};
+use crate::content::ContentConfig;
+use crate::executable;
+use crate::handlers::HandlerRegistry;
+use crate::limits::ResourceLimits;
+use crate::output_filter::{OutputConfig, OutputFilter, SELECT_PARAM};
+use crate::output_schema::{OutputSchema, Validation};
+use crate::pipeline::{PipelineConfig, StepLog};
+use crate::policy::Denial;
+use crate::progress::{ProgressConfig, ProgressParser};
+use crate::rbac::{Caller, ToolAccess};
+use crate::redact::{RedactConfig, Redactions, Redactor};
+use crate::sandbox::{SandboxConfig, expand_path};
+use crate::script::{ScriptConfig, ScriptTool};
+use crate::secrets::{EnvValue, SecretSource};
+use crate::trust::{Trust, TrustStore};
+use crate::wasm::{WasmConfig, WasmTool};
 use serde::Deserialize;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::fs;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::time::Instant;
 use tokio::sync::RwLock;
-use tracing::{debug, info};
+use tracing::{debug, info, warn};
 
 #[derive(Debug, Deserialize)]
 struct ToolConfig {
@@ -16,11 +36,56 @@ struct ToolDefinition {
     name: String,
     description: String,
     command: String,
+    // May be omitted for internal handlers, which declare their own
+    #[serde(default)]
     args: Vec<ArgDefinition>,
     #[allow(dead_code)]
     #[serde(default)]
     static_flags: Vec<String>,
     internal_handler: Option<String>,
+    // A WASI module run in-process instead of a command
+    wasm: Option<WasmConfig>,
+    // An inline Rhai script run instead of a command
+    script: Option<ScriptConfig>,
+    // Steps calling other tools, run instead of a command
+    pipeline: Option<PipelineConfig>,
+    // Pinned SHA-256 of the resolved command binary
+    sha256: Option<String>,
+    // Access control; a tool with neither list is available to everyone
+    #[serde(default)]
+    roles: Vec<String>,
+    #[serde(default)]
+    allowed_principals: Vec<String>,
+    // Linux isolation for the spawned command
+    sandbox: Option<SandboxConfig>,
+    // rlimits, or a cgroup when delegated
+    limits: Option<ResourceLimits>,
+    // Extra environment; values may be `{ secret: ... }` references
+    #[serde(default)]
+    env: BTreeMap<String, EnvValue>,
+    // Output filters; the default detectors apply without one
+    redact: Option<RedactConfig>,
+    // Return a job id at once and run in the background
+    #[serde(default, rename = "async")]
+    background: bool,
+    // How output lines map to progress notifications
+    progress: Option<ProgressConfig>,
+    // Same parameters, same result, and no side effects
+    #[serde(default)]
+    idempotent: bool,
+    // Reuse results of an idempotent tool for a while
+    cache: Option<CacheConfig>,
+    // jq filters over the command's JSON output
+    output: Option<OutputConfig>,
+    // JSON Schema every result should match
+    output_schema: Option<serde_json::Value>,
+    // Whether a mismatch is logged or fails the call
+    #[serde(default)]
+    output_validation: Validation,
+    // A sample result, shown to clients
+    example_output: Option<serde_json::Value>,
+    // Images, binary or several parts instead of JSON
+    content: Option<ContentConfig>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -34,34 +99,223 @@ struct ArgDefinition {
     #[allow(dead_code)]
     #[serde(default)]
     default: Option<String>,
+    // Filled in from a secret at spawn time instead of by the caller
+    secret: Option<SecretSource>,
 }
 
+/// How a tool's output is returned to the client.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputKind {
+    Text,
+    // Also as `structuredContent`, for tools with an output schema
+    Structured,
+    // Rendered MCP content, for tools with a `content:` block
+    Content,
+}
+
+/// What `list_tools` shows of a tool.
+#[derive(Debug, Clone)]
+pub struct ToolInfo {
+    pub name: String,
+    pub description: String,
+    pub args: Vec<CliArg>,
+    pub definition: serde_json::Value,
+    pub output_schema: Option<serde_json::Value>,
+    pub example_output: Option<serde_json::Value>,
+}
+
+// Loaded from whatever directory the client starts in, so it must be trusted
+const PROJECT_TOOLS_FILE: &str = "./tools.yaml";
+
 #[derive(Default, Debug, Clone)]
 pub struct DynamicToolManager {
     tools: Arc<RwLock<HashMap<String, CliTool>>>,
+    // Directories commands must resolve into; empty allows any
+    allowed_exec_dirs: Vec<PathBuf>,
+    // What `internal_handler:` names resolve to
+    handlers: Arc<HandlerRegistry>,
+}
+
+/// Tool names with what each one runs, for reviewing a file before trusting it.
+pub fn describe_tools(content: &str) -> Result<Vec<(String, String)>, String> {
+    let config: ToolConfig =
+        serde_yaml::from_str(content).map_err(|e| format!("Failed to parse YAML: {}", e))?;
+    Ok(config
+        .tools
+        .into_iter()
+        .map(|def| {
+            let runs = if let Some(handler) = def.internal_handler {
+                format!("(internal handler {})", handler)
+            } else if let Some(wasm) = def.wasm {
+                format!("(wasm module {})", wasm.module)
+            } else if let Some(script) = def.script {
+                match script.tools.as_slice() {
+                    [] => "(script)".to_string(),
+                    tools => format!("(script calling {})", tools.join(", ")),
+                }
+            } else if let Some(pipeline) = def.pipeline {
+                let tools: Vec<_> = pipeline.steps.into_iter().map(|s| s.tool).collect();
+                format!("(pipeline of {})", tools.join(", "))
+            } else {
+                def.command
+            };
+            (def.name, runs)
+        })
+        .collect())
+}
+
+/// Files a tools file runs by path rather than from `$PATH`: commands given
+/// as a path and wasm modules, relative ones resolved against `dir`.
+/// Trusting a project file covers these as well.
+pub fn local_files(content: &str, dir: &Path) -> Result<Vec<PathBuf>, String> {
+    let config: ToolConfig =
+        serde_yaml::from_str(content).map_err(|e| format!("Failed to parse YAML: {}", e))?;
+    Ok(config
+        .tools
+        .into_iter()
+        .filter_map(|def| {
+            if let Some(wasm) = def.wasm {
+                Some(expand_path(&wasm.module).unwrap_or_else(|| PathBuf::from(&wasm.module)))
+            } else if def.internal_handler.is_none()
+                && def.script.is_none()
+                && def.pipeline.is_none()
+                && def.command.contains('/')
+            {
+                Some(PathBuf::from(def.command))
+            } else {
+                None
+            }
+        })
+        .map(|path| dir.join(path))
+        .collect())
 }
 
 impl DynamicToolManager {
     pub fn new() -> Self {
         Self {
             tools: Arc::new(RwLock::new(HashMap::new())),
+            allowed_exec_dirs: Vec::new(),
+            handlers: Arc::default(),
         }
     }
 
+    pub fn with_allowed_exec_dirs(mut self, dirs: Vec<PathBuf>) -> Self {
+        self.allowed_exec_dirs = dirs;
+        self
+    }
+
+    pub fn with_handlers(mut self, handlers: HandlerRegistry) -> Self {
+        self.handlers = Arc::new(handlers);
+        self
+    }
+
     pub async fn load_from_yaml(&self, path: &str) -> Result<(), String> {
         let content =
             fs::read_to_string(path).map_err(|e| format!("Failed to read config file: {}", e))?;
+        self.load_config(&content).await
+    }
 
+    pub(crate) async fn load_config(&self, content: &str) -> Result<(), String> {
         let config: ToolConfig =
-            serde_yaml::from_str(&content).map_err(|e| format!("Failed to parse YAML: {}", e))?;
+            serde_yaml::from_str(content).map_err(|e| format!("Failed to parse YAML: {}", e))?;
+
+        // The same tools untyped, for the effective config
+        let raw: serde_yaml::Value =
+            serde_yaml::from_str(content).map_err(|e| format!("Failed to parse YAML: {}", e))?;
+        let mut definitions = raw
+            .get("tools")
+            .and_then(serde_yaml::Value::as_sequence)
+            .cloned()
+            .unwrap_or_default()
+            .into_iter();
 
-        let mut tools = self.tools.write().await;
+        // Stage every tool and check them all before any becomes callable
+        let mut tools = HashMap::new();
 
-        for tool_def in config.tools {
-            let cli_tool = self.convert_to_cli_tool(tool_def)?;
+        for mut tool_def in config.tools {
+            let definition = definitions.next();
+            let kinds = [
+                tool_def.internal_handler.is_some(),
+                tool_def.wasm.is_some(),
+                tool_def.script.is_some(),
+                tool_def.pipeline.is_some(),
+            ];
+            let in_process = kinds.iter().any(|&kind| kind);
+            if kinds.iter().filter(|&&kind| kind).count() > 1 {
+                return Err(format!(
+                    "Tool '{}' may have only one of internal_handler, wasm, script and pipeline",
+                    tool_def.name
+                ));
+            }
+            if !in_process {
+                // Resolve now so a later PATH change can't swap the binary
+                let Some(resolved) = executable::resolve(&tool_def.command) else {
+                    warn!(
+                        "Tool '{}': command '{}' not found or not executable; skipping it",
+                        tool_def.name, tool_def.command
+                    );
+                    continue;
+                };
+                executable::verify(
+                    &resolved,
+                    tool_def.sha256.as_deref(),
+                    &self.allowed_exec_dirs,
+                )
+                .map_err(|e| format!("Refusing tool '{}': {}", tool_def.name, e))?;
+                debug!("Tool '{}' runs {:?}", tool_def.name, resolved);
+                tool_def.command = resolved.display().to_string();
+            } else if tool_def.sha256.is_some() {
+                warn!("Tool '{}' runs in-process; its sha256 pin has no effect", tool_def.name);
+            }
+
+            let mut cli_tool = self.convert_to_cli_tool(tool_def)?;
+            let mut definition = serde_json::to_value(definition).unwrap_or_default();
+            if let Some(fields) = definition.as_object_mut() {
+                fields.insert("command".to_string(), cli_tool.command.clone().into());
+            }
+            cli_tool.definition = definition;
             tools.insert(cli_tool.name.clone(), cli_tool);
         }
 
+        // Steps may name tools defined later in the file, so check them last
+        for tool in tools.values() {
+            for step in tool.pipeline.iter().flat_map(|p| &p.steps) {
+                match tools.get(&step.tool) {
+                    None => {
+                        return Err(format!(
+                            "Tool '{}': step '{}' calls unknown tool '{}'",
+                            tool.name, step.id, step.tool
+                        ));
+                    }
+                    Some(target) if target.pipeline.is_some() => {
+                        return Err(format!(
+                            "Tool '{}': step '{}' calls pipeline '{}'; pipelines cannot nest",
+                            tool.name, step.id, step.tool
+                        ));
+                    }
+                    Some(_) => {}
+                }
+            }
+            for name in tool.script.iter().flat_map(|script| script.tools()) {
+                match tools.get(name) {
+                    None => {
+                        return Err(format!(
+                            "Tool '{}': script calls unknown tool '{}'",
+                            tool.name, name
+                        ));
+                    }
+                    Some(target) if target.script.is_some() || target.pipeline.is_some() => {
+                        return Err(format!(
+                            "Tool '{}': script calls '{}', a script or pipeline; scripts cannot call those",
+                            tool.name, name
+                        ));
+                    }
+                    Some(_) => {}
+                }
+            }
+        }
+
+        *self.tools.write().await = tools;
         Ok(())
     }
 
@@ -71,7 +325,7 @@ impl DynamicToolManager {
             // 1. Environment variable
             std::env::var("GAMECODE_TOOLS_FILE").ok(),
             // 2. Current directory (project-specific)
-            Some("./tools.yaml".to_string()),
+            Some(PROJECT_TOOLS_FILE.to_string()),
             // 3. User config directory (user defaults)
             home::home_dir().map(|d| {
                 d.join(".config/gamecode-mcp/tools.yaml")
@@ -82,10 +336,21 @@ impl DynamicToolManager {
 
         for location in locations.into_iter().flatten() {
             debug!("Checking for tools config at: {}", location);
-            if std::path::Path::new(&location).exists() {
-                info!("Loading tools from: {}", location);
-                return self.load_from_yaml(&location).await;
+            if !std::path::Path::new(&location).exists() {
+                continue;
+            }
+            if location == PROJECT_TOOLS_FILE {
+                let store = match TrustStore::default_path() {
+                    Some(path) => TrustStore::load(&path)?,
+                    None => TrustStore::default(),
+                };
+                if !self.load_project_file(Path::new(&location), &store).await? {
+                    continue;
+                }
+                return Ok(());
             }
+            info!("Loading tools from: {}", location);
+            return self.load_from_yaml(&location).await;
         }
 
         Err("No tools.yaml found!\n\n\
@@ -100,7 +365,52 @@ impl DynamicToolManager {
             .to_string())
     }
 
+    /// Load a project-local tools file if the user has trusted its current
+    /// contents. Returns `false`, with a warning, when it isn't trusted.
+    async fn load_project_file(&self, path: &Path, store: &TrustStore) -> Result<bool, String> {
+        // Check and parse the same bytes, so an edit in between can't slip in
+        let content =
+            fs::read_to_string(path).map_err(|e| format!("Failed to read config file: {}", e))?;
+        let local = local_files(&content, path.parent().unwrap_or(Path::new(".")))?;
+        match store.check(path, content.as_bytes(), &local) {
+            Trust::Trusted => {
+                info!("Loading tools from: {}", path.display());
+                self.load_config(&content).await?;
+                Ok(true)
+            }
+            Trust::Untrusted => {
+                warn!(
+                    "Ignoring untrusted {}; review it and run `gamecode-mcp trust {}` to allow it",
+                    path.display(),
+                    path.display()
+                );
+                Ok(false)
+            }
+            Trust::Changed => {
+                warn!(
+                    "Ignoring {}: it, or a local file it runs, changed since it was trusted; \
+                     review it and run `gamecode-mcp trust {}` again",
+                    path.display(),
+                    path.display()
+                );
+                Ok(false)
+            }
+        }
+    }
+
     fn convert_to_cli_tool(&self, def: ToolDefinition) -> Result<CliTool, String> {
+        let handler = match &def.internal_handler {
+            Some(name) => Some(self.handlers.get(name).ok_or_else(|| {
+                format!(
+                    "Tool '{}': unknown internal handler '{}' (available: {})",
+                    def.name,
+                    name,
+                    self.handlers.names().collect::<Vec<_>>().join(", ")
+                )
+            })?),
+            None => None,
+        };
+
         let mut args = Vec::new();
 
         for arg_def in def.args {
@@ -112,21 +422,167 @@ impl DynamicToolManager {
                 _ => return Err(format!("Unknown arg type: {}", arg_def.arg_type)),
             };
 
+            if let Some(handler) = &handler
+                && !handler.args().iter().any(|a| a.name == arg_def.name)
+            {
+                return Err(format!(
+                    "Tool '{}': internal handler '{}' has no argument '{}'",
+                    def.name,
+                    handler.name(),
+                    arg_def.name
+                ));
+            }
+
             args.push(CliArg {
                 name: arg_def.name,
                 description: arg_def.description,
                 required: arg_def.required,
                 arg_type,
                 cli_flag: arg_def.cli_flag,
+                secret: arg_def.secret,
             });
         }
 
+        // Without its own list, a handler's tool takes what the handler declares
+        if let Some(handler) = &handler
+            && args.is_empty()
+        {
+            args = handler
+                .args()
+                .into_iter()
+                .map(|arg| CliArg {
+                    name: arg.name.to_string(),
+                    description: arg.description.to_string(),
+                    required: arg.required,
+                    arg_type: arg.arg_type,
+                    cli_flag: None,
+                    secret: None,
+                })
+                .collect();
+        }
+
+        // Settings for spawning a command mean nothing to in-process tools
+        let in_process = [
+            (def.internal_handler.is_some(), "uses an internal handler", "it runs inside the server"),
+            (def.wasm.is_some(), "runs a wasm module", "set fuel, timeout_secs and memory under wasm: instead"),
+            (def.script.is_some(), "runs a script", "set max_operations and timeout_secs under script: instead"),
+            (def.pipeline.is_some(), "is a pipeline", "its steps use their own tools' settings"),
+        ]
+        .into_iter()
+        .find_map(|(set, kind, hint)| set.then_some((kind, hint)));
+        if let Some((kind, hint)) = in_process
+            && (def.sandbox.is_some() || def.limits.is_some() || !def.env.is_empty())
+        {
+            warn!(
+                "Tool '{}' {}; its sandbox, limits and env have no effect ({})",
+                def.name, kind, hint
+            );
+        }
+        if let Some(pipeline) = &def.pipeline {
+            pipeline
+                .validate()
+                .map_err(|e| format!("Tool '{}': {}", def.name, e))?;
+        }
+
+        let script = def
+            .script
+            .as_ref()
+            .map(ScriptTool::load)
+            .transpose()
+            .map_err(|e| format!("Tool '{}': {}", def.name, e))?;
+
+        let wasm = def
+            .wasm
+            .as_ref()
+            .map(WasmTool::load)
+            .transpose()
+            .map_err(|e| format!("Tool '{}': {}", def.name, e))?;
+
+        let redactor = Redactor::new(def.redact.as_ref())
+            .map_err(|e| format!("Tool '{}': {}", def.name, e))?;
+
+        if let Some(content) = &def.content {
+            content
+                .validate()
+                .map_err(|e| format!("Tool '{}': {}", def.name, e))?;
+            if def.output.is_some() || def.output_schema.is_some() {
+                return Err(format!(
+                    "Tool '{}': content can't be combined with output or output_schema",
+                    def.name
+                ));
+            }
+        }
+        if (def.output.is_some() || def.content.is_some()) && in_process.is_some() {
+            warn!(
+                "Tool '{}' does not run a command; its output and content settings have no effect",
+                def.name
+            );
+        }
+        let output = def
+            .output
+            .as_ref()
+            .map(OutputFilter::new)
+            .transpose()
+            .map_err(|e| format!("Tool '{}': {}", def.name, e))?;
+        if output.as_ref().is_some_and(OutputFilter::has_select)
+            && args.iter().any(|arg| arg.name == SELECT_PARAM)
+        {
+            return Err(format!(
+                "Tool '{}': output.select needs the '{}' parameter, but an argument has that name",
+                def.name, SELECT_PARAM
+            ));
+        }
+
+        let output_schema = def
+            .output_schema
+            .map(|schema| OutputSchema::new(schema, def.output_validation))
+            .transpose()
+            .map_err(|e| format!("Tool '{}': {}", def.name, e))?;
+        if let (Some(schema), Some(example)) = (&output_schema, &def.example_output) {
+            schema.validate(example).map_err(|e| {
+                format!("Tool '{}': example_output does not match output_schema: {}", def.name, e)
+            })?;
+        }
+
+        if def.cache.is_some() && !def.idempotent {
+            return Err(format!(
+                "Tool '{}': cache requires idempotent: true",
+                def.name
+            ));
+        }
+
+        let progress = def
+            .progress
+            .as_ref()
+            .map(ProgressParser::new)
+            .transpose()
+            .map_err(|e| format!("Tool '{}': {}", def.name, e))?;
+
         Ok(CliTool {
             name: def.name,
             description: def.description,
             command: def.command,
             args,
-            internal_handler: def.internal_handler,
+            internal_handler: handler,
+            wasm,
+            script,
+            pipeline: def.pipeline,
+            access: ToolAccess {
+                roles: def.roles,
+                allowed_principals: def.allowed_principals,
+            },
+            sandbox: def.sandbox,
+            limits: def.limits,
+            env: def.env.into_iter().collect(),
+            redactor,
+            background: def.background,
+            progress,
+            cache: def.cache,
+            output,
+            output_schema,
+            example_output: def.example_output,
+            content: def.content,
+            definition: serde_json::Value::Null,
         })
     }
 
@@ -134,22 +590,235 @@ impl DynamicToolManager {
         &self,
         tool_name: &str,
         params: HashMap<String, serde_json::Value>,
-    ) -> Result<String, String> {
+    ) -> Result<String, ToolError> {
+        let control = CallControl::default();
+        self.execute_tool_with_steps(tool_name, params, &control).await.0
+    }
+
+    /// Run a tool, also returning audit entries for the calls it made if it
+    /// is a pipeline or script. `control` carries progress out and
+    /// cancellation in.
+    pub async fn execute_tool_with_steps(
+        &self,
+        tool_name: &str,
+        params: HashMap<String, serde_json::Value>,
+        control: &CallControl,
+    ) -> (Result<String, ToolError>, Vec<AuditEntry>) {
+        let tool = match self.tool(tool_name).await {
+            Ok(tool) => tool,
+            Err(e) => return (Err(e), Vec::new()),
+        };
+        // A caller who can't run every tool this one calls can't run it
+        let steps = tool.pipeline.iter().flat_map(|p| &p.steps).map(|step| step.tool.as_str());
+        let scripted = tool.script.iter().flat_map(|script| script.tools()).map(String::as_str);
+        let called = steps.chain(scripted);
+        if let Err(denial) = self.authorize_all(&tool.name, called, &control.identity.caller).await {
+            return (Err(ToolError::Denied(denial)), Vec::new());
+        }
+        let log = StepLog::default();
+        let result = match &tool.pipeline {
+            // Cancelling drops the steps, which stops their commands
+            Some(pipeline) => match tool.check_required(&params) {
+                Ok(()) => {
+                    let run = pipeline.run(self, &tool.name, params, control, &log);
+                    control.or_cancel(run).await.and_then(|output| {
+                        tool.check_output(&output)?;
+                        Ok(output)
+                    })
+                }
+                Err(e) => Err(e.into()),
+            },
+            None => self.run(&tool, params, control, &log).await,
+        };
+        let steps = std::mem::take(&mut *log.lock().unwrap());
+        (result, steps)
+    }
+
+    /// Run a tool as a pipeline step, checked and filtered as if the caller
+    /// had called it. Pipelines can't call pipelines, which keeps a chain of
+    /// steps from looping.
+    pub async fn execute_step(
+        &self,
+        tool_name: &str,
+        params: HashMap<String, serde_json::Value>,
+        control: &CallControl,
+        log: &StepLog,
+        entry: AuditEntry,
+    ) -> (AuditEntry, Result<String, ToolError>) {
+        let tool = match self.tool(tool_name).await {
+            Ok(tool) => tool,
+            Err(e) => return (entry, Err(e)),
+        };
+        if tool.pipeline.is_some() {
+            let e = format!("'{}' is a pipeline; pipelines cannot nest", tool_name);
+            return (entry, Err(e.into()));
+        }
+        self.run_nested(&tool, params, control, log, entry).await
+    }
+
+    /// Run a tool for a script's `call_tool`, checked and filtered as if the
+    /// caller had called it, and logged as "<script>/<tool>". Scripts can only
+    /// call plain tools, which keeps a chain of calls from looping.
+    pub async fn execute_from_script(
+        &self,
+        script: &str,
+        tool_name: &str,
+        params: HashMap<String, serde_json::Value>,
+        control: &CallControl,
+        log: &StepLog,
+    ) -> Result<String, ToolError> {
+        let tool = self.tool(tool_name).await?;
+        if tool.script.is_some() || tool.pipeline.is_some() {
+            return Err(format!(
+                "'{}' is a script or pipeline; scripts cannot call those",
+                tool_name
+            )
+            .into());
+        }
+        let entry = AuditEntry::new(tool_name).with_pipeline(&format!("{}/{}", script, tool_name));
+        let started = Instant::now();
+        let (entry, result) = self.run_nested(&tool, params, control, log, entry).await;
+        log.lock().unwrap().push(entry.finish_result(&result, started.elapsed()));
+        result
+    }
+
+    // Everything but pipelines; a script logs its calls to `log`
+    async fn run(
+        &self,
+        tool: &CliTool,
+        params: HashMap<String, serde_json::Value>,
+        control: &CallControl,
+        log: &StepLog,
+    ) -> Result<String, ToolError> {
+        let output = match &tool.script {
+            Some(script) => {
+                tool.check_required(&params)?;
+                let run = script.run(self.clone(), &tool.name, params, control, log);
+                control.or_cancel(run).await?
+            }
+            None => tool.execute_with(params, control).await?,
+        };
+        tool.check_output(&output)?;
+        Ok(output)
+    }
+
+    // A call a tool makes for its caller: the caller's access and the
+    // policy rules apply to it, and its own `redact` to what it returns
+    async fn run_nested(
+        &self,
+        tool: &CliTool,
+        params: HashMap<String, serde_json::Value>,
+        control: &CallControl,
+        log: &StepLog,
+        entry: AuditEntry,
+    ) -> (AuditEntry, Result<String, ToolError>) {
+        let entry = match control.identity.check(&tool.name, &tool.access, &params).await {
+            Ok(None) => entry,
+            Ok(Some(confirmed)) => entry.with_reason(&confirmed),
+            Err(denial) => return (entry, Err(ToolError::Denied(denial))),
+        };
+        // Progress is the outer call's to report
+        let control = CallControl {
+            progress: None,
+            ..control.clone()
+        };
+        let result = self.run(tool, params, &control, log).await;
+
+        let mut redactions = Redactions::new();
+        let result = match &tool.content {
+            Some(_) => tool.redactor.apply_content(result, &mut redactions),
+            None => tool.redactor.apply(result, &mut redactions),
+        };
+        if !redactions.is_empty() {
+            info!("Redacted {:?} from {} output", redactions, tool.name);
+        }
+        (entry.with_redactions(redactions), result)
+    }
+
+    // Check `caller` against each tool another one calls, before running any
+    async fn authorize_all<'a>(
+        &self,
+        tool_name: &str,
+        called: impl Iterator<Item = &'a str>,
+        caller: &Caller,
+    ) -> Result<(), Denial> {
+        let tools = self.tools.read().await;
+        for name in called {
+            if let Some(Err(reason)) = tools.get(name).map(|tool| tool.access.check(caller)) {
+                warn!("Denied {} to {}: it calls {}: {}", tool_name, caller.name, name, reason);
+                return Err(Denial {
+                    message: format!(
+                        "Access denied: '{}' calls tool '{}', which you may not call",
+                        tool_name, name
+                    ),
+                    ..Denial::forbidden(name, format!("calls '{}': {}", name, reason))
+                });
+            }
+        }
+        Ok(())
+    }
+
+    // A copy, so no lock is held while the tool runs
+    async fn tool(&self, tool_name: &str) -> Result<CliTool, ToolError> {
         let tools = self.tools.read().await;
+        tools
+            .get(tool_name)
+            .cloned()
+            .ok_or_else(|| format!("Tool not found: {}", tool_name).into())
+    }
 
-        if let Some(tool) = tools.get(tool_name) {
-            tool.execute(params).await
-        } else {
-            Err(format!("Tool not found: {}", tool_name))
+    /// The output filters for a tool, if it exists.
+    pub async fn redactor(&self, tool_name: &str) -> Option<Redactor> {
+        let tools = self.tools.read().await;
+        tools.get(tool_name).map(|tool| tool.redactor.clone())
+    }
+
+    /// How a tool's output is returned.
+    pub async fn output_kind(&self, tool_name: &str) -> OutputKind {
+        let tools = self.tools.read().await;
+        match tools.get(tool_name) {
+            Some(tool) if tool.content.is_some() => OutputKind::Content,
+            Some(tool) if tool.output_schema.is_some() => OutputKind::Structured,
+            _ => OutputKind::Text,
         }
     }
 
-    pub async fn list_tools(&self) -> Vec<(String, String)> {
+    /// Whether a tool is marked `async: true`.
+    pub async fn is_background(&self, tool_name: &str) -> bool {
         let tools = self.tools.read().await;
+        tools.get(tool_name).is_some_and(|tool| tool.background)
+    }
+
+    /// A tool's cache settings, if it is cached.
+    pub async fn cache_config(&self, tool_name: &str) -> Option<CacheConfig> {
+        let tools = self.tools.read().await;
+        tools.get(tool_name).and_then(|tool| tool.cache.clone())
+    }
+
+    /// Who may call a tool. Unknown tools are open, so that execution
+    /// reports them as not found.
+    pub async fn access(&self, tool_name: &str) -> ToolAccess {
+        let tools = self.tools.read().await;
+        tools.get(tool_name).map(|tool| tool.access.clone()).unwrap_or_default()
+    }
+
+    /// Tools visible to `caller`.
+    pub async fn list_tools(&self, caller: &Caller) -> Vec<ToolInfo> {
+        let tools = self.tools.read().await;
+        let mut tools = tools
+            .values()
+            .filter(|tool| tool.access.check(caller).is_ok())
+            .map(|tool| ToolInfo {
+                name: tool.name.clone(),
+                description: tool.description.clone(),
+                args: tool.args.clone(),
+                definition: tool.definition.clone(),
+                output_schema: tool.output_schema.as_ref().map(|s| s.schema().clone()),
+                example_output: tool.example_output.clone(),
+            })
+            .collect::<Vec<_>>();
+        tools.sort_by(|a, b| a.name.cmp(&b.name));
         tools
-            .iter()
-            .map(|(name, tool)| (name.clone(), tool.description.clone()))
-            .collect()
     }
 }
 
@@ -170,6 +839,7 @@ impl DynamicToolManager {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::rbac::RoleBindings;
     use tempfile::TempDir;
     use std::fs::File;
     use std::io::Write;
@@ -201,10 +871,10 @@ tools:
         
         assert!(result.is_ok());
         
-        let tools = manager.list_tools().await;
+        let tools = manager.list_tools(&RoleBindings::default().caller(None)).await;
         assert_eq!(tools.len(), 1);
-        assert_eq!(tools[0].0, "test_tool");
-        assert_eq!(tools[0].1, "A test tool");
+        assert_eq!(tools[0].name, "test_tool");
+        assert_eq!(tools[0].description, "A test tool");
     }
 
     #[tokio::test]
@@ -283,7 +953,7 @@ tools:
         let result = manager.execute_tool("nonexistent", HashMap::new()).await;
         
         assert!(result.is_err());
-        assert_eq!(result.unwrap_err(), "Tool not found: nonexistent");
+        assert_eq!(result.unwrap_err().to_string(), "Tool not found: nonexistent");
     }
 
     #[tokio::test]
@@ -326,4 +996,343 @@ tools:
         assert_eq!(parsed["result"], 7);
         assert_eq!(parsed["operation"], "addition");
     }
+
+    #[tokio::test]
+    async fn test_internal_handlers_are_checked_at_load() {
+        let manager = DynamicToolManager::new();
+        let err = manager
+            .load_config("tools:\n  - name: x\n    description: X\n    command: internal\n    internal_handler: unknown_handler\n")
+            .await
+            .unwrap_err();
+        assert!(err.contains("unknown internal handler 'unknown_handler'"), "{}", err);
+        assert!(err.contains("add, list_files, multiply"), "{}", err);
+
+        let err = manager
+            .load_config("tools:\n  - name: x\n    description: X\n    command: internal\n    internal_handler: add\n    args:\n      - { name: c, description: C, required: true, type: number, cli_flag: null }\n")
+            .await
+            .unwrap_err();
+        assert!(err.contains("has no argument 'c'"), "{}", err);
+
+        // Without an args list the handler's declared arguments are used
+        manager
+            .load_config("tools:\n  - name: sum\n    description: Add\n    command: internal\n    internal_handler: add\n")
+            .await
+            .unwrap();
+        let tools = manager.tools.read().await;
+        let names: Vec<_> = tools["sum"].args.iter().map(|a| a.name.as_str()).collect();
+        assert_eq!(names, ["a", "b"]);
+    }
+
+    #[tokio::test]
+    async fn test_cache_requires_idempotent() {
+        let manager = DynamicToolManager::new();
+        let tool = "tools:\n  - name: sum\n    description: Add\n    command: internal\n    internal_handler: add\n    cache: { ttl: 60s }\n";
+        let err = manager.load_config(tool).await.unwrap_err();
+        assert!(err.contains("cache requires idempotent: true"), "{}", err);
+
+        manager
+            .load_config(&format!("{}    idempotent: true\n", tool))
+            .await
+            .unwrap();
+        let config = manager.cache_config("sum").await.unwrap();
+        assert_eq!(config.ttl, chrono::Duration::seconds(60));
+    }
+
+    #[tokio::test]
+    async fn test_pipeline_binds_steps_and_logs_each_call() {
+        let yaml = r#"
+tools:
+  - name: scale
+    description: Sum, then multiply each factor by the sum
+    command: pipeline
+    pipeline:
+      max_parallel: 2
+      steps:
+        - id: sum
+          tool: add
+          params: { a: $.params.a, b: $.params.b }
+        - id: scaled
+          tool: multiply
+          foreach: $.params.factors
+          params: { a: $.item, b: $.steps.sum.result }
+        - id: never
+          tool: add
+          when: $.params.missing
+      output:
+        total: $.steps.sum.result
+        scaled: $.steps.scaled[*].result
+  - name: add
+    description: Add
+    command: internal
+    internal_handler: add
+  - name: multiply
+    description: Multiply
+    command: internal
+    internal_handler: multiply
+"#;
+        let manager = DynamicToolManager::new();
+        manager.load_config(yaml).await.unwrap();
+
+        let params: HashMap<String, serde_json::Value> =
+            serde_json::from_value(serde_json::json!({ "a": 1, "b": 2, "factors": [1, 2, 3] }))
+                .unwrap();
+        let (result, steps) = manager.execute_tool_with_steps("scale", params, &CallControl::default()).await;
+        assert_eq!(result.unwrap(), r#"{"scaled":[3,6,9],"total":3}"#);
+        let mut labels: Vec<_> = steps.iter().map(|e| e.pipeline.clone().unwrap()).collect();
+        labels.sort();
+        assert_eq!(
+            labels,
+            ["scale/scaled[0]", "scale/scaled[1]", "scale/scaled[2]", "scale/sum"]
+        );
+
+        // A failing step names itself
+        let params = HashMap::from([("a".to_string(), serde_json::json!("x"))]);
+        let err = manager.execute_tool("scale", params).await.unwrap_err();
+        assert!(err.to_string().starts_with("Step 'scale/sum' (add) failed"), "{}", err);
+
+        let rejected = DynamicToolManager::new();
+        let err = rejected
+            .load_config(&yaml.replace("tool: multiply", "tool: divide"))
+            .await
+            .unwrap_err();
+        assert!(err.contains("step 'scaled' calls unknown tool 'divide'"), "{}", err);
+        assert!(rejected.list_tools(&RoleBindings::default().caller(None)).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_pipeline_steps_are_checked_as_the_caller() {
+        let temp_dir = TempDir::new().unwrap();
+        fs::write(temp_dir.path().join("CUST-123456.txt"), "").unwrap();
+        let policy_path = temp_dir.path().join("policy.yaml");
+        fs::write(
+            &policy_path,
+            "rules:\n  - name: no-etc\n    tools: [ls]\n    when: 'params.path == \"/etc\"'\n    effect: deny\n",
+        )
+        .unwrap();
+        let yaml = r#"
+tools:
+  - name: survey
+    description: List a directory
+    command: pipeline
+    pipeline:
+      steps:
+        - { id: ls, tool: ls, params: { path: $.params.dir } }
+  - name: audit_survey
+    description: List, then run an admin tool
+    command: pipeline
+    pipeline:
+      steps:
+        - { id: ls, tool: ls, params: { path: $.params.dir } }
+        - { id: admin, tool: admin_only }
+  - name: ls
+    description: List files
+    command: internal
+    internal_handler: list_files
+    redact:
+      patterns: [{ name: customer_id, regex: "CUST-[0-9]{6}" }]
+  - name: admin_only
+    description: Admin tool
+    command: internal
+    internal_handler: list_files
+    roles: [admin]
+"#;
+        let manager = DynamicToolManager::new();
+        manager.load_config(yaml).await.unwrap();
+        let policy = Arc::new(crate::policy::Policy::load(&policy_path).unwrap());
+        let control = CallControl {
+            identity: crate::policy::CallIdentity {
+                caller: policy.roles.caller(None),
+                policy,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let dir = |path: &Path| {
+            HashMap::from([("dir".to_string(), serde_json::json!(path.to_str().unwrap()))])
+        };
+
+        // The step's own redact block applies to what it passes on
+        let (result, steps) = manager
+            .execute_tool_with_steps("survey", dir(temp_dir.path()), &control)
+            .await;
+        let output = result.unwrap();
+        assert!(!output.contains("CUST-123456"), "{}", output);
+        assert_eq!(steps[0].redactions.get("customer_id"), Some(&1));
+
+        // Policy rules see the step's own parameters
+        let (result, steps) = manager
+            .execute_tool_with_steps("survey", dir(Path::new("/etc")), &control)
+            .await;
+        assert!(matches!(result, Err(ToolError::Denied(ref d)) if d.kind == "policy_denied"));
+        assert_eq!(steps[0].status, Some(crate::audit::AuditStatus::Denied));
+
+        // A step the caller may not call stops the pipeline before it starts
+        let (result, steps) = manager
+            .execute_tool_with_steps("audit_survey", dir(temp_dir.path()), &control)
+            .await;
+        assert!(matches!(result, Err(ToolError::Denied(ref d)) if d.kind == "forbidden"));
+        assert!(steps.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_script_calls_are_checked_as_the_caller() {
+        let temp_dir = TempDir::new().unwrap();
+        fs::write(temp_dir.path().join("CUST-123456.txt"), "").unwrap();
+        let policy_path = temp_dir.path().join("policy.yaml");
+        fs::write(
+            &policy_path,
+            "rules:\n  - name: no-etc\n    tools: [ls]\n    when: 'params.path == \"/etc\"'\n    effect: deny\n",
+        )
+        .unwrap();
+        let yaml = r#"
+tools:
+  - name: lister
+    description: List a directory, catching errors
+    command: script
+    script:
+      tools: [ls]
+      source: 'try { call_tool("ls", #{ path: params.dir }) } catch { "caught" }'
+  - name: ls
+    description: List files
+    command: internal
+    internal_handler: list_files
+    redact:
+      patterns: [{ name: customer_id, regex: "CUST-[0-9]{6}" }]
+"#;
+        let manager = DynamicToolManager::new();
+        manager.load_config(yaml).await.unwrap();
+        let policy = Arc::new(crate::policy::Policy::load(&policy_path).unwrap());
+        let control = CallControl {
+            identity: crate::policy::CallIdentity {
+                caller: policy.roles.caller(None),
+                policy,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let dir = |path: &Path| {
+            HashMap::from([("dir".to_string(), serde_json::json!(path.to_str().unwrap()))])
+        };
+
+        let (result, calls) = manager
+            .execute_tool_with_steps("lister", dir(temp_dir.path()), &control)
+            .await;
+        assert!(!result.unwrap().contains("CUST-123456"));
+        assert_eq!(calls[0].pipeline.as_deref(), Some("lister/ls"));
+        assert_eq!(calls[0].redactions.get("customer_id"), Some(&1));
+
+        // Catching the error doesn't get a script past a denial
+        let (result, calls) = manager
+            .execute_tool_with_steps("lister", dir(Path::new("/etc")), &control)
+            .await;
+        assert!(matches!(result, Err(ToolError::Denied(ref d)) if d.kind == "policy_denied"));
+        assert_eq!(calls[0].status, Some(crate::audit::AuditStatus::Denied));
+
+        let rejected = DynamicToolManager::new();
+        let err = rejected
+            .load_config(&yaml.replace("tools: [ls]", "tools: [ls, rm]"))
+            .await
+            .unwrap_err();
+        assert!(err.contains("script calls unknown tool 'rm'"), "{}", err);
+        assert!(rejected.list_tools(&RoleBindings::default().caller(None)).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_tools_hidden_from_callers_without_role() {
+        let temp_dir = TempDir::new().unwrap();
+        let yaml_path = temp_dir.path().join("rbac.yaml");
+
+        let yaml_content = r#"
+tools:
+  - name: read_logs
+    description: Read-only tool
+    command: internal
+    args: []
+    internal_handler: list_files
+    roles: [readonly, admin]
+  - name: deploy
+    description: Admin tool
+    command: internal
+    args: []
+    internal_handler: list_files
+    roles: [admin]
+    allowed_principals: [release-bot]
+"#;
+        fs::write(&yaml_path, yaml_content).unwrap();
+
+        let manager = DynamicToolManager::new();
+        manager.load_from_yaml(yaml_path.to_str().unwrap()).await.unwrap();
+
+        let bindings: RoleBindings = serde_yaml::from_str(
+            "default_roles: [readonly]\nusers:\n  alice: [admin]\n",
+        )
+        .unwrap();
+        let as_user = |name: &str| {
+            bindings.caller(Some(&crate::auth::Principal {
+                name: name.to_string(),
+                method: crate::auth::AuthMethod::Token,
+                groups: vec![],
+            }))
+        };
+
+        let junior = as_user("junior");
+        let visible = manager.list_tools(&junior).await;
+        assert_eq!(visible.len(), 1);
+        assert_eq!(visible[0].name, "read_logs");
+        assert!(manager.access("deploy").await.check(&junior).is_err());
+        assert!(manager.access("read_logs").await.check(&junior).is_ok());
+
+        assert_eq!(manager.list_tools(&as_user("alice")).await.len(), 2);
+        assert!(manager.access("deploy").await.check(&as_user("release-bot")).is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_refused_pin_loads_no_tools() {
+        let manager = DynamicToolManager::new();
+        let yaml = format!(
+            "tools:\n  - name: sum\n    description: Add\n    command: internal\n    internal_handler: add\n  - name: tampered\n    description: T\n    command: sh\n    sha256: \"{}\"\n",
+            "0".repeat(64)
+        );
+        let err = manager.load_config(&yaml).await.unwrap_err();
+        assert!(err.starts_with("Refusing tool 'tampered'"), "{}", err);
+        assert!(manager.list_tools(&RoleBindings::default().caller(None)).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_project_file_needs_trust() {
+        let temp_dir = TempDir::new().unwrap();
+        let yaml_path = temp_dir.path().join("tools.yaml");
+        let yaml_content = "tools:\n  - name: sum\n    description: Add\n    command: internal\n    args: []\n    internal_handler: add\n";
+        fs::write(&yaml_path, yaml_content).unwrap();
+
+        let mut store = TrustStore::load(&temp_dir.path().join("trusted.json")).unwrap();
+        let manager = DynamicToolManager::new();
+        let caller = RoleBindings::default().caller(None);
+        assert!(!manager.load_project_file(&yaml_path, &store).await.unwrap());
+        assert!(manager.list_tools(&caller).await.is_empty());
+
+        store.trust(&yaml_path, yaml_content.as_bytes(), &[]);
+        assert!(manager.load_project_file(&yaml_path, &store).await.unwrap());
+        assert_eq!(manager.list_tools(&caller).await.len(), 1);
+
+        // Edits invalidate the approval
+        fs::write(&yaml_path, yaml_content.replace("Add", "Totally harmless")).unwrap();
+        let manager = DynamicToolManager::new();
+        assert!(!manager.load_project_file(&yaml_path, &store).await.unwrap());
+
+        // So do edits to a script it runs by path
+        let script = temp_dir.path().join("deploy.sh");
+        fs::write(&script, "#!/bin/sh\necho '{}'\n").unwrap();
+        let yaml_content = format!(
+            "tools:\n  - name: deploy\n    description: Deploy\n    command: {}\n",
+            script.display()
+        );
+        fs::write(&yaml_path, &yaml_content).unwrap();
+        let local = local_files(&yaml_content, temp_dir.path()).unwrap();
+        assert_eq!(local, vec![script.clone()]);
+        store.trust(&yaml_path, yaml_content.as_bytes(), &local);
+        fs::write(&script, "#!/bin/sh\nrm -rf ~\n").unwrap();
+        assert!(!manager.load_project_file(&yaml_path, &store).await.unwrap());
+        assert!(manager.list_tools(&caller).await.is_empty());
+    }
 }
@@ -0,0 +1,147 @@
+//! Resolving and vetting tool commands when tools.yaml is loaded, so that a
+//! later change to PATH or to a writable directory can't swap the binary.
+
+use sha2::{Digest, Sha256};
+use std::ffi::OsStr;
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+
+/// Find `command` the way a shell would and return its canonical path.
+/// Relative PATH entries (including an empty one, meaning the current
+/// directory) are skipped, as they are the usual hijacking vector.
+pub fn resolve(command: &str) -> Option<PathBuf> {
+    resolve_in(command, &std::env::var_os("PATH")?)
+}
+
+fn resolve_in(command: &str, path: &OsStr) -> Option<PathBuf> {
+    let candidate = if command.contains('/') {
+        PathBuf::from(command)
+    } else {
+        std::env::split_paths(path)
+            .filter(|dir| dir.is_absolute())
+            .map(|dir| dir.join(command))
+            .find(|candidate| is_executable(candidate))?
+    };
+    let resolved = fs::canonicalize(candidate).ok()?;
+    is_executable(&resolved).then_some(resolved)
+}
+
+fn is_executable(path: &Path) -> bool {
+    fs::metadata(path).is_ok_and(|m| m.is_file() && m.permissions().mode() & 0o111 != 0)
+}
+
+/// Check that a resolved command is safe to run: inside an allowed
+/// directory (when any are configured), not replaceable by other users, and
+/// matching its pinned SHA-256.
+pub fn verify(path: &Path, sha256: Option<&str>, allowed_dirs: &[PathBuf]) -> Result<(), String> {
+    if !allowed_dirs.is_empty() && !allowed_dirs.iter().any(|dir| path.starts_with(dir)) {
+        return Err(format!(
+            "{:?} is not in an allowed executable directory ({})",
+            path,
+            allowed_dirs
+                .iter()
+                .map(|d| d.display().to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        ));
+    }
+
+    // Anyone who can write the binary or one of its directories can run code
+    // as the server. Sticky directories like /tmp only let owners replace files.
+    for ancestor in path.ancestors() {
+        let metadata = fs::metadata(ancestor).map_err(|e| format!("{:?}: {}", ancestor, e))?;
+        let mode = metadata.permissions().mode();
+        let sticky_dir = metadata.is_dir() && mode & 0o1000 != 0;
+        if mode & 0o002 != 0 && !sticky_dir {
+            return Err(format!("{:?} is world-writable", ancestor));
+        }
+    }
+
+    if let Some(expected) = sha256 {
+        let content = fs::read(path).map_err(|e| format!("Failed to read {:?}: {}", path, e))?;
+        let actual = hex::encode(Sha256::digest(&content));
+        if !actual.eq_ignore_ascii_case(expected.trim()) {
+            return Err(format!(
+                "{:?} has SHA-256 {}, but tools.yaml pins {}",
+                path, actual, expected
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Canonicalize the `--allowed-exec-dirs` entries; missing ones are dropped.
+pub fn allowed_dirs(spec: &str) -> Vec<PathBuf> {
+    std::env::split_paths(spec)
+        .filter(|dir| !dir.as_os_str().is_empty())
+        .filter_map(|dir| match fs::canonicalize(&dir) {
+            Ok(dir) => Some(dir),
+            Err(e) => {
+                tracing::warn!("Ignoring allowed executable directory {:?}: {}", dir, e);
+                None
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn script(dir: &Path, name: &str, mode: u32) -> PathBuf {
+        let path = dir.join(name);
+        fs::write(&path, "#!/bin/sh\necho '{}'\n").unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(mode)).unwrap();
+        path
+    }
+
+    #[test]
+    fn test_resolve_ignores_relative_path_entries() {
+        let temp_dir = TempDir::new().unwrap();
+        let bin = temp_dir.path().join("bin");
+        fs::create_dir(&bin).unwrap();
+        script(&bin, "tool", 0o755);
+        script(&bin, "plain", 0o644);
+
+        let relative = std::env::join_paths(["", "bin"]).unwrap();
+        assert!(resolve_in("tool", &relative).is_none());
+
+        let path = std::env::join_paths([PathBuf::from(""), bin.clone()]).unwrap();
+        let resolved = resolve_in("tool", &path).unwrap();
+        assert_eq!(resolved, fs::canonicalize(bin.join("tool")).unwrap());
+
+        // Not executable, or not there at all
+        assert!(resolve_in("plain", &path).is_none());
+        assert!(resolve_in("missing", &path).is_none());
+        assert!(resolve("/bin/sh").is_some_and(|p| p.is_absolute()));
+    }
+
+    #[test]
+    fn test_verify() {
+        let temp_dir = TempDir::new().unwrap();
+        let dir = fs::canonicalize(temp_dir.path()).unwrap();
+        fs::set_permissions(&dir, fs::Permissions::from_mode(0o755)).unwrap();
+        let tool = script(&dir, "tool", 0o755);
+        let digest = hex::encode(Sha256::digest(fs::read(&tool).unwrap()));
+
+        assert!(verify(&tool, Some(&digest), &[]).is_ok());
+        assert!(verify(&tool, Some(&digest.to_uppercase()), std::slice::from_ref(&dir)).is_ok());
+
+        let err = verify(&tool, Some(&"0".repeat(64)), &[]).unwrap_err();
+        assert!(err.contains("pins"), "{}", err);
+
+        let err = verify(&tool, None, &[PathBuf::from("/usr/bin")]).unwrap_err();
+        assert!(err.contains("not in an allowed executable directory"), "{}", err);
+
+        fs::set_permissions(&tool, fs::Permissions::from_mode(0o757)).unwrap();
+        assert!(verify(&tool, None, &[]).unwrap_err().contains("world-writable"));
+
+        fs::set_permissions(&tool, fs::Permissions::from_mode(0o755)).unwrap();
+        fs::set_permissions(&dir, fs::Permissions::from_mode(0o777)).unwrap();
+        assert!(verify(&tool, None, &[]).unwrap_err().contains("world-writable"));
+        fs::set_permissions(&dir, fs::Permissions::from_mode(0o1777)).unwrap();
+        assert!(verify(&tool, None, &[]).is_ok());
+    }
+}
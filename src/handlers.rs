@@ -0,0 +1,294 @@
+//! Internal handlers: tools implemented in Rust and compiled into the server.
+//!
+//! A tool with `internal_handler: NAME` runs the handler registered under
+//! that name instead of spawning a command. To add one, implement
+//! [`InternalHandler`] and register it in `main` next to the built-ins:
+//!
+//! ```ignore
+//! let mut handlers = HandlerRegistry::default();
+//! handlers.register("word_count", WordCount);
+//! let tool_manager = DynamicToolManager::new().with_handlers(handlers);
+//! ```
+
+use crate::cli_tool::ArgType;
+use serde::Deserialize;
+use serde::de::DeserializeOwned;
+use serde_json::{Value, json};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+
+/// A tool implemented in-process.
+pub trait InternalHandler: Send + Sync + 'static {
+    /// The tool's parameters, deserialized from the call's arguments.
+    type Params: DeserializeOwned + Send;
+
+    /// The arguments the handler accepts. A tool that lists no `args` gets
+    /// these, and one that does may only list these.
+    fn args(&self) -> Vec<HandlerArg>;
+
+    fn call(&self, params: Self::Params) -> impl Future<Output = Result<Value, String>> + Send;
+}
+
+/// One declared argument of a handler.
+#[derive(Debug, Clone)]
+pub struct HandlerArg {
+    pub name: &'static str,
+    pub description: &'static str,
+    pub required: bool,
+    pub arg_type: ArgType,
+}
+
+type BoxFuture<'a> = Pin<Box<dyn Future<Output = Result<Value, String>> + Send + 'a>>;
+
+// Object-safe form of `InternalHandler`, so the registry can hold any of them
+trait ErasedHandler: Send + Sync {
+    fn args(&self) -> Vec<HandlerArg>;
+    fn call(&self, params: Value) -> BoxFuture<'_>;
+}
+
+impl<H: InternalHandler> ErasedHandler for H {
+    fn args(&self) -> Vec<HandlerArg> {
+        InternalHandler::args(self)
+    }
+
+    fn call(&self, params: Value) -> BoxFuture<'_> {
+        Box::pin(async move {
+            let params = serde_json::from_value(params)
+                .map_err(|e| format!("Invalid parameters: {}", e))?;
+            InternalHandler::call(self, params).await
+        })
+    }
+}
+
+/// A registered handler, as attached to a tool.
+#[derive(Clone)]
+pub struct Handler {
+    name: String,
+    inner: Arc<dyn ErasedHandler>,
+}
+
+impl Handler {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn args(&self) -> Vec<HandlerArg> {
+        self.inner.args()
+    }
+
+    pub async fn call(&self, params: HashMap<String, Value>) -> Result<String, String> {
+        let params = Value::Object(params.into_iter().collect());
+        Ok(self.inner.call(params).await?.to_string())
+    }
+}
+
+impl fmt::Debug for Handler {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("Handler").field(&self.name).finish()
+    }
+}
+
+/// Handlers by the name tools.yaml refers to them with. The default
+/// registry holds the built-ins; `empty()` starts without them.
+#[derive(Clone)]
+pub struct HandlerRegistry {
+    handlers: BTreeMap<String, Handler>,
+}
+
+impl HandlerRegistry {
+    pub fn empty() -> Self {
+        Self {
+            handlers: BTreeMap::new(),
+        }
+    }
+
+    /// Add a handler, replacing any registered under the same name.
+    pub fn register<H: InternalHandler>(&mut self, name: &str, handler: H) -> &mut Self {
+        self.handlers.insert(
+            name.to_string(),
+            Handler {
+                name: name.to_string(),
+                inner: Arc::new(handler),
+            },
+        );
+        self
+    }
+
+    pub fn get(&self, name: &str) -> Option<Handler> {
+        self.handlers.get(name).cloned()
+    }
+
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.handlers.keys().map(String::as_str)
+    }
+}
+
+impl Default for HandlerRegistry {
+    fn default() -> Self {
+        let mut registry = Self::empty();
+        registry
+            .register("add", Arithmetic::Add)
+            .register("multiply", Arithmetic::Multiply)
+            .register("list_files", ListFiles);
+        registry
+    }
+}
+
+impl fmt::Debug for HandlerRegistry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list().entries(self.names()).finish()
+    }
+}
+
+enum Arithmetic {
+    Add,
+    Multiply,
+}
+
+#[derive(Deserialize)]
+struct Operands {
+    a: f64,
+    b: f64,
+}
+
+impl InternalHandler for Arithmetic {
+    type Params = Operands;
+
+    fn args(&self) -> Vec<HandlerArg> {
+        ["a", "b"]
+            .into_iter()
+            .map(|name| HandlerArg {
+                name,
+                description: "A number",
+                required: true,
+                arg_type: ArgType::Number,
+            })
+            .collect()
+    }
+
+    async fn call(&self, Operands { a, b }: Operands) -> Result<Value, String> {
+        let (result, operation) = match self {
+            Arithmetic::Add => (a + b, "addition"),
+            Arithmetic::Multiply => (a * b, "multiplication"),
+        };
+        Ok(json!({ "result": number(result), "operation": operation }))
+    }
+}
+
+// Whole results print as integers, as they always have
+fn number(n: f64) -> Value {
+    if n.fract() == 0.0 && n.abs() < (1u64 << 53) as f64 {
+        json!(n as i64)
+    } else {
+        json!(n)
+    }
+}
+
+struct ListFiles;
+
+#[derive(Deserialize)]
+struct ListFilesParams {
+    #[serde(default = "current_dir")]
+    path: String,
+}
+
+fn current_dir() -> String {
+    ".".to_string()
+}
+
+impl InternalHandler for ListFiles {
+    type Params = ListFilesParams;
+
+    fn args(&self) -> Vec<HandlerArg> {
+        vec![HandlerArg {
+            name: "path",
+            description: "Directory path (defaults to current directory)",
+            required: false,
+            arg_type: ArgType::String,
+        }]
+    }
+
+    async fn call(&self, ListFilesParams { path }: ListFilesParams) -> Result<Value, String> {
+        let mut entries = match tokio::fs::read_dir(&path).await {
+            Ok(entries) => entries,
+            Err(e) => return Ok(json!({ "error": e.to_string() })),
+        };
+        let mut files = Vec::new();
+        while let Ok(Some(entry)) = entries.next_entry().await {
+            if let Ok(metadata) = entry.metadata().await {
+                files.push(json!({
+                    "name": entry.file_name().to_string_lossy(),
+                    "is_dir": metadata.is_dir(),
+                    "size": metadata.len(),
+                }));
+            }
+        }
+        Ok(json!({ "path": path, "files": files }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Greet;
+
+    #[derive(Deserialize)]
+    struct GreetParams {
+        name: String,
+        #[serde(default)]
+        loud: bool,
+    }
+
+    impl InternalHandler for Greet {
+        type Params = GreetParams;
+
+        fn args(&self) -> Vec<HandlerArg> {
+            vec![HandlerArg {
+                name: "name",
+                description: "Who to greet",
+                required: true,
+                arg_type: ArgType::String,
+            }]
+        }
+
+        async fn call(&self, params: GreetParams) -> Result<Value, String> {
+            let greeting = format!("hello {}", params.name);
+            Ok(json!({ "greeting": if params.loud { greeting.to_uppercase() } else { greeting } }))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_registered_handler_gets_typed_params() {
+        let mut registry = HandlerRegistry::empty();
+        registry.register("greet", Greet);
+        assert!(registry.get("add").is_none());
+        assert_eq!(registry.names().collect::<Vec<_>>(), ["greet"]);
+
+        let handler = registry.get("greet").unwrap();
+        assert_eq!(handler.args()[0].name, "name");
+        let params = HashMap::from([
+            ("name".to_string(), json!("ada")),
+            ("loud".to_string(), json!(true)),
+        ]);
+        assert_eq!(handler.call(params).await.unwrap(), r#"{"greeting":"HELLO ADA"}"#);
+
+        let err = handler.call(HashMap::new()).await.unwrap_err();
+        assert!(err.contains("Invalid parameters: missing field `name`"), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn test_list_files_output_is_valid_json() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        std::fs::write(temp_dir.path().join("quote\"d.txt"), "abc").unwrap();
+
+        let handler = HandlerRegistry::default().get("list_files").unwrap();
+        let params = HashMap::from([("path".to_string(), json!(temp_dir.path()))]);
+        let output: Value = serde_json::from_str(&handler.call(params).await.unwrap()).unwrap();
+        assert_eq!(output["files"][0]["name"], "quote\"d.txt");
+        assert_eq!(output["files"][0]["size"], 3);
+    }
+}
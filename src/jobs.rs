@@ -0,0 +1,337 @@
+//! Background jobs for tools marked `async: true`.
+//!
+//! Calling such a tool returns a job id at once; the client then polls with
+//! `job_status` and `job_output`, or stops it with `job_cancel`. Finished
+//! jobs are kept for a retention period, and with a jobs directory they are
+//! also written to disk so their results survive a restart.
+
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use tokio_util::sync::CancellationToken;
+use tracing::warn;
+
+// Beyond this many finished jobs, the oldest are dropped early
+const MAX_FINISHED_JOBS: usize = 200;
+// Jobs one principal may have running at once
+pub const MAX_RUNNING_JOBS: usize = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum JobStatus {
+    Running,
+    Succeeded,
+    Failed,
+    Cancelled,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Job {
+    pub id: String,
+    pub tool: String,
+    pub status: JobStatus,
+    pub started: DateTime<Utc>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub finished: Option<DateTime<Utc>>,
+    // Principal that started the job; only it can see the job
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub owner: Option<String>,
+    // The tool's result, or its error as JSON
+    #[serde(default)]
+    pub output: String,
+}
+
+impl Job {
+    /// The job without its output, for `job_status`.
+    pub fn summary(&self) -> serde_json::Value {
+        serde_json::json!({
+            "job_id": self.id,
+            "tool": self.tool,
+            "status": self.status,
+            "started": self.started,
+            "finished": self.finished,
+            "output_bytes": self.output.len(),
+        })
+    }
+}
+
+struct Slot {
+    job: Job,
+    // Only set while the job runs in this process
+    cancel: Option<CancellationToken>,
+}
+
+pub struct JobTable {
+    jobs: Mutex<BTreeMap<String, Slot>>,
+    // Where jobs are persisted, if anywhere
+    dir: Option<PathBuf>,
+    retention: Duration,
+}
+
+impl Default for JobTable {
+    fn default() -> Self {
+        Self {
+            jobs: Mutex::new(BTreeMap::new()),
+            dir: None,
+            retention: Duration::hours(24),
+        }
+    }
+}
+
+impl JobTable {
+    /// Open the table, loading jobs persisted in `dir`. Jobs that were still
+    /// running when the server stopped are marked failed.
+    pub fn new(dir: Option<PathBuf>, retention: Duration) -> Result<Self, String> {
+        let table = Self {
+            dir,
+            retention,
+            ..Self::default()
+        };
+        let Some(dir) = &table.dir else {
+            return Ok(table);
+        };
+        // Job outputs are full tool results, so only we may read them
+        crate::secrets::create_private_dir(dir)?;
+        let entries = fs::read_dir(dir).map_err(|e| format!("Failed to read {:?}: {}", dir, e))?;
+
+        let mut jobs = table.jobs.lock().unwrap();
+        for entry in entries.flatten() {
+            let path = entry.path();
+            if path.extension().is_none_or(|ext| ext != "json") {
+                continue;
+            }
+            let job = fs::read_to_string(&path)
+                .map_err(|e| e.to_string())
+                .and_then(|content| serde_json::from_str::<Job>(&content).map_err(|e| e.to_string()));
+            let mut job = match job {
+                Ok(job) => job,
+                Err(e) => {
+                    warn!("Ignoring job file {:?}: {}", path, e);
+                    continue;
+                }
+            };
+            // Whatever was still running, cancelled or not, has stopped
+            if job.finished.is_none() {
+                if job.status == JobStatus::Running {
+                    job.status = JobStatus::Failed;
+                    job.output = serde_json::json!({
+                        "error": "The server restarted while the job was running"
+                    })
+                    .to_string();
+                }
+                job.finished = Some(Utc::now());
+                table.persist(&job);
+            }
+            jobs.insert(job.id.clone(), Slot { job, cancel: None });
+        }
+        table.prune(&mut jobs);
+        drop(jobs);
+        Ok(table)
+    }
+
+    /// Register a running job; the token is cancelled by `job_cancel`.
+    /// Fails when the owner already has `MAX_RUNNING_JOBS` running.
+    pub fn start(&self, tool: &str, owner: Option<&str>) -> Result<(String, CancellationToken), String> {
+        let mut jobs = self.jobs.lock().unwrap();
+        // A cancelled job counts until its task has actually stopped
+        let running = jobs
+            .values()
+            .filter(|slot| slot.job.finished.is_none() && slot.job.owner.as_deref() == owner)
+            .count();
+        if running >= MAX_RUNNING_JOBS {
+            return Err(format!(
+                "{} jobs are already running; wait for one to finish or cancel it",
+                running
+            ));
+        }
+        let job = Job {
+            id: uuid::Uuid::new_v4().to_string(),
+            tool: tool.to_string(),
+            status: JobStatus::Running,
+            started: Utc::now(),
+            finished: None,
+            owner: owner.map(String::from),
+            output: String::new(),
+        };
+        let token = CancellationToken::new();
+        self.persist(&job);
+        let id = job.id.clone();
+        self.prune(&mut jobs);
+        jobs.insert(
+            id.clone(),
+            Slot {
+                job,
+                cancel: Some(token.clone()),
+            },
+        );
+        Ok((id, token))
+    }
+
+    /// Record a job's outcome. A cancelled job stays cancelled.
+    pub fn finish(&self, id: &str, status: JobStatus, output: String) {
+        let mut jobs = self.jobs.lock().unwrap();
+        let Some(slot) = jobs.get_mut(id) else {
+            return;
+        };
+        if slot.job.status == JobStatus::Running {
+            slot.job.status = status;
+        }
+        slot.job.finished = Some(Utc::now());
+        slot.job.output = output;
+        slot.cancel = None;
+        self.persist(&slot.job);
+    }
+
+    pub fn get(&self, id: &str, owner: Option<&str>) -> Result<Job, String> {
+        let jobs = self.jobs.lock().unwrap();
+        match jobs.get(id) {
+            Some(slot) if slot.job.owner.as_deref() == owner => Ok(slot.job.clone()),
+            _ => Err(format!("No job '{}'", id)),
+        }
+    }
+
+    /// Ask a running job to stop. Its status is `cancelled` from now on.
+    pub fn cancel(&self, id: &str, owner: Option<&str>) -> Result<Job, String> {
+        let mut jobs = self.jobs.lock().unwrap();
+        let slot = match jobs.get_mut(id) {
+            Some(slot) if slot.job.owner.as_deref() == owner => slot,
+            _ => return Err(format!("No job '{}'", id)),
+        };
+        if let Some(token) = slot.cancel.take() {
+            token.cancel();
+            slot.job.status = JobStatus::Cancelled;
+            self.persist(&slot.job);
+        }
+        Ok(slot.job.clone())
+    }
+
+    // Drop finished jobs past the retention period, then the oldest beyond the cap
+    fn prune(&self, jobs: &mut BTreeMap<String, Slot>) {
+        let cutoff = Utc::now()
+            .checked_sub_signed(self.retention)
+            .unwrap_or(DateTime::<Utc>::MIN_UTC);
+        let mut finished: Vec<(DateTime<Utc>, String)> = jobs
+            .values()
+            .filter_map(|slot| slot.job.finished.map(|at| (at, slot.job.id.clone())))
+            .collect();
+        finished.sort();
+        let excess = finished.len().saturating_sub(MAX_FINISHED_JOBS);
+        for (index, (at, id)) in finished.into_iter().enumerate() {
+            if index < excess || at < cutoff {
+                jobs.remove(&id);
+                if let Some(dir) = &self.dir {
+                    let _ = fs::remove_file(job_path(dir, &id));
+                }
+            }
+        }
+    }
+
+    fn persist(&self, job: &Job) {
+        let Some(dir) = &self.dir else {
+            return;
+        };
+        let path = job_path(dir, &job.id);
+        let written = serde_json::to_string(job)
+            .map_err(|e| e.to_string())
+            .and_then(|content| crate::secrets::write_private(&path, content.as_bytes()));
+        if let Err(e) = written {
+            warn!("Failed to persist job {}: {}", job.id, e);
+        }
+    }
+}
+
+fn job_path(dir: &Path, id: &str) -> PathBuf {
+    dir.join(format!("{}.json", id))
+}
+
+/// A page of a job's output: `tail` lines from the end, or `limit` bytes
+/// from `offset`. Offsets are clamped to character boundaries.
+pub fn page(output: &str, offset: usize, limit: usize, tail: Option<usize>) -> (usize, &str) {
+    if let Some(lines) = tail {
+        let start = output
+            .trim_end_matches('\n')
+            .rmatch_indices('\n')
+            .nth(lines.saturating_sub(1))
+            .map_or(0, |(i, _)| i + 1);
+        let start = if lines == 0 { output.len() } else { start };
+        return (start, &output[start..]);
+    }
+    let floor = |mut i: usize| {
+        i = i.min(output.len());
+        while !output.is_char_boundary(i) {
+            i -= 1;
+        }
+        i
+    };
+    let start = floor(offset);
+    let end = floor(start.saturating_add(limit));
+    (start, &output[start..end])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_jobs_persist_and_are_private() {
+        let dir = TempDir::new().unwrap();
+        let table = JobTable::new(Some(dir.path().to_path_buf()), Duration::hours(1)).unwrap();
+        let (done, _) = table.start("build", Some("alice")).unwrap();
+        table.finish(&done, JobStatus::Succeeded, "{\"ok\":true}".to_string());
+        let (running, token) = table.start("tests", Some("alice")).unwrap();
+
+        assert!(table.get(&done, Some("bob")).is_err());
+        assert!(table.get(&done, None).is_err());
+        assert_eq!(table.cancel(&running, Some("alice")).unwrap().status, JobStatus::Cancelled);
+        assert!(token.is_cancelled());
+        // The task noticing the cancellation doesn't change the outcome
+        table.finish(&running, JobStatus::Failed, String::new());
+        assert_eq!(table.get(&running, Some("alice")).unwrap().status, JobStatus::Cancelled);
+
+        let (lost, _) = table.start("deploy", None).unwrap();
+        let reopened = JobTable::new(Some(dir.path().to_path_buf()), Duration::hours(1)).unwrap();
+        assert_eq!(reopened.get(&done, Some("alice")).unwrap().output, "{\"ok\":true}");
+        let lost = reopened.get(&lost, None).unwrap();
+        assert_eq!(lost.status, JobStatus::Failed);
+        assert!(lost.output.contains("restarted"));
+        use std::os::unix::fs::PermissionsExt;
+        let mode = fs::metadata(job_path(dir.path(), &done)).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        // Past retention, finished jobs are dropped on the next start
+        let expiring = JobTable::new(Some(dir.path().to_path_buf()), Duration::zero()).unwrap();
+        assert!(expiring.get(&done, Some("alice")).is_err());
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn test_running_jobs_are_limited_per_owner() {
+        let table = JobTable::default();
+        let ids: Vec<String> = (0..MAX_RUNNING_JOBS)
+            .map(|_| table.start("build", Some("alice")).unwrap().0)
+            .collect();
+        assert!(table.start("build", Some("alice")).unwrap_err().contains("already running"));
+        assert!(table.start("build", Some("bob")).is_ok());
+
+        // Cancelling isn't enough; the job's task has to stop
+        table.cancel(&ids[0], Some("alice")).unwrap();
+        assert!(table.start("build", Some("alice")).is_err());
+        table.finish(&ids[0], JobStatus::Failed, String::new());
+        assert!(table.start("build", Some("alice")).is_ok());
+    }
+
+    #[test]
+    fn test_page() {
+        let output = "one\ntwo\nthree\n";
+        assert_eq!(page(output, 0, 5, None), (0, "one\nt"));
+        assert_eq!(page(output, 4, 100, None), (4, "two\nthree\n"));
+        assert_eq!(page(output, 100, 5, None), (14, ""));
+        assert_eq!(page(output, 0, 0, Some(2)), (4, "two\nthree\n"));
+        assert_eq!(page(output, 0, 0, Some(9)), (0, output));
+        assert_eq!(page("héllo", 0, 2, None), (0, "h"));
+    }
+}
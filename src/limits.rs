@@ -0,0 +1,535 @@
+//! Per-tool resource limits for spawned commands.
+//!
+//! Limits are rlimits set between fork and exec. When the server runs in a
+//! delegated cgroup v2 subtree, a tool can instead get its own transient
+//! cgroup, which bounds the whole process tree and reports OOM kills exactly.
+
+use crate::cli_tool::ToolError;
+use serde::{Deserialize, Deserializer};
+use std::os::unix::process::{CommandExt, ExitStatusExt};
+use std::process::{Command, ExitStatus};
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ResourceLimits {
+    // Address space (RLIMIT_AS), or memory.max in a cgroup: "512M", "2G" or bytes
+    #[serde(default, deserialize_with = "size")]
+    pub memory: Option<u64>,
+    // CPU time (RLIMIT_CPU)
+    pub cpu_seconds: Option<u64>,
+    // RLIMIT_NOFILE
+    pub open_files: Option<u64>,
+    // RLIMIT_NPROC, or pids.max in a cgroup
+    pub processes: Option<u64>,
+    // Largest file the tool may write (RLIMIT_FSIZE)
+    #[serde(default, deserialize_with = "size")]
+    pub file_size: Option<u64>,
+    // Run in a transient cgroup when the server's cgroup is delegated
+    #[serde(default)]
+    pub cgroup: bool,
+}
+
+pub(crate) fn size<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<u64>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Size {
+        Bytes(u64),
+        Text(String),
+    }
+    match Size::deserialize(deserializer)? {
+        Size::Bytes(bytes) => Ok(Some(bytes)),
+        Size::Text(text) => parse_size(&text).map(Some).map_err(serde::de::Error::custom),
+    }
+}
+
+/// Parse `512M`, `2G`, `64KiB` or a plain byte count; units are powers of 1024.
+fn parse_size(text: &str) -> Result<u64, String> {
+    let text = text.trim();
+    let split = text.find(|c: char| !c.is_ascii_digit()).unwrap_or(text.len());
+    let (number, unit) = text.split_at(split);
+    let number: u64 = number
+        .parse()
+        .map_err(|_| format!("invalid size '{}'", text))?;
+    let shift = match unit.trim().to_ascii_uppercase().as_str() {
+        "" | "B" => 0,
+        "K" | "KB" | "KIB" => 10,
+        "M" | "MB" | "MIB" => 20,
+        "G" | "GB" | "GIB" => 30,
+        "T" | "TB" | "TIB" => 40,
+        _ => return Err(format!("invalid size '{}': unknown unit '{}'", text, unit)),
+    };
+    number
+        .checked_mul(1 << shift)
+        .ok_or_else(|| format!("size '{}' is too large", text))
+}
+
+pub(crate) fn format_size(bytes: u64) -> String {
+    for (shift, unit) in [(30, "G"), (20, "M"), (10, "K")] {
+        if bytes >= 1 << shift && bytes.is_multiple_of(1 << shift) {
+            return format!("{}{}", bytes >> shift, unit);
+        }
+    }
+    format!("{} bytes", bytes)
+}
+
+impl ResourceLimits {
+    /// Install the limits on `cmd`. The guard owns the tool's cgroup, if any,
+    /// and is used afterwards to tell whether a failure was a limit.
+    pub fn apply(&self, tool: &str, cmd: &mut Command) -> Result<LimitGuard, String> {
+        #[cfg(target_os = "linux")]
+        let cgroup = if self.cgroup {
+            cgroup::ToolCgroup::create(tool, self)
+        } else {
+            None
+        };
+        #[cfg(not(target_os = "linux"))]
+        let cgroup: Option<cgroup::ToolCgroup> = None;
+
+        // A cgroup bounds the whole tree, so it replaces the per-process limits
+        let mut rlimits = Vec::new();
+        if let Some(cpu) = self.cpu_seconds {
+            // SIGXCPU at the soft limit, SIGKILL a second later
+            rlimits.push((libc::RLIMIT_CPU, cpu, cpu.saturating_add(1)));
+        }
+        if let Some(memory) = self.memory
+            && cgroup.is_none()
+        {
+            rlimits.push((libc::RLIMIT_AS, memory, memory));
+        }
+        if let Some(files) = self.open_files {
+            rlimits.push((libc::RLIMIT_NOFILE, files, files));
+        }
+        if let Some(processes) = self.processes
+            && cgroup.is_none()
+        {
+            rlimits.push((libc::RLIMIT_NPROC, processes, processes));
+        }
+        if let Some(file_size) = self.file_size {
+            rlimits.push((libc::RLIMIT_FSIZE, file_size, file_size));
+        }
+
+        // Unprivileged processes can't raise their hard limits
+        for (resource, soft, hard) in &mut rlimits {
+            let mut current = libc::rlimit {
+                rlim_cur: 0,
+                rlim_max: 0,
+            };
+            if unsafe { libc::getrlimit(*resource, &mut current) } == 0 {
+                *soft = (*soft).min(current.rlim_max);
+                *hard = (*hard).min(current.rlim_max);
+            }
+        }
+
+        let procs = cgroup.as_ref().map(|cgroup| cgroup.procs_path()).transpose()?;
+
+        // SAFETY: only open/write/close/setrlimit between fork and exec, on
+        // values prepared above
+        unsafe {
+            cmd.pre_exec(move || {
+                if let Some(procs) = &procs {
+                    // Writing 0 moves the writing process
+                    let fd = libc::open(procs.as_ptr(), libc::O_WRONLY | libc::O_CLOEXEC);
+                    if fd < 0 {
+                        return Err(std::io::Error::last_os_error());
+                    }
+                    let written = libc::write(fd, b"0".as_ptr().cast(), 1);
+                    libc::close(fd);
+                    if written != 1 {
+                        return Err(std::io::Error::last_os_error());
+                    }
+                }
+                for (resource, soft, hard) in &rlimits {
+                    let limit = libc::rlimit {
+                        rlim_cur: *soft,
+                        rlim_max: *hard,
+                    };
+                    if libc::setrlimit(*resource, &limit) != 0 {
+                        return Err(std::io::Error::last_os_error());
+                    }
+                }
+                Ok(())
+            });
+        }
+
+        Ok(LimitGuard {
+            tool: tool.to_string(),
+            limits: self.clone(),
+            cgroup,
+        })
+    }
+
+    fn describe(&self, limit: &str) -> String {
+        let value = match limit {
+            "memory" => self.memory.map(format_size),
+            "cpu_seconds" => self.cpu_seconds.map(|s| format!("{}s", s)),
+            "open_files" => self.open_files.map(|n| n.to_string()),
+            "processes" => self.processes.map(|n| n.to_string()),
+            "file_size" => self.file_size.map(format_size),
+            _ => None,
+        };
+        value.unwrap_or_default()
+    }
+}
+
+/// Keeps a tool's cgroup alive for the call and removes it afterwards.
+pub struct LimitGuard {
+    tool: String,
+    limits: ResourceLimits,
+    cgroup: Option<cgroup::ToolCgroup>,
+}
+
+impl LimitGuard {
+    /// Attribute a failed run to one of the limits, if it hit one.
+    pub fn violation(&self, status: &ExitStatus, stderr: &str) -> Option<ToolError> {
+        if status.success() {
+            return None;
+        }
+        let limit = self.exceeded(status, stderr)?;
+        Some(ToolError::LimitExceeded {
+            limit,
+            message: format!(
+                "Tool '{}' exceeded its {} limit ({})",
+                self.tool,
+                limit,
+                self.limits.describe(limit)
+            ),
+        })
+    }
+
+    fn exceeded(&self, status: &ExitStatus, stderr: &str) -> Option<&'static str> {
+        // A cgroup counts its own limit hits, so those need no guessing
+        if let Some(cgroup) = &self.cgroup {
+            if cgroup.event("memory.events", "oom_kill") > 0 {
+                return Some("memory");
+            }
+            if cgroup.event("pids.events", "max") > 0 {
+                return Some("processes");
+            }
+        }
+        let limits = &self.limits;
+        let memory = limits.memory.is_some() && self.cgroup.is_none();
+        let processes = limits.processes.is_some() && self.cgroup.is_none();
+
+        match status.signal() {
+            Some(libc::SIGXCPU) if limits.cpu_seconds.is_some() => return Some("cpu_seconds"),
+            // The hard limit, for a tool that ignored or handled SIGXCPU
+            Some(libc::SIGKILL) if limits.cpu_seconds.is_some() && self.cgroup.is_none() => {
+                return Some("cpu_seconds");
+            }
+            Some(libc::SIGXFSZ) if limits.file_size.is_some() => return Some("file_size"),
+            // Allocators abort when RLIMIT_AS makes an allocation fail
+            Some(libc::SIGABRT) if memory => return Some("memory"),
+            Some(_) => return None,
+            None => {}
+        }
+
+        // A tool that handles the failure only reports the errno, in English
+        // if we're lucky, so its message is the last resort
+        let stderr = stderr.to_lowercase();
+        let mentions = |needles: &[&str]| needles.iter().any(|n| stderr.contains(n));
+        if memory && mentions(&["cannot allocate memory", "out of memory", "memory allocation"]) {
+            Some("memory")
+        } else if processes && mentions(&["resource temporarily unavailable"]) {
+            Some("processes")
+        } else if limits.open_files.is_some() && mentions(&["too many open files"]) {
+            Some("open_files")
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod cgroup {
+    use super::ResourceLimits;
+    use std::ffi::CString;
+    use std::fs;
+    use std::os::unix::ffi::OsStrExt;
+    use std::path::{Path, PathBuf};
+    use std::sync::OnceLock;
+    use tracing::{debug, info, warn};
+
+    const LEAF: &str = "gamecode-mcp";
+
+    pub struct ToolCgroup {
+        path: PathBuf,
+    }
+
+    impl ToolCgroup {
+        /// A fresh cgroup for one call, or `None` (with a warning the first
+        /// time) when the server can't manage cgroups.
+        pub fn create(tool: &str, limits: &ResourceLimits) -> Option<Self> {
+            let parent = delegated_parent().as_ref()?;
+            let id = uuid::Uuid::new_v4().simple().to_string();
+            let cgroup = Self {
+                path: parent.join(format!("tool-{}-{}", tool, &id[..8])),
+            };
+            if let Err(e) = fs::create_dir(&cgroup.path) {
+                warn!("Failed to create cgroup {:?}: {}", cgroup.path, e);
+                return None;
+            }
+
+            let mut settings = Vec::new();
+            if let Some(memory) = limits.memory {
+                settings.push(("memory.max", memory.to_string()));
+                settings.push(("memory.swap.max", "0".to_string()));
+            }
+            if let Some(processes) = limits.processes {
+                settings.push(("pids.max", processes.to_string()));
+            }
+            for (file, value) in settings {
+                if let Err(e) = fs::write(cgroup.path.join(file), &value) {
+                    // Swap accounting is often disabled; the rest must stick
+                    if file == "memory.swap.max" {
+                        continue;
+                    }
+                    warn!("Failed to set {} in {:?}: {}", file, cgroup.path, e);
+                    return None;
+                }
+            }
+            Some(cgroup)
+        }
+
+        pub fn procs_path(&self) -> Result<CString, String> {
+            CString::new(self.path.join("cgroup.procs").as_os_str().as_bytes())
+                .map_err(|e| format!("Invalid cgroup path {:?}: {}", self.path, e))
+        }
+
+        /// A counter from a `*.events` file, or 0 if unreadable.
+        pub fn event(&self, file: &str, key: &str) -> u64 {
+            fs::read_to_string(self.path.join(file))
+                .map(|events| parse_event(&events, key))
+                .unwrap_or(0)
+        }
+    }
+
+    impl Drop for ToolCgroup {
+        fn drop(&mut self) {
+            // Kill stragglers the tool left behind so the cgroup can go
+            let _ = fs::write(self.path.join("cgroup.kill"), "1");
+            if let Err(e) = fs::remove_dir(&self.path) {
+                debug!("Failed to remove cgroup {:?}: {}", self.path, e);
+            }
+        }
+    }
+
+    /// The server's own cgroup, prepared once to hold tool cgroups.
+    fn delegated_parent() -> &'static Option<PathBuf> {
+        static PARENT: OnceLock<Option<PathBuf>> = OnceLock::new();
+        PARENT.get_or_init(|| match prepare() {
+            Ok(parent) => {
+                info!("Running tools with cgroup limits under {:?}", parent);
+                Some(parent)
+            }
+            Err(e) => {
+                warn!(
+                    "cgroup limits unavailable ({}); falling back to rlimits. \
+                     Run the server in a delegated cgroup v2 subtree, e.g. \
+                     systemd-run --user -p Delegate=yes",
+                    e
+                );
+                None
+            }
+        })
+    }
+
+    fn prepare() -> Result<PathBuf, String> {
+        let mounts = fs::read_to_string("/proc/self/mounts").map_err(|e| e.to_string())?;
+        let mount = cgroup2_mount(&mounts).ok_or("no cgroup2 filesystem mounted")?;
+        let cgroups = fs::read_to_string("/proc/self/cgroup").map_err(|e| e.to_string())?;
+        let own = unified_path(&cgroups).ok_or("not in a cgroup v2 hierarchy")?;
+        let dir = mount.join(own.trim_start_matches('/'));
+
+        let read = |path: &Path| fs::read_to_string(path).map_err(|e| format!("{:?}: {}", path, e));
+        let available = read(&dir.join("cgroup.controllers"))?;
+        let wanted: Vec<&str> = ["memory", "pids"]
+            .into_iter()
+            .filter(|c| available.split_whitespace().any(|a| a == *c))
+            .collect();
+        if wanted.is_empty() {
+            return Err(format!("no memory or pids controller in {:?}", dir));
+        }
+
+        let enabled = read(&dir.join("cgroup.subtree_control"))?;
+        if wanted.iter().all(|c| enabled.split_whitespace().any(|e| e == *c)) {
+            return Ok(dir);
+        }
+
+        // A cgroup with processes can't enable controllers for children, so
+        // move the server into a leaf of its own first
+        let leaf = dir.join(LEAF);
+        if !leaf.exists() {
+            fs::create_dir(&leaf).map_err(|e| format!("{:?}: {}", leaf, e))?;
+        }
+        fs::write(leaf.join("cgroup.procs"), std::process::id().to_string())
+            .map_err(|e| format!("moving the server into {:?}: {}", leaf, e))?;
+        let control: Vec<String> = wanted.iter().map(|c| format!("+{}", c)).collect();
+        fs::write(dir.join("cgroup.subtree_control"), control.join(" "))
+            .map_err(|e| format!("enabling {} in {:?}: {}", wanted.join(", "), dir, e))?;
+        Ok(dir)
+    }
+
+    fn cgroup2_mount(mounts: &str) -> Option<PathBuf> {
+        mounts.lines().find_map(|line| {
+            let mut fields = line.split_whitespace();
+            let mount_point = fields.nth(1)?;
+            (fields.next()? == "cgroup2").then(|| PathBuf::from(mount_point))
+        })
+    }
+
+    fn unified_path(cgroups: &str) -> Option<&str> {
+        cgroups.lines().find_map(|line| line.strip_prefix("0::"))
+    }
+
+    fn parse_event(events: &str, key: &str) -> u64 {
+        events
+            .lines()
+            .find_map(|line| {
+                let (name, count) = line.split_once(' ')?;
+                (name == key).then(|| count.trim().parse().ok()).flatten()
+            })
+            .unwrap_or(0)
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn test_cgroup_discovery() {
+            let mounts = "proc /proc proc rw 0 0\ncgroup2 /sys/fs/cgroup/unified cgroup2 rw 0 0\n";
+            assert_eq!(
+                cgroup2_mount(mounts),
+                Some(PathBuf::from("/sys/fs/cgroup/unified"))
+            );
+            let cgroups = "4:memory:/x\n0::/user.slice/app.service\n";
+            assert_eq!(unified_path(cgroups), Some("/user.slice/app.service"));
+
+            let events = "low 0\nhigh 0\nmax 3\noom 1\noom_kill 1\n";
+            assert_eq!(parse_event(events, "oom_kill"), 1);
+            assert_eq!(parse_event(events, "max"), 3);
+            assert_eq!(parse_event(events, "missing"), 0);
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod cgroup {
+    pub struct ToolCgroup;
+
+    impl ToolCgroup {
+        pub fn procs_path(&self) -> Result<std::ffi::CString, String> {
+            unreachable!("cgroups are Linux-only")
+        }
+
+        pub fn event(&self, _file: &str, _key: &str) -> u64 {
+            0
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limits(yaml: &str) -> ResourceLimits {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    #[test]
+    fn test_sizes() {
+        let parsed = limits("memory: 512M\nfile_size: 4096\ncpu_seconds: 10\n");
+        assert_eq!(parsed.memory, Some(512 << 20));
+        assert_eq!(parsed.file_size, Some(4096));
+        assert_eq!(parse_size("2GiB"), Ok(2 << 30));
+        assert_eq!(parse_size("64 k"), Ok(64 << 10));
+        assert!(parse_size("lots").is_err());
+        assert!(parse_size("5X").is_err());
+        assert!(serde_yaml::from_str::<ResourceLimits>("memory: 1Q").is_err());
+
+        assert_eq!(format_size(512 << 20), "512M");
+        assert_eq!(format_size(1500), "1500 bytes");
+    }
+
+    #[test]
+    fn test_cpu_limit_is_reported() {
+        let guard_limits = limits("cpu_seconds: 1\n");
+        let mut cmd = Command::new("sh");
+        cmd.args(["-c", "while :; do :; done"]);
+        let guard = guard_limits.apply("spin", &mut cmd).unwrap();
+        let output = cmd.output().unwrap();
+
+        let err = guard
+            .violation(&output.status, &String::from_utf8_lossy(&output.stderr))
+            .unwrap();
+        assert!(matches!(err, ToolError::LimitExceeded { limit: "cpu_seconds", .. }));
+        assert!(err.to_string().contains("cpu_seconds limit (1s)"), "{}", err);
+    }
+
+    #[test]
+    fn test_cpu_hard_limit_is_reported() {
+        let mut cmd = Command::new("sh");
+        cmd.args(["-c", "trap '' XCPU; while :; do :; done"]);
+        let guard = limits("cpu_seconds: 1\n").apply("stubborn", &mut cmd).unwrap();
+        let output = cmd.output().unwrap();
+        assert_eq!(output.status.signal(), Some(libc::SIGKILL));
+
+        let err = guard.violation(&output.status, "").unwrap();
+        assert!(matches!(err, ToolError::LimitExceeded { limit: "cpu_seconds", .. }));
+    }
+
+    #[test]
+    fn test_file_size_limit_is_reported() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let target = temp_dir.path().join("big");
+        let mut cmd = Command::new("dd");
+        cmd.args([
+            "if=/dev/zero".to_string(),
+            format!("of={}", target.display()),
+            "bs=1024".to_string(),
+            "count=64".to_string(),
+        ]);
+        let guard = limits("file_size: 4K\n").apply("writer", &mut cmd).unwrap();
+        let output = cmd.output().unwrap();
+
+        let err = guard
+            .violation(&output.status, &String::from_utf8_lossy(&output.stderr))
+            .unwrap();
+        assert!(matches!(err, ToolError::LimitExceeded { limit: "file_size", .. }));
+        assert!(std::fs::metadata(&target).unwrap().len() <= 4096);
+    }
+
+    #[test]
+    fn test_ordinary_failures_are_not_limits() {
+        let mut cmd = Command::new("sh");
+        cmd.args(["-c", "echo 'no such file' >&2; exit 1"]);
+        let guard = limits("memory: 1G\nopen_files: 64\n").apply("plain", &mut cmd).unwrap();
+        let output = cmd.output().unwrap();
+        assert!(!output.status.success());
+        assert!(guard.violation(&output.status, "no such file").is_none());
+
+        // Killed by something else, whatever it printed
+        let mut cmd = Command::new("sh");
+        cmd.args(["-c", "kill -TERM $$"]);
+        let guard = limits("memory: 1G
+").apply("plain", &mut cmd).unwrap();
+        let output = cmd.output().unwrap();
+        assert!(guard.violation(&output.status, "out of memory").is_none());
+        let mut cmd = Command::new("sh");
+        cmd.args(["-c", "kill -KILL $$"]);
+        let guard = limits("memory: 1G\n").apply("plain", &mut cmd).unwrap();
+        let output = cmd.output().unwrap();
+        assert!(guard.violation(&output.status, "").is_none());
+
+        let mut cmd = Command::new("sh");
+        cmd.args(["-c", "kill -ABRT $$"]);
+        let guard = limits("memory: 1G
+cpu_seconds: 18446744073709551615
+")
+            .apply("hungry", &mut cmd)
+            .unwrap();
+        let output = cmd.output().unwrap();
+        let err = guard.violation(&output.status, "").unwrap();
+        assert!(matches!(err, ToolError::LimitExceeded { limit: "memory", .. }));
+    }
+}
@@ -0,0 +1,298 @@
+use axum::Router;
+use axum::serve::Listener;
+use rmcp::transport::sse_server::{SseServer, SseServerConfig};
+use rmcp::transport::streamable_http_server::{
+    StreamableHttpServerConfig, StreamableHttpService, session::local::LocalSessionManager,
+};
+use crate::tls::{TlsListener, TlsPeer};
+use rmcp::{RoleServer, Service};
+use std::net::IpAddr;
+use std::os::unix::fs::FileTypeExt;
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::net::{TcpListener, UnixListener};
+use tokio_util::sync::CancellationToken;
+use tracing::info;
+
+/// Streamable HTTP endpoint (MCP 2025-03-26 and later)
+pub const MCP_PATH: &str = "/mcp";
+/// Legacy HTTP+SSE endpoints (MCP 2024-11-05)
+pub const SSE_PATH: &str = "/sse";
+pub const SSE_POST_PATH: &str = "/message";
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ListenAddr {
+    Tcp(String),
+    Unix(PathBuf),
+}
+
+impl ListenAddr {
+    /// Parse `host:port`, `tcp://host:port`, `http://host:port`,
+    /// `unix:/path/to.sock` or `unix:///path/to.sock`.
+    pub fn parse(value: &str) -> Result<Self, String> {
+        if let Some(path) = value.strip_prefix("unix:") {
+            let path = path.strip_prefix("//").unwrap_or(path);
+            if path.is_empty() {
+                return Err(format!("Missing socket path in '{}'", value));
+            }
+            return Ok(ListenAddr::Unix(PathBuf::from(path)));
+        }
+
+        let addr = value
+            .strip_prefix("tcp://")
+            .or_else(|| value.strip_prefix("http://"))
+            .unwrap_or(value)
+            .trim_end_matches('/');
+        match addr.rsplit_once(':') {
+            Some((host, port)) if port.parse::<u16>().is_ok() => {
+                let host = if host.is_empty() { "127.0.0.1" } else { host };
+                Ok(ListenAddr::Tcp(format!("{}:{}", host, port)))
+            }
+            _ => Err(format!(
+                "Invalid listen address '{}' (expected host:port or unix:/path)",
+                value
+            )),
+        }
+    }
+
+    /// Whether only this machine can connect: a loopback address or a Unix
+    /// socket, whose file permissions decide who may use it.
+    pub fn is_local(&self) -> bool {
+        match self {
+            ListenAddr::Unix(_) => true,
+            ListenAddr::Tcp(addr) => {
+                let host = addr.rsplit_once(':').map_or(addr.as_str(), |(host, _)| host);
+                let host = host.trim_start_matches('[').trim_end_matches(']');
+                host.eq_ignore_ascii_case("localhost")
+                    || host.parse::<IpAddr>().is_ok_and(|ip| ip.is_loopback())
+            }
+        }
+    }
+}
+
+/// Build the HTTP router serving both MCP transports. `new_session` is called
+/// once per client session, so sessions can share state through it.
+pub fn router<S, F>(new_session: F, ct: CancellationToken) -> Router
+where
+    S: Service<RoleServer> + Send + 'static,
+    F: Fn() -> S + Clone + Send + Sync + 'static,
+{
+    let streamable = StreamableHttpService::new(
+        {
+            let new_session = new_session.clone();
+            move || Ok(new_session())
+        },
+        Arc::new(LocalSessionManager::default()),
+        StreamableHttpServerConfig::default(),
+    );
+
+    let (sse_server, sse_router) = SseServer::new(SseServerConfig {
+        // Unused: we bind the listener ourselves
+        bind: ([127, 0, 0, 1], 0).into(),
+        sse_path: SSE_PATH.to_string(),
+        post_path: SSE_POST_PATH.to_string(),
+        ct: ct.child_token(),
+        sse_keep_alive: None,
+    });
+    sse_server.with_service(new_session);
+
+    Router::new()
+        .nest_service(MCP_PATH, streamable)
+        .merge(sse_router)
+}
+
+/// Serve `app` until `ct` is cancelled, over TLS when a config is given.
+pub async fn serve(
+    addr: &ListenAddr,
+    app: Router,
+    tls: Option<Arc<rustls::ServerConfig>>,
+    ct: CancellationToken,
+) -> std::io::Result<()> {
+    match (addr, tls) {
+        (ListenAddr::Tcp(addr), Some(tls)) => {
+            let listener = TlsListener::bind(addr, tls).await?;
+            info!(
+                "Listening on https://{}{} (legacy SSE at {})",
+                listener.local_addr()?.addr,
+                MCP_PATH,
+                SSE_PATH
+            );
+            axum::serve(listener, app.into_make_service_with_connect_info::<TlsPeer>())
+                .with_graceful_shutdown(ct.cancelled_owned())
+                .await
+        }
+        (ListenAddr::Unix(_), Some(_)) => Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "TLS is only supported for TCP listen addresses",
+        )),
+        (ListenAddr::Tcp(addr), None) => {
+            let listener = TcpListener::bind(addr).await?;
+            info!(
+                "Listening on http://{}{} (legacy SSE at {})",
+                listener.local_addr()?,
+                MCP_PATH,
+                SSE_PATH
+            );
+            axum::serve(listener, app)
+                .with_graceful_shutdown(ct.cancelled_owned())
+                .await
+        }
+        (ListenAddr::Unix(path), None) => {
+            // Clear a socket left behind by a previous run, but nothing else
+            match std::fs::symlink_metadata(path) {
+                Ok(metadata) if metadata.file_type().is_socket() => {
+                    // A socket someone still answers on belongs to a running server
+                    match tokio::net::UnixStream::connect(path).await {
+                        Ok(_) => {
+                            return Err(std::io::Error::new(
+                                std::io::ErrorKind::AddrInUse,
+                                format!("{} is in use by a running server", path.display()),
+                            ));
+                        }
+                        Err(e) if e.kind() == std::io::ErrorKind::ConnectionRefused => {
+                            std::fs::remove_file(path)?
+                        }
+                        Err(e) => return Err(e),
+                    }
+                }
+                Ok(_) => {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::AlreadyExists,
+                        format!("{} exists and is not a socket", path.display()),
+                    ));
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e),
+            }
+            let listener = UnixListener::bind(path)?;
+            info!("Listening on unix:{} ({} and {})", path.display(), MCP_PATH, SSE_PATH);
+            let result = axum::serve(listener, app)
+                .with_graceful_shutdown(ct.cancelled_owned())
+                .await;
+            let _ = std::fs::remove_file(path);
+            result
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rmcp::ServerHandler;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[derive(Clone)]
+    struct EmptyServer;
+    impl ServerHandler for EmptyServer {}
+
+    #[test]
+    fn test_parse_listen_addr() {
+        assert_eq!(
+            ListenAddr::parse("0.0.0.0:8080").unwrap(),
+            ListenAddr::Tcp("0.0.0.0:8080".to_string())
+        );
+        assert_eq!(
+            ListenAddr::parse("tcp://build-box:9000").unwrap(),
+            ListenAddr::Tcp("build-box:9000".to_string())
+        );
+        assert_eq!(
+            ListenAddr::parse(":8080").unwrap(),
+            ListenAddr::Tcp("127.0.0.1:8080".to_string())
+        );
+        assert_eq!(
+            ListenAddr::parse("unix:///run/gamecode.sock").unwrap(),
+            ListenAddr::Unix(PathBuf::from("/run/gamecode.sock"))
+        );
+        assert_eq!(
+            ListenAddr::parse("unix:gamecode.sock").unwrap(),
+            ListenAddr::Unix(PathBuf::from("gamecode.sock"))
+        );
+        assert!(ListenAddr::parse("localhost").is_err());
+        assert!(ListenAddr::parse("unix:").is_err());
+
+        for local in [":8080", "localhost:80", "[::1]:80", "unix:/run/x.sock"] {
+            assert!(ListenAddr::parse(local).unwrap().is_local(), "{}", local);
+        }
+        for remote in ["0.0.0.0:8080", "[::]:80", "build-box:9000"] {
+            assert!(!ListenAddr::parse(remote).unwrap().is_local(), "{}", remote);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_unix_socket_never_replaces_a_file() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let path = temp_dir.path().join("important.txt");
+        std::fs::write(&path, "keep me").unwrap();
+        let app = router(|| EmptyServer, CancellationToken::new());
+        let err = serve(&ListenAddr::Unix(path.clone()), app, None, CancellationToken::new())
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("not a socket"), "{}", err);
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "keep me");
+    }
+
+    #[tokio::test]
+    async fn test_unix_socket_replaces_only_a_stale_socket() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let path = temp_dir.path().join("mcp.sock");
+        let addr = ListenAddr::Unix(path.clone());
+        let start = || {
+            let app = router(|| EmptyServer, CancellationToken::new());
+            let ct = CancellationToken::new();
+            ct.cancel();
+            serve(&addr, app, None, ct)
+        };
+
+        let live = std::os::unix::net::UnixListener::bind(&path).unwrap();
+        let err = start().await.unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::AddrInUse, "{}", err);
+        assert!(path.exists());
+
+        // Closed, the socket file is left behind for the next run to clear
+        drop(live);
+        start().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_streamable_http_initialize_over_unix_socket() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let socket = temp_dir.path().join("mcp.sock");
+        let addr = ListenAddr::Unix(socket.clone());
+        let ct = CancellationToken::new();
+
+        let app = router(|| EmptyServer, ct.clone());
+        let server = tokio::spawn({
+            let ct = ct.clone();
+            async move { serve(&addr, app, None, ct).await }
+        });
+        while !socket.exists() {
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+
+        let body = r#"{"jsonrpc":"2.0","id":1,"method":"initialize","params":{"protocolVersion":"2025-03-26","capabilities":{},"clientInfo":{"name":"test","version":"1"}}}"#;
+        let request = format!(
+            "POST {} HTTP/1.1\r\nHost: localhost\r\nContent-Type: application/json\r\nAccept: application/json, text/event-stream\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            MCP_PATH,
+            body.len(),
+            body
+        );
+        let mut stream = tokio::net::UnixStream::connect(&socket).await.unwrap();
+        stream.write_all(request.as_bytes()).await.unwrap();
+
+        // The response is an SSE stream; read until the initialize result arrives
+        let mut response = String::new();
+        let mut buf = [0u8; 4096];
+        while !response.contains("serverInfo") {
+            let n = stream.read(&mut buf).await.unwrap();
+            assert!(n > 0, "connection closed early: {}", response);
+            response.push_str(&String::from_utf8_lossy(&buf[..n]));
+        }
+        assert!(response.starts_with("HTTP/1.1 200"));
+        assert!(response.to_lowercase().contains("mcp-session-id:"));
+
+        ct.cancel();
+        drop(stream);
+        server.await.unwrap().unwrap();
+        assert!(!socket.exists());
+    }
+}
@@ -1,17 +1,66 @@
-use rmcp::{ServerHandler, ServiceExt, schemars, tool};
+use rmcp::handler::server::router::tool::ToolRouter;
+use rmcp::handler::server::wrapper::Parameters;
+use rmcp::model::{
+    InitializeRequestParam, CallToolResult, Content, InitializeResult, ListResourceTemplatesResult, ListResourcesResult,
+    Meta, PaginatedRequestParam, ProgressToken, ReadResourceRequestParam, ReadResourceResult,
+    ResourceContents,
+};
+use rmcp::service::RequestContext;
+use rmcp::{ErrorData, RoleServer, ServerHandler, ServiceExt, schemars, tool, tool_handler, tool_router};
 use std::collections::HashMap;
-use std::path::PathBuf;
-use std::sync::{Arc, Mutex};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Instant;
 use tokio::io::{stdin, stdout};
-use tracing::{debug, error, info, warn};
-use flag_rs::{CommandBuilder, Flag, FlagType, FlagValue};
+use tokio_util::sync::CancellationToken;
+use tracing::{Instrument, Span, debug, error, info, info_span, warn};
+use flag_rs::{Command, CommandBuilder, Context, Flag, FlagType, FlagValue};
 
 mod audit;
+mod audit_query;
+mod auth;
+mod cache;
 mod cli_tool;
+mod content;
 mod dynamic_tools;
+mod executable;
+mod handlers;
+mod jobs;
+mod limits;
+mod listen;
+mod output_filter;
+mod output_schema;
+mod pipeline;
+mod policy;
+mod policy_expr;
+mod progress;
+mod rbac;
+mod redact;
+mod resources;
+mod sandbox;
+mod script;
+mod secrets;
+mod session;
+mod tls;
+mod trust;
+mod wasm;
 
-use audit::AuditJournal;
-use dynamic_tools::DynamicToolManager;
+use audit::{AuditEntry, AuditJournal, AuditStatus};
+use audit_query::AuditFilter;
+use auth::{AuthConfig, Authenticator};
+use cache::ResultCache;
+use cli_tool::CallControl;
+use dynamic_tools::{DynamicToolManager, OutputKind};
+use handlers::HandlerRegistry;
+use jobs::{JobStatus, JobTable};
+use listen::ListenAddr;
+use policy::{CallIdentity, Policy};
+use redact::Redactions;
+use resources::ResourceDir;
+use secrets::SecretStore;
+use session::SessionInfo;
+use trust::TrustStore;
 
 #[derive(Debug, serde::Deserialize, schemars::JsonSchema)]
 pub struct RunToolRequest {
@@ -21,17 +70,77 @@ pub struct RunToolRequest {
     pub params: HashMap<String, serde_json::Value>,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, serde::Deserialize, schemars::JsonSchema)]
+pub struct JobRequest {
+    #[schemars(description = "Job id returned when an async tool was called")]
+    pub job_id: String,
+}
+
+#[derive(Debug, serde::Deserialize, schemars::JsonSchema)]
+pub struct JobOutputRequest {
+    #[schemars(description = "Job id returned when an async tool was called")]
+    pub job_id: String,
+    #[schemars(description = "Byte offset to read from (default 0); pass next_offset to page")]
+    pub offset: Option<usize>,
+    #[schemars(description = "Maximum bytes to return (default 65536)")]
+    pub limit: Option<usize>,
+    #[schemars(description = "Return only the last N lines instead of a page")]
+    pub tail: Option<usize>,
+}
+
+const DEFAULT_JOB_OUTPUT_LIMIT: usize = 64 * 1024;
+
+#[derive(Clone)]
 pub struct GameCodeMcpServer {
     tool_manager: DynamicToolManager,
     audit: AuditJournal,
+    policy: Arc<Policy>,
+    // Per-client session; filled in by the initialize handshake
+    session: Arc<RwLock<SessionInfo>>,
+    // Background jobs, shared by every session
+    jobs: Arc<JobTable>,
+    // Results of cached tools, shared by every session
+    cache: Arc<ResultCache>,
+    // Directories whose files are served as resources
+    resource_dirs: Arc<Vec<ResourceDir>>,
+    tool_router: ToolRouter<Self>,
 }
 
 impl GameCodeMcpServer {
-    pub fn new(audit: AuditJournal) -> Self {
+    pub fn new(audit: AuditJournal, policy: Policy, tool_manager: DynamicToolManager) -> Self {
         Self {
-            tool_manager: DynamicToolManager::new(),
+            tool_manager,
             audit,
+            policy: Arc::new(policy),
+            session: Arc::new(RwLock::new(SessionInfo::new())),
+            jobs: Arc::new(JobTable::default()),
+            cache: Arc::new(ResultCache::default()),
+            resource_dirs: Arc::new(Vec::new()),
+            tool_router: Self::tool_router(),
+        }
+    }
+
+    pub fn with_jobs(mut self, jobs: JobTable) -> Self {
+        self.jobs = Arc::new(jobs);
+        self
+    }
+
+    pub fn with_cache(mut self, cache: ResultCache) -> Self {
+        self.cache = Arc::new(cache);
+        self
+    }
+
+    pub fn with_resource_dirs(mut self, dirs: Vec<ResourceDir>) -> Self {
+        self.resource_dirs = Arc::new(dirs);
+        self
+    }
+
+    /// A server for another client session, sharing the loaded tools and
+    /// audit journal with this one.
+    pub fn new_session(&self) -> Self {
+        Self {
+            session: Arc::new(RwLock::new(SessionInfo::new())),
+            ..self.clone()
         }
     }
 
@@ -42,33 +151,75 @@ impl GameCodeMcpServer {
             warn!("Please create a tools.yaml file to enable tools.");
         }
     }
+
+    fn session(&self) -> SessionInfo {
+        self.session.read().unwrap().clone()
+    }
+
+    // Tools the session's caller may see, for resources
+    async fn visible_tools(
+        &self,
+        context: &RequestContext<RoleServer>,
+    ) -> Result<Vec<dynamic_tools::ToolInfo>, ErrorData> {
+        let session = self.session();
+        check_principal(&session, context).map_err(|e| ErrorData::invalid_request(e, None))?;
+        let caller = self.policy.roles.caller(session.principal.as_ref());
+        Ok(self.tool_manager.list_tools(&caller).await)
+    }
 }
 
 // Dynamic tool execution - all tools come from YAML now
-#[tool(tool_box)]
+#[tool_router]
 impl GameCodeMcpServer {
     #[tool(description = "Execute a tool defined in tools.yaml")]
-    async fn run(&self, #[tool(aggr)] req: RunToolRequest) -> String {
-        // Log tool invocation to audit journal
-        self.audit.log_tool_invocation(&req.tool).await;
-        
-        match self.tool_manager.execute_tool(&req.tool, req.params).await {
-            Ok(result) => result,
-            Err(e) => format!(r#"{{"error": "{}"}}"#, e),
+    async fn run(
+        &self,
+        Parameters(req): Parameters<RunToolRequest>,
+        context: RequestContext<RoleServer>,
+    ) -> Result<CallToolResult, ErrorData> {
+        let session = self.session();
+        if let Err(e) = check_principal(&session, &context) {
+            return Ok(text_result(e));
         }
+        let span = info_span!(
+            "tool_call",
+            tool = %req.tool,
+            session_id = %session.session_id,
+            client = %session.client_label(),
+            principal = session.principal_name().unwrap_or("-"),
+        );
+        let progress = context.meta.get_progress_token();
+        let result = self
+            .run_tool(req, &session, &context, progress)
+            .instrument(span)
+            .await;
+        Ok(result)
     }
 
     #[tool(description = "List all available tools from tools.yaml")]
-    async fn list_tools(&self) -> String {
-        let tools = self.tool_manager.list_tools().await;
+    async fn list_tools(&self, context: RequestContext<RoleServer>) -> String {
+        let session = self.session();
+        if let Err(e) = check_principal(&session, &context) {
+            return e;
+        }
+        let caller = self.policy.roles.caller(session.principal.as_ref());
+        let tools = self.tool_manager.list_tools(&caller).await;
 
         let tool_list: Vec<serde_json::Value> = tools
             .into_iter()
-            .map(|(name, desc)| {
-                serde_json::json!({
-                    "name": name,
-                    "description": desc
-                })
+            .map(|tool| {
+                let mut entry = serde_json::json!({
+                    "name": tool.name,
+                    "description": tool.description
+                });
+                // Named as in MCP's own tool listing
+                if let Some(schema) = tool.output_schema {
+                    entry["outputSchema"] = schema;
+                }
+                if let Some(example) = tool.example_output {
+                    entry["example_output"] = example;
+                }
+                entry
             })
             .collect();
 
@@ -78,20 +229,509 @@ impl GameCodeMcpServer {
         })
         .to_string()
     }
+
+    #[tool(description = "Get the status of a background job started by an async tool")]
+    async fn job_status(
+        &self,
+        Parameters(req): Parameters<JobRequest>,
+        context: RequestContext<RoleServer>,
+    ) -> String {
+        let session = self.session();
+        if let Err(e) = check_principal(&session, &context) {
+            return e;
+        }
+        match self.jobs.get(&req.job_id, session.principal_name()) {
+            Ok(job) => job.summary().to_string(),
+            Err(e) => job_not_found(&e),
+        }
+    }
+
+    #[tool(description = "Read a background job's output, by byte range or as its last lines")]
+    async fn job_output(
+        &self,
+        Parameters(req): Parameters<JobOutputRequest>,
+        context: RequestContext<RoleServer>,
+    ) -> String {
+        let session = self.session();
+        if let Err(e) = check_principal(&session, &context) {
+            return e;
+        }
+        let job = match self.jobs.get(&req.job_id, session.principal_name()) {
+            Ok(job) => job,
+            Err(e) => return job_not_found(&e),
+        };
+        let limit = req.limit.unwrap_or(DEFAULT_JOB_OUTPUT_LIMIT);
+        let (offset, output) = jobs::page(&job.output, req.offset.unwrap_or(0), limit, req.tail);
+        let next_offset = offset + output.len();
+        serde_json::json!({
+            "job_id": job.id,
+            "status": job.status,
+            "output": output,
+            "offset": offset,
+            "next_offset": next_offset,
+            "total": job.output.len(),
+            "done": job.status != JobStatus::Running && next_offset >= job.output.len(),
+        })
+        .to_string()
+    }
+
+    #[tool(description = "Cancel a running background job")]
+    async fn job_cancel(
+        &self,
+        Parameters(req): Parameters<JobRequest>,
+        context: RequestContext<RoleServer>,
+    ) -> String {
+        let session = self.session();
+        if let Err(e) = check_principal(&session, &context) {
+            return e;
+        }
+        match self.jobs.cancel(&req.job_id, session.principal_name()) {
+            Ok(job) => {
+                info!("Job {} ({}) is {:?}", job.id, job.tool, job.status);
+                job.summary().to_string()
+            }
+            Err(e) => job_not_found(&e),
+        }
+    }
+}
+
+impl GameCodeMcpServer {
+    async fn run_tool(
+        &self,
+        mut req: RunToolRequest,
+        session: &SessionInfo,
+        context: &RequestContext<RoleServer>,
+        progress: Option<ProgressToken>,
+    ) -> CallToolResult {
+        let peer = &context.peer;
+        let entry = AuditEntry::new(&req.tool).with_session(session);
+        let started = Instant::now();
+
+        // The bypass flag is ours, not the tool's, so rules, cache keys and
+        // commands never see it, whether or not the tool is cached
+        let bypass = req
+            .params
+            .remove(cache::BYPASS_PARAM)
+            .is_some_and(|v| v == true || v == "true");
+
+        let identity = CallIdentity {
+            policy: self.policy.clone(),
+            principal: session.principal.clone(),
+            caller: self.policy.roles.caller(session.principal.as_ref()),
+            peer: Some(peer.clone()),
+        };
+        let access = self.tool_manager.access(&req.tool).await;
+        let entry = match identity.check(&req.tool, &access, &req.params).await {
+            Ok(None) => entry,
+            Ok(Some(confirmed)) => entry.with_reason(&confirmed),
+            Err(denial) => {
+                self.audit_denial(entry, started, &denial.reason).await;
+                return text_result(denial.to_json());
+            }
+        };
+
+        if self.tool_manager.is_background(&req.tool).await {
+            // In fail-closed mode, don't start a call we can't record
+            if let Err(e) = self.audit.log_start(&entry).await {
+                return text_result(audit_unavailable(&e));
+            }
+            let (tool, principal) = (req.tool.clone(), session.principal_name());
+            let (job_id, token) = match self.jobs.start(&tool, principal) {
+                Ok(job) => job,
+                Err(e) => {
+                    warn!("Refused to start {} as a job: {}", tool, e);
+                    self.audit_denial(entry, started, &e).await;
+                    return text_result(
+                        serde_json::json!({ "error": e, "kind": "too_many_jobs" }).to_string(),
+                    );
+                }
+            };
+            // The job outlives this request, so only job_cancel stops it
+            let control = CallControl {
+                progress: None,
+                cancel: token,
+                identity,
+            };
+            return text_result(self.start_job(req, job_id, control, entry, started, session));
+        }
+
+        let cache = self.tool_manager.cache_config(&req.tool).await;
+        let cached = cache.map(|config| (config.key(&req.tool, &req.params), config.ttl, bypass));
+        let kind = self.tool_manager.output_kind(&req.tool).await;
+        if let Some((key, _, false)) = &cached
+            && let Some(hit) = self.cache.get(key)
+        {
+            return self.cache_hit(entry, started, hit, kind).await;
+        }
+        if let Err(e) = self.audit.log_start(&entry).await {
+            return text_result(audit_unavailable(&e));
+        }
+        let tool = req.tool.clone();
+
+        // Updates go out as the tool runs, a few a second at most
+        let (sender, forwarder) = match progress {
+            Some(token) => {
+                let (sender, updates) = tokio::sync::watch::channel(None);
+                let forwarder = tokio::spawn(progress::forward(peer.clone(), token, updates));
+                (Some(sender), Some(forwarder))
+            }
+            None => (None, None),
+        };
+        // A client's notifications/cancelled, or its disconnecting, stops the tool
+        let control = CallControl {
+            progress: sender,
+            cancel: context.ct.clone(),
+            identity,
+        };
+        let result = self.execute(req, entry, started, session, control).await;
+        // Anything not sent by now is stale, and must not follow the result
+        if let Some(forwarder) = forwarder {
+            forwarder.abort();
+            let _ = forwarder.await;
+        }
+        match result {
+            Ok(output) => {
+                if let Some((key, ttl, _)) = cached {
+                    self.cache.put(&key, &tool, output.clone(), ttl);
+                }
+                tool_result(output, kind)
+            }
+            Err(error) => text_result(error),
+        }
+    }
+
+    // Answer a call from the cache. It is audited like any other success.
+    async fn cache_hit(
+        &self,
+        entry: AuditEntry,
+        started: Instant,
+        hit: cache::Cached,
+        kind: OutputKind,
+    ) -> CallToolResult {
+        debug!("Cache hit for {}, stored {}", hit.tool, hit.stored);
+        let entry = entry
+            .with_cache_hit()
+            .finish(AuditStatus::Success, started.elapsed());
+        if let Err(e) = self.audit.log_tool_invocation(&entry).await
+            && self.audit.is_required()
+        {
+            return text_result(audit_unavailable(&e));
+        }
+        let mut meta = Meta::new();
+        meta.insert("cache_hit".to_string(), serde_json::json!(true));
+        meta.insert("cached_at".to_string(), serde_json::json!(hit.stored));
+        meta.insert("expires_at".to_string(), serde_json::json!(hit.expires));
+        CallToolResult {
+            meta: Some(meta),
+            ..tool_result(hit.output, kind)
+        }
+    }
+
+    // Run an `async: true` tool as a job and return its id right away
+    fn start_job(
+        &self,
+        req: RunToolRequest,
+        job_id: String,
+        control: CallControl,
+        entry: AuditEntry,
+        started: Instant,
+        session: &SessionInfo,
+    ) -> String {
+        info!("Started {} as job {}", req.tool, job_id);
+        let response = serde_json::json!({
+            "job_id": job_id,
+            "tool": req.tool,
+            "status": JobStatus::Running,
+        })
+        .to_string();
+
+        let entry = entry.with_job(&job_id);
+        let (server, session) = (self.clone(), session.clone());
+        let job = async move {
+            let result = server.execute(req, entry, started, &session, control).await;
+            // A cancelled job stays cancelled whatever it returned
+            let (status, output) = match result {
+                Ok(output) => (JobStatus::Succeeded, output),
+                Err(output) => (JobStatus::Failed, output),
+            };
+            server.jobs.finish(&job_id, status, output);
+        };
+        tokio::spawn(job.instrument(Span::current()));
+        response
+    }
+
+    // Execute, redact and audit a call that has been allowed. Err carries the
+    // error JSON returned to the client.
+    async fn execute(
+        &self,
+        req: RunToolRequest,
+        entry: AuditEntry,
+        started: Instant,
+        session: &SessionInfo,
+        control: CallControl,
+    ) -> Result<String, String> {
+        let (result, steps) = self
+            .tool_manager
+            .execute_tool_with_steps(&req.tool, req.params, &control)
+            .await;
+
+        // Each pipeline step gets its own entry, ahead of the pipeline's
+        for step in steps {
+            let step = match &entry.job_id {
+                Some(job_id) => step.with_job(job_id),
+                None => step,
+            };
+            let logged = self.audit.log_tool_invocation(&step.with_session(session)).await;
+            if let Err(e) = logged
+                && self.audit.is_required()
+            {
+                return Err(audit_unavailable(&e));
+            }
+        }
+
+        // Filter what goes back to the model
+        let mut redactions = Redactions::new();
+        let kind = self.tool_manager.output_kind(&req.tool).await;
+        let result = match self.tool_manager.redactor(&req.tool).await {
+            Some(redactor) if kind == OutputKind::Content => {
+                redactor.apply_content(result, &mut redactions)
+            }
+            Some(redactor) => redactor.apply(result, &mut redactions),
+            None => result,
+        };
+        if !redactions.is_empty() {
+            info!("Redacted {:?} from {} output", redactions, req.tool);
+        }
+        let entry = entry.with_redactions(redactions);
+
+        // Log tool invocation to audit journal
+        let logged = self
+            .audit
+            .log_tool_invocation(&entry.finish_result(&result, started.elapsed()))
+            .await;
+        if let Err(e) = logged
+            && self.audit.is_required()
+        {
+            return Err(audit_unavailable(&e));
+        }
+
+        result.map_err(|e| e.to_json())
+    }
+}
+
+impl GameCodeMcpServer {
+    // Read a shared file. The read is checked and audited like a tool call
+    // named `resources/read`, with the URI and directory as its parameters.
+    async fn read_file(
+        &self,
+        dir: &ResourceDir,
+        relative: &str,
+        uri: &str,
+        context: &RequestContext<RoleServer>,
+    ) -> Result<ResourceContents, ErrorData> {
+        let session = self.session();
+        let entry = AuditEntry::new(resources::READ_TOOL)
+            .with_session(&session)
+            .with_resource(uri);
+        let started = Instant::now();
+
+        let identity = CallIdentity {
+            policy: self.policy.clone(),
+            principal: session.principal.clone(),
+            caller: self.policy.roles.caller(session.principal.as_ref()),
+            peer: Some(context.peer.clone()),
+        };
+        let params = HashMap::from([
+            ("uri".to_string(), serde_json::json!(uri)),
+            ("dir".to_string(), serde_json::json!(dir.name)),
+        ]);
+        let entry = match identity.check(resources::READ_TOOL, &dir.access, &params).await {
+            Ok(None) => entry,
+            Ok(Some(confirmed)) => entry.with_reason(&confirmed),
+            Err(denial) => {
+                self.audit_denial(entry, started, &denial.reason).await;
+                // A directory the caller may not read doesn't exist for it
+                return Err(match denial.kind {
+                    "forbidden" => ErrorData::resource_not_found(format!("No resource {}", uri), None),
+                    _ => ErrorData::invalid_request(denial.to_json(), None),
+                });
+            }
+        };
+        if let Err(e) = self.audit.log_start(&entry).await {
+            return Err(ErrorData::internal_error(audit_unavailable(&e), None));
+        }
+
+        let mut redactions = Redactions::new();
+        let result = resources::read_file(dir, relative, uri, &mut redactions);
+        let entry = entry.with_redactions(redactions);
+        let (status, entry) = match &result {
+            Ok(_) => (AuditStatus::Success, entry),
+            Err(e) => (AuditStatus::Failed, entry.with_reason(e)),
+        };
+        let logged = self
+            .audit
+            .log_tool_invocation(&entry.finish(status, started.elapsed()))
+            .await;
+        if let Err(e) = logged
+            && self.audit.is_required()
+        {
+            return Err(ErrorData::internal_error(audit_unavailable(&e), None));
+        }
+        if result.is_ok() {
+            info!("Read {} for {}", uri, session.client_label());
+        }
+        result.map_err(|e| ErrorData::resource_not_found(e, None))
+    }
+
+    async fn audit_denial(&self, entry: AuditEntry, started: Instant, reason: &str) {
+        let entry = entry
+            .finish(AuditStatus::Denied, started.elapsed())
+            .with_reason(reason);
+        // The call is refused either way, so a journal failure changes nothing
+        let _ = self.audit.log_tool_invocation(&entry).await;
+    }
+}
+
+/// Requests within a session must come from the principal that opened it,
+/// so a leaked session id is useless without the matching credentials.
+fn check_principal(session: &SessionInfo, context: &RequestContext<RoleServer>) -> Result<(), String> {
+    let caller = auth::request_principal(&context.extensions);
+    if caller.as_ref().map(|p| &p.name) == session.principal.as_ref().map(|p| &p.name) {
+        return Ok(());
+    }
+    warn!(
+        "Session {} belongs to {} but the request came from {}",
+        session.session_id,
+        session.principal_name().unwrap_or("-"),
+        caller.as_ref().map_or("-", |p| p.name.as_str())
+    );
+    Err(serde_json::json!({
+        "error": "Request principal does not match the session",
+        "kind": "unauthorized"
+    })
+    .to_string())
+}
+
+fn text_result(text: String) -> CallToolResult {
+    CallToolResult::success(vec![Content::text(text)])
+}
+
+/// A tool's result. Tools with an output schema also get it as
+/// `structuredContent`, which MCP requires to be an object; tools with a
+/// `content:` block get their rendered content.
+fn tool_result(output: String, kind: OutputKind) -> CallToolResult {
+    match kind {
+        OutputKind::Text => text_result(output),
+        OutputKind::Structured => {
+            let value = serde_json::from_str::<serde_json::Value>(&output)
+                .ok()
+                .filter(serde_json::Value::is_object);
+            CallToolResult {
+                structured_content: value,
+                ..text_result(output)
+            }
+        }
+        OutputKind::Content => match content::parse(&output) {
+            Some(content) => CallToolResult::success(content),
+            None => text_result(output),
+        },
+    }
+}
+
+fn job_not_found(message: &str) -> String {
+    serde_json::json!({ "error": message, "kind": "not_found" }).to_string()
 }
 
-#[tool(tool_box)]
+fn audit_unavailable(reason: &str) -> String {
+    serde_json::json!({
+        "error": format!("Tool call refused: audit journal unavailable ({})", reason),
+        "kind": "audit_unavailable"
+    })
+    .to_string()
+}
+
+#[tool_handler]
 impl ServerHandler for GameCodeMcpServer {
+    async fn initialize(
+        &self,
+        request: InitializeRequestParam,
+        context: RequestContext<RoleServer>,
+    ) -> Result<InitializeResult, ErrorData> {
+        if context.peer.peer_info().is_none() {
+            context.peer.set_peer_info(request.clone());
+        }
+
+        let mut session = self.session.write().unwrap();
+        session.set_client(&request);
+        session.principal = auth::request_principal(&context.extensions);
+        info!(
+            "Client {} connected as {} (session {})",
+            session.client_label(),
+            session.principal_name().unwrap_or("-"),
+            session.session_id
+        );
+        Ok(self.get_info())
+    }
+
+    async fn list_resources(
+        &self,
+        _request: Option<PaginatedRequestParam>,
+        context: RequestContext<RoleServer>,
+    ) -> Result<ListResourcesResult, ErrorData> {
+        let tools = self.visible_tools(&context).await?;
+        Ok(ListResourcesResult::with_all_items(resources::list(&tools)))
+    }
+
+    async fn list_resource_templates(
+        &self,
+        _request: Option<PaginatedRequestParam>,
+        context: RequestContext<RoleServer>,
+    ) -> Result<ListResourceTemplatesResult, ErrorData> {
+        let session = self.session();
+        check_principal(&session, &context).map_err(|e| ErrorData::invalid_request(e, None))?;
+        let caller = self.policy.roles.caller(session.principal.as_ref());
+        Ok(ListResourceTemplatesResult::with_all_items(resources::templates(
+            &self.resource_dirs,
+            &caller,
+        )))
+    }
+
+    async fn read_resource(
+        &self,
+        request: ReadResourceRequestParam,
+        context: RequestContext<RoleServer>,
+    ) -> Result<ReadResourceResult, ErrorData> {
+        let tools = self.visible_tools(&context).await?;
+        let uri = request.uri.as_str();
+        let not_found = || ErrorData::resource_not_found(format!("No resource {}", uri), None);
+        let contents = if uri == resources::CONFIG_URI {
+            resources::config(&tools)
+        } else if let Some(name) = resources::tool_name(uri) {
+            match tools.iter().find(|tool| tool.name == name) {
+                Some(tool) => resources::tool_doc(tool),
+                None => return Err(not_found()),
+            }
+        } else if let Some((dir, relative)) = resources::file_dir(&self.resource_dirs, uri) {
+            self.read_file(dir, relative, uri, &context).await?
+        } else {
+            return Err(not_found());
+        };
+        Ok(ReadResourceResult {
+            contents: vec![contents],
+        })
+    }
+
     fn get_info(&self) -> rmcp::model::ServerInfo {
         rmcp::model::ServerInfo {
             protocol_version: Default::default(),
-            capabilities: rmcp::model::ServerCapabilities {
-                tools: Some(rmcp::model::ToolsCapability { list_changed: None }),
-                ..Default::default()
-            },
+            capabilities: rmcp::model::ServerCapabilities::builder()
+                .enable_tools()
+                .enable_resources()
+                .build(),
             server_info: rmcp::model::Implementation {
                 name: "gamecode".to_string(),
                 version: env!("CARGO_PKG_VERSION").to_string(),
+                ..Default::default()
             },
             instructions: Some(
                 "GameCode MCP Server - Dynamic CLI tool integration. Configure tools in tools.yaml"
@@ -115,7 +755,29 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     // Parse command line arguments
     let audit_log = Arc::new(Mutex::new(String::new()));
     let audit_log_clone = Arc::clone(&audit_log);
-    
+    let audit_required = Arc::new(AtomicBool::new(false));
+    let audit_required_clone = Arc::clone(&audit_required);
+    let listen = Arc::new(Mutex::new(String::new()));
+    let listen_clone = Arc::clone(&listen);
+    let auth_config = Arc::new(Mutex::new(String::new()));
+    let auth_config_clone = Arc::clone(&auth_config);
+    let policy = Arc::new(Mutex::new(String::new()));
+    let policy_clone = Arc::clone(&policy);
+    let exec_dirs = Arc::new(Mutex::new(String::new()));
+    let exec_dirs_clone = Arc::clone(&exec_dirs);
+    let jobs_dir = Arc::new(Mutex::new(String::new()));
+    let jobs_dir_clone = Arc::clone(&jobs_dir);
+    let job_retention = Arc::new(Mutex::new(String::new()));
+    let job_retention_clone = Arc::clone(&job_retention);
+    let cache_dir = Arc::new(Mutex::new(String::new()));
+    let cache_dir_clone = Arc::clone(&cache_dir);
+    let resource_dirs = Arc::new(Mutex::new(String::new()));
+    let resource_dirs_clone = Arc::clone(&resource_dirs);
+    let allow_unauthenticated = Arc::new(AtomicBool::new(false));
+    let allow_unauthenticated_clone = Arc::clone(&allow_unauthenticated);
+    let serve = Arc::new(AtomicBool::new(false));
+    let serve_clone = Arc::clone(&serve);
+
     let app = CommandBuilder::new("gamecode-mcp")
         .short("GameCode MCP Server")
         .long("Dynamic CLI tool integration for Claude through YAML configuration")
@@ -126,32 +788,174 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                 .value_type(FlagType::String)
                 .default(FlagValue::String("".to_string()))
         )
+        .flag(
+            Flag::new("audit-required")
+                .usage("Fail closed: refuse to start or to run tools if the audit journal cannot be written")
+                .value_type(FlagType::Bool)
+                .default(FlagValue::Bool(false))
+        )
+        .flag(
+            Flag::new("listen")
+                .short('l')
+                .usage("Serve MCP over HTTP instead of stdio: host:port or unix:/path/to.sock")
+                .value_type(FlagType::String)
+                .default(FlagValue::String("".to_string()))
+        )
+        .flag(
+            Flag::new("auth-config")
+                .usage("YAML file with bearer tokens, TLS/mTLS and JWT settings for --listen")
+                .value_type(FlagType::String)
+                .default(FlagValue::String("".to_string()))
+        )
+        .flag(
+            Flag::new("allow-unauthenticated")
+                .usage("Let --listen serve a non-loopback TCP address without --auth-config")
+                .value_type(FlagType::Bool)
+                .default(FlagValue::Bool(false))
+        )
+        .flag(
+            Flag::new("policy")
+                .usage("YAML policy file with role bindings and argument-level rules for tool calls")
+                .value_type(FlagType::String)
+                .default(FlagValue::String("".to_string()))
+        )
+        .flag(
+            Flag::new("allowed-exec-dirs")
+                .usage("Colon-separated directories tool commands must resolve into (default: anywhere)")
+                .value_type(FlagType::String)
+                .default(FlagValue::String("".to_string()))
+        )
+        .flag(
+            Flag::new("jobs-dir")
+                .usage("Directory to keep background jobs in, so their results survive a restart")
+                .value_type(FlagType::String)
+                .default(FlagValue::String("".to_string()))
+        )
+        .flag(
+            Flag::new("job-retention")
+                .usage("How long finished background jobs are kept, e.g. 30m, 24h or 7d")
+                .value_type(FlagType::String)
+                .default(FlagValue::String("24h".to_string()))
+        )
+        .flag(
+            Flag::new("cache-dir")
+                .usage("Directory to keep cached tool results in, so they survive a restart")
+                .value_type(FlagType::String)
+                .default(FlagValue::String("".to_string()))
+        )
+        .flag(
+            Flag::new("resource-dirs")
+                .usage("Colon-separated NAME=PATH directories whose files clients may read as resources")
+                .value_type(FlagType::String)
+                .default(FlagValue::String("".to_string()))
+        )
+        .subcommand(build_audit_command())
+        .subcommand(build_auth_command())
+        .subcommand(build_trust_command())
+        .subcommand(build_secret_command())
         .run(move |ctx| {
             // Get audit log path from flags
             if let Some(log_path) = ctx.flag("audit-log") {
                 let mut log = audit_log_clone.lock().unwrap();
                 *log = log_path.to_string();
             }
+            let required = ctx.flag("audit-required").is_some_and(|v| v != "false");
+            audit_required_clone.store(required, Ordering::SeqCst);
+            if let Some(addr) = ctx.flag("listen") {
+                *listen_clone.lock().unwrap() = addr.to_string();
+            }
+            if let Some(path) = ctx.flag("auth-config") {
+                *auth_config_clone.lock().unwrap() = path.to_string();
+            }
+            let allow = ctx.flag("allow-unauthenticated").is_some_and(|v| v != "false");
+            allow_unauthenticated_clone.store(allow, Ordering::SeqCst);
+            if let Some(path) = ctx.flag("policy") {
+                *policy_clone.lock().unwrap() = path.to_string();
+            }
+            if let Some(dirs) = ctx.flag("allowed-exec-dirs") {
+                *exec_dirs_clone.lock().unwrap() = dirs.to_string();
+            }
+            if let Some(dir) = ctx.flag("jobs-dir") {
+                *jobs_dir_clone.lock().unwrap() = dir.to_string();
+            }
+            if let Some(retention) = ctx.flag("job-retention") {
+                *job_retention_clone.lock().unwrap() = retention.to_string();
+            }
+            if let Some(dir) = ctx.flag("cache-dir") {
+                *cache_dir_clone.lock().unwrap() = dir.to_string();
+            }
+            if let Some(dirs) = ctx.flag("resource-dirs") {
+                *resource_dirs_clone.lock().unwrap() = dirs.to_string();
+            }
+            serve_clone.store(true, Ordering::SeqCst);
             Ok(())
         })
         .build();
-    
-    // Execute the app to parse args
-    let args: Vec<String> = std::env::args().collect();
+
+    // Execute the app to parse args (skipping the program name)
+    let args: Vec<String> = std::env::args().skip(1).collect();
     if let Err(e) = app.execute(args) {
         error!("Failed to parse arguments: {}", e);
         return Err(e.into());
     }
-    
+
+    // A subcommand ran instead of the server
+    if !serve.load(Ordering::SeqCst) {
+        return Ok(());
+    }
+
+    let listen_addr = listen.lock().unwrap().clone();
+    let listen_addr = if listen_addr.is_empty() {
+        None
+    } else {
+        Some(ListenAddr::parse(&listen_addr)?)
+    };
+
+    let auth_config = auth_config.lock().unwrap().clone();
+    let auth_config = if auth_config.is_empty() {
+        None
+    } else {
+        Some(AuthConfig::load(&PathBuf::from(auth_config))?)
+    };
+
+    let policy = policy.lock().unwrap().clone();
+    let policy = if policy.is_empty() {
+        Policy::default()
+    } else {
+        Policy::load(&PathBuf::from(policy))?
+    };
+
+    let jobs_dir = jobs_dir.lock().unwrap().clone();
+    let jobs_dir = if jobs_dir.is_empty() {
+        None
+    } else {
+        Some(PathBuf::from(jobs_dir))
+    };
+    let job_retention = match job_retention.lock().unwrap().as_str() {
+        "" => chrono::Duration::hours(24),
+        retention => audit_query::parse_age(retention)?,
+    };
+    let jobs = JobTable::new(jobs_dir, job_retention)?;
+
+    let cache = match cache_dir.lock().unwrap().as_str() {
+        "" => ResultCache::default(),
+        dir => ResultCache::with_dir(PathBuf::from(dir))?,
+    };
+
     // Set up audit journal if path provided
-    let audit_log_path = audit_log.lock().unwrap();
+    let audit_log_path = audit_log.lock().unwrap().clone();
     let audit_path = if !audit_log_path.is_empty() {
-        Some(PathBuf::from(audit_log_path.clone()))
+        Some(PathBuf::from(audit_log_path))
     } else {
         None
     };
-    drop(audit_log_path);
-    let audit = AuditJournal::new(audit_path);
+    let audit = match AuditJournal::new(audit_path, audit_required.load(Ordering::SeqCst)) {
+        Ok(audit) => audit,
+        Err(e) => {
+            error!("Refusing to start: {}", e);
+            return Err(e.into());
+        }
+    };
 
     info!(
         "Starting GameCode MCP Server v{}...",
@@ -160,14 +964,90 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     if audit.is_enabled() {
         info!("Audit logging enabled");
     }
+    if audit.is_required() {
+        info!("Audit logging required: tool calls will be refused if the journal cannot be written");
+    }
     info!("Loading tool configuration...");
 
-    let server = GameCodeMcpServer::new(audit);
+    let exec_dirs = executable::allowed_dirs(&exec_dirs.lock().unwrap());
+    if !exec_dirs.is_empty() {
+        info!("Tool commands restricted to {:?}", exec_dirs);
+    }
+    // Register compiled-in handlers for `internal_handler:` here
+    let handlers = HandlerRegistry::default();
+    let tool_manager = DynamicToolManager::new()
+        .with_allowed_exec_dirs(exec_dirs)
+        .with_handlers(handlers);
+    let mut resource_dirs = resources::parse_dirs(&resource_dirs.lock().unwrap())?;
+    resources::apply_access(&mut resource_dirs, &policy.resources)?;
+    if !resource_dirs.is_empty() {
+        info!("Serving files from {:?}", resource_dirs);
+    }
+    let server = GameCodeMcpServer::new(audit, policy, tool_manager)
+        .with_jobs(jobs)
+        .with_cache(cache)
+        .with_resource_dirs(resource_dirs);
 
     // Initialize the server and load tools
     server.initialize().await;
     info!("Server initialized");
 
+    if let Some(addr) = listen_addr {
+        let (authenticator, tls) = match auth_config {
+            Some(mut config) => {
+                let tls = config.tls.take().map(|t| tls::server_config(&t)).transpose()?;
+                (Some(Arc::new(Authenticator::new(config)?)), tls)
+            }
+            None if !addr.is_local() && !allow_unauthenticated.load(Ordering::SeqCst) => {
+                let e = format!(
+                    "Refusing to serve {:?} without --auth-config: anyone who can reach it could \
+                     run tools. Listen on a loopback address or a Unix socket, configure \
+                     authentication, or pass --allow-unauthenticated",
+                    addr
+                );
+                error!("{}", e);
+                return Err(e.into());
+            }
+            None => {
+                warn!("No --auth-config given: any client that can reach {:?} may run tools", addr);
+                (None, None)
+            }
+        };
+
+        // Every HTTP session gets its own server sharing the loaded tools
+        let ct = CancellationToken::new();
+        let mut app = listen::router(move || server.new_session(), ct.clone());
+        if let Some(authenticator) = authenticator {
+            app = app.layer(axum::middleware::from_fn_with_state(
+                authenticator,
+                auth::require_auth,
+            ));
+        }
+        tokio::spawn({
+            let ct = ct.clone();
+            async move {
+                let mut sigterm =
+                    tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+                        .expect("failed to install SIGTERM handler");
+                tokio::select! {
+                    _ = tokio::signal::ctrl_c() => {}
+                    _ = sigterm.recv() => {}
+                }
+                info!("Shutting down...");
+                ct.cancel();
+            }
+        });
+        if let Err(e) = listen::serve(&addr, app, tls, ct).await {
+            error!("HTTP server error: {}", e);
+            return Err(e.into());
+        }
+        info!("Server quit");
+        return Ok(());
+    }
+
+    if auth_config.is_some() {
+        warn!("--auth-config only applies to --listen; ignoring it for stdio");
+    }
     let transport = (stdin(), stdout());
     debug!("Transport setup complete");
 
@@ -199,3 +1079,271 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     info!("Server quit: {:?}", quit_reason);
     Ok(())
 }
+
+fn build_audit_command() -> Command {
+    let filter_flags = || {
+        vec![
+            Flag::new("dir")
+                .short('d')
+                .usage("Audit log directory (defaults to --audit-log or ~/.config/gamecode-mcp/audit)")
+                .value_type(FlagType::String),
+            Flag::new("since")
+                .usage("Only entries at or after this time (RFC 3339, YYYY-MM-DD, today, yesterday, or an age like 24h/7d)")
+                .value_type(FlagType::String),
+            Flag::new("until")
+                .usage("Only entries before this time (same formats as --since)")
+                .value_type(FlagType::String),
+            Flag::new("tool")
+                .usage("Only entries for this tool")
+                .value_type(FlagType::String),
+            Flag::new("user")
+                .usage("Only entries for this user")
+                .value_type(FlagType::String),
+            Flag::new("client")
+                .usage("Only entries from this MCP client (clientInfo name)")
+                .value_type(FlagType::String),
+            Flag::new("session")
+                .usage("Only entries from this session id")
+                .value_type(FlagType::String),
+            Flag::new("status")
+                .usage("Only entries with this status (success, failed, denied or cancelled)")
+                .value_type(FlagType::String),
+            Flag::new("format")
+                .short('o')
+                .usage("Output format: table or json")
+                .value_type(FlagType::String)
+                .default(FlagValue::String("table".to_string())),
+        ]
+    };
+
+    let mut query = CommandBuilder::new("query")
+        .short("Search audit journals")
+        .run(|ctx| {
+            let (dir, filter, json) = audit_args(ctx)?;
+            let entries = audit_query::read_entries(&dir, &filter).map_err(cli_error)?;
+            if json {
+                println!("{}", serde_json::to_string_pretty(&entries).map_err(cli_error)?);
+            } else {
+                println!("{}", audit_query::render_entries_table(&entries));
+            }
+            Ok(())
+        });
+    let mut stats = CommandBuilder::new("stats")
+        .short("Summarize audit journals per tool (counts, failure rate, p50/p95 duration)")
+        .run(|ctx| {
+            let (dir, filter, json) = audit_args(ctx)?;
+            let entries = audit_query::read_entries(&dir, &filter).map_err(cli_error)?;
+            let stats = audit_query::compute_stats(&entries);
+            if json {
+                println!("{}", serde_json::to_string_pretty(&stats).map_err(cli_error)?);
+            } else {
+                println!("{}", audit_query::render_stats_table(&stats));
+            }
+            Ok(())
+        });
+    for flag in filter_flags() {
+        query = query.flag(flag);
+    }
+    for flag in filter_flags() {
+        stats = stats.flag(flag);
+    }
+
+    CommandBuilder::new("audit")
+        .short("Inspect audit journals written with --audit-log")
+        .subcommand(query.build())
+        .subcommand(stats.build())
+        .build()
+}
+
+fn build_auth_command() -> Command {
+    let token = CommandBuilder::new("token")
+        .short("Generate a bearer token and the --auth-config entry for it")
+        .flag(
+            Flag::new("principal")
+                .short('p')
+                .usage("Name the token authenticates as")
+                .value_type(FlagType::String)
+                .required(),
+        )
+        .run(|ctx| {
+            let principal = ctx
+                .flag("principal")
+                .ok_or_else(|| cli_error("--principal is required"))?;
+            let token = auth::generate_token();
+            println!("Token (shown once, give it to the client):\n  {}\n", token);
+            println!("Add to the tokens list in your auth config:");
+            println!("  - principal: {}\n    sha256: {}", principal, auth::hash_token(&token));
+            Ok(())
+        })
+        .build();
+    let hash = CommandBuilder::new("hash")
+        .short("Print the SHA-256 of an existing token read from stdin")
+        .run(|_| {
+            let mut token = String::new();
+            std::io::stdin().read_line(&mut token).map_err(cli_error)?;
+            println!("{}", auth::hash_token(token.trim()));
+            Ok(())
+        })
+        .build();
+
+    CommandBuilder::new("auth")
+        .short("Manage credentials for --auth-config")
+        .subcommand(token)
+        .subcommand(hash)
+        .build()
+}
+
+fn build_trust_command() -> Command {
+    CommandBuilder::new("trust")
+        .short("Allow a project-local tools.yaml to load (defaults to ./tools.yaml)")
+        .flag(
+            Flag::new("revoke")
+                .usage("Remove the file from the trust store instead")
+                .value_type(FlagType::Bool)
+                .default(FlagValue::Bool(false)),
+        )
+        .run(|ctx| {
+            let path = PathBuf::from(ctx.args().first().map_or("tools.yaml", String::as_str));
+            let store_path = TrustStore::default_path()
+                .ok_or_else(|| cli_error("Could not determine home directory"))?;
+            let mut store = TrustStore::load(&store_path).map_err(cli_error)?;
+
+            if ctx.flag("revoke").is_some_and(|v| v != "false") {
+                if store.revoke(&path) {
+                    store.save().map_err(cli_error)?;
+                    println!("No longer trusting {}", path.display());
+                } else {
+                    println!("{} was not trusted", path.display());
+                }
+                return Ok(());
+            }
+
+            let content = std::fs::read(&path)
+                .map_err(|e| cli_error(format!("Failed to read {}: {}", path.display(), e)))?;
+            let text = String::from_utf8_lossy(&content);
+            let tools = dynamic_tools::describe_tools(&text).map_err(cli_error)?;
+            let local = dynamic_tools::local_files(&text, path.parent().unwrap_or(Path::new(".")))
+                .map_err(cli_error)?;
+            println!(
+                "Trusting {} (sha256 {}), which defines:",
+                path.display(),
+                trust::digest(&content, &local)
+            );
+            for (name, runs) in &tools {
+                println!("  {:<24} {}", name, runs);
+            }
+            if !local.is_empty() {
+                println!("\nand runs these files as they are now:");
+                for file in &local {
+                    println!("  {}", file.display());
+                }
+            }
+            store.trust(&path, &content, &local);
+            store.save().map_err(cli_error)?;
+            println!("\nIt will need trusting again if it or any of those files changes.");
+            Ok(())
+        })
+        .build()
+}
+
+fn build_secret_command() -> Command {
+    let name_arg = |ctx: &Context| {
+        ctx.args()
+            .first()
+            .cloned()
+            .ok_or_else(|| cli_error("Expected a secret name"))
+    };
+    let set = CommandBuilder::new("set")
+        .short("Store a secret read from stdin: gamecode-mcp secret set NAME")
+        .run(move |ctx| {
+            let name = name_arg(ctx)?;
+            let mut value = String::new();
+            std::io::stdin().read_line(&mut value).map_err(cli_error)?;
+            let value = value.trim_end_matches(['\n', '\r']);
+            if value.is_empty() {
+                return Err(cli_error("Refusing to store an empty secret"));
+            }
+            let mut store = SecretStore::open_or_create_default().map_err(cli_error)?;
+            store.set(&name, value);
+            store.save().map_err(cli_error)?;
+            println!("Stored '{}'; reference it as `secret: {{ keyfile: {} }}`", name, name);
+            Ok(())
+        })
+        .build();
+    let rm = CommandBuilder::new("rm")
+        .short("Delete a stored secret: gamecode-mcp secret rm NAME")
+        .run(move |ctx| {
+            let name = name_arg(ctx)?;
+            let mut store = SecretStore::open_default().map_err(cli_error)?;
+            if !store.remove(&name) {
+                return Err(cli_error(format!("No secret named '{}'", name)));
+            }
+            store.save().map_err(cli_error)?;
+            println!("Deleted '{}'", name);
+            Ok(())
+        })
+        .build();
+    let list = CommandBuilder::new("list")
+        .short("List the names of stored secrets")
+        .run(|_| {
+            let store = SecretStore::open_default().map_err(cli_error)?;
+            for name in store.names() {
+                println!("{}", name);
+            }
+            Ok(())
+        })
+        .build();
+
+    CommandBuilder::new("secret")
+        .short("Manage the encrypted keyfile for `secret: { keyfile: NAME }` references")
+        .subcommand(set)
+        .subcommand(rm)
+        .subcommand(list)
+        .build()
+}
+
+fn audit_args(ctx: &Context) -> flag_rs::Result<(PathBuf, AuditFilter, bool)> {
+    let dir = match ctx.flag("dir").or(ctx.flag("audit-log")) {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => home::home_dir()
+            .map(|d| d.join(".config/gamecode-mcp/audit"))
+            .ok_or_else(|| cli_error("Could not determine home directory; pass --dir"))?,
+    };
+
+    let parse_time = |name: &str| {
+        ctx.flag(name)
+            .map(|v| audit_query::parse_time(v))
+            .transpose()
+            .map_err(flag_rs::Error::FlagParsing)
+    };
+    let filter = AuditFilter {
+        since: parse_time("since")?,
+        until: parse_time("until")?,
+        tool: ctx.flag("tool").cloned(),
+        user: ctx.flag("user").cloned(),
+        client: ctx.flag("client").cloned(),
+        session_id: ctx.flag("session").cloned(),
+        status: ctx
+            .flag("status")
+            .map(|v| audit_query::parse_status(v))
+            .transpose()
+            .map_err(flag_rs::Error::FlagParsing)?,
+    };
+
+    let json = match ctx.flag("format").map(|f| f.as_str()) {
+        None | Some("table") => false,
+        Some("json") => true,
+        Some(other) => {
+            return Err(flag_rs::Error::FlagParsing(format!(
+                "Unknown format '{}' (expected table or json)",
+                other
+            )));
+        }
+    };
+
+    Ok((dir, filter, json))
+}
+
+fn cli_error(e: impl ToString) -> flag_rs::Error {
+    flag_rs::Error::Custom(e.to_string().into())
+}
@@ -0,0 +1,185 @@
+//! jq filters over a command's JSON output.
+//!
+//! Many CLIs print far more JSON than the model needs, `kubectl get -o json`
+//! being the usual example. A tool's `output.filter` is a jq expression, run
+//! with jaq, that cuts the result down before it is returned. `output.select`
+//! names more filters, and the model may pick one of them with the `select`
+//! parameter; it never gets to run an expression of its own.
+
+use jaq_core::load::{Arena, File, Loader};
+use jaq_core::{Compiler, Ctx, Native, RcIter, load};
+use jaq_json::Val;
+use serde::Deserialize;
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::fmt;
+
+/// The parameter that picks one of a tool's `output.select` filters.
+pub const SELECT_PARAM: &str = "select";
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct OutputConfig {
+    // Applied to every result
+    pub filter: Option<String>,
+    // Filters the caller may choose by name, run after `filter`
+    #[serde(default)]
+    pub select: BTreeMap<String, String>,
+}
+
+/// A compiled jq expression.
+#[derive(Clone)]
+struct Jq {
+    source: String,
+    filter: jaq_core::Filter<Native<Val>>,
+}
+
+impl fmt::Debug for Jq {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("Jq").field(&self.source).finish()
+    }
+}
+
+impl Jq {
+    fn compile(source: &str) -> Result<Self, String> {
+        let loader = Loader::new(jaq_std::defs().chain(jaq_json::defs()));
+        let arena = Arena::default();
+        let program = File { code: source, path: () };
+        let modules = loader.load(&arena, program).map_err(|errors| {
+            let reasons: Vec<String> = errors.into_iter().flat_map(|(_, e)| load_errors(e)).collect();
+            format!("invalid jq filter '{}': {}", source, reasons.join("; "))
+        })?;
+        let filter = Compiler::default()
+            .with_funs(jaq_std::funs().chain(jaq_json::funs()))
+            .compile(modules)
+            .map_err(|errors| {
+                let reasons: Vec<String> = errors
+                    .into_iter()
+                    .flat_map(|(_, e)| e)
+                    .map(|(name, undefined)| format!("undefined {} '{}'", undefined.as_str(), name))
+                    .collect();
+                format!("invalid jq filter '{}': {}", source, reasons.join("; "))
+            })?;
+        Ok(Self {
+            source: source.to_string(),
+            filter,
+        })
+    }
+
+    // Several results become an array, none becomes null
+    fn run(&self, input: Value) -> Result<Value, String> {
+        let inputs = RcIter::new(core::iter::empty());
+        let mut results = self
+            .filter
+            .run((Ctx::new([], &inputs), Val::from(input)))
+            .map(|result| result.map(Value::from))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| format!("Output filter '{}' failed: {}", self.source, e))?;
+        Ok(match results.len() {
+            0 => Value::Null,
+            1 => results.remove(0),
+            _ => Value::Array(results),
+        })
+    }
+}
+
+fn load_errors(error: load::Error<&str>) -> Vec<String> {
+    let near = |rest: &str| match rest.chars().take(10).collect::<String>() {
+        rest if rest.is_empty() => "the end".to_string(),
+        rest => format!("'{}'", rest),
+    };
+    match error {
+        load::Error::Io(errors) => errors.into_iter().map(|(path, e)| format!("{}: {}", path, e)).collect(),
+        load::Error::Lex(errors) => errors
+            .into_iter()
+            .map(|(expect, rest)| format!("expected {} at {}", expect.as_str(), near(rest)))
+            .collect(),
+        load::Error::Parse(errors) => errors
+            .into_iter()
+            .map(|(expect, rest)| format!("expected {} at {}", expect.as_str(), near(rest)))
+            .collect(),
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct OutputFilter {
+    filter: Option<Jq>,
+    select: BTreeMap<String, Jq>,
+}
+
+impl OutputFilter {
+    pub fn new(config: &OutputConfig) -> Result<Self, String> {
+        let filter = config.filter.as_deref().map(Jq::compile).transpose()?;
+        let select = config
+            .select
+            .iter()
+            .map(|(name, source)| Ok((name.clone(), Jq::compile(source)?)))
+            .collect::<Result<_, String>>()?;
+        Ok(Self { filter, select })
+    }
+
+    /// Whether the tool takes a `select` parameter.
+    pub fn has_select(&self) -> bool {
+        !self.select.is_empty()
+    }
+
+    /// Run the tool's filter, then the one `select` names, if any.
+    pub fn apply(&self, output: Value, select: Option<&Value>) -> Result<Value, String> {
+        let output = match &self.filter {
+            Some(filter) => filter.run(output)?,
+            None => output,
+        };
+        let Some(select) = select else {
+            return Ok(output);
+        };
+        let chosen = select.as_str().and_then(|name| self.select.get(name));
+        match chosen {
+            Some(filter) => filter.run(output),
+            None => {
+                let names: Vec<&str> = self.select.keys().map(String::as_str).collect();
+                Err(format!(
+                    "Unknown {} {}; expected one of: {}",
+                    SELECT_PARAM,
+                    select,
+                    names.join(", ")
+                ))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_filter_then_select() {
+        let config: OutputConfig = serde_yaml::from_str(
+            "filter: '[.items[] | {name: .metadata.name, phase: .status.phase}]'\n\
+             select:\n  names: 'map(.name)'\n  running: '.[] | select(.phase == \"Running\") | .name'\n",
+        )
+        .unwrap();
+        let output = OutputFilter::new(&config).unwrap();
+        let pods = json!({"kind": "List", "items": [
+            {"metadata": {"name": "a", "uid": "1"}, "status": {"phase": "Running"}},
+            {"metadata": {"name": "b", "uid": "2"}, "status": {"phase": "Pending"}},
+            {"metadata": {"name": "c", "uid": "3"}, "status": {"phase": "Running"}},
+        ]});
+
+        assert_eq!(
+            output.apply(pods.clone(), None).unwrap(),
+            json!([{"name": "a", "phase": "Running"}, {"name": "b", "phase": "Pending"}, {"name": "c", "phase": "Running"}])
+        );
+        assert_eq!(output.apply(pods.clone(), Some(&json!("names"))).unwrap(), json!(["a", "b", "c"]));
+        // Several results come back as an array
+        assert_eq!(output.apply(pods.clone(), Some(&json!("running"))).unwrap(), json!(["a", "c"]));
+        let err = output.apply(pods, Some(&json!(".[0]"))).unwrap_err();
+        assert!(err.contains("expected one of: names, running"), "{}", err);
+
+        let bad = OutputConfig { filter: Some(".items[".to_string()), select: BTreeMap::new() };
+        assert!(OutputFilter::new(&bad).unwrap_err().contains("invalid jq filter"));
+        let undefined = OutputConfig { filter: Some("nope(1)".to_string()), select: BTreeMap::new() };
+        assert!(OutputFilter::new(&undefined).unwrap_err().contains("undefined filter 'nope'"));
+    }
+}
@@ -0,0 +1,121 @@
+//! Output schemas for tools.
+//!
+//! A tool with an `output_schema` has every result checked against it, which
+//! catches a CLI changing its output under us. By default a mismatch is only
+//! logged; `output_validation: error` fails the call instead. The schema is
+//! also shown to clients, and results are returned as `structuredContent`.
+
+use serde::Deserialize;
+use serde_json::Value;
+use std::fmt;
+use std::sync::Arc;
+use tracing::warn;
+
+// Violations listed in one error before the rest are counted
+const MAX_REPORTED: usize = 5;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Validation {
+    // Log a mismatch and return the result anyway
+    #[default]
+    Warn,
+    // Fail the call
+    Error,
+}
+
+#[derive(Clone)]
+pub struct OutputSchema {
+    schema: Value,
+    validator: Arc<jsonschema::Validator>,
+    mode: Validation,
+}
+
+impl fmt::Debug for OutputSchema {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("OutputSchema")
+            .field("schema", &self.schema)
+            .field("mode", &self.mode)
+            .finish()
+    }
+}
+
+impl OutputSchema {
+    pub fn new(schema: Value, mode: Validation) -> Result<Self, String> {
+        let validator =
+            jsonschema::validator_for(&schema).map_err(|e| format!("invalid output_schema: {}", e))?;
+        Ok(Self {
+            schema,
+            validator: Arc::new(validator),
+            mode,
+        })
+    }
+
+    pub fn schema(&self) -> &Value {
+        &self.schema
+    }
+
+    /// What's wrong with `value`, if anything.
+    pub fn validate(&self, value: &Value) -> Result<(), String> {
+        let errors: Vec<String> = self
+            .validator
+            .iter_errors(value)
+            .map(|e| match e.instance_path().as_str() {
+                "" => e.to_string(),
+                path => format!("{} at {}", e, path),
+            })
+            .collect();
+        if errors.is_empty() {
+            return Ok(());
+        }
+        let mut message = errors[..errors.len().min(MAX_REPORTED)].join("; ");
+        if errors.len() > MAX_REPORTED {
+            message.push_str(&format!(" (and {} more)", errors.len() - MAX_REPORTED));
+        }
+        Err(message)
+    }
+
+    /// Check a tool's result. Only fails in `error` mode.
+    pub fn check(&self, tool: &str, output: &str) -> Result<(), String> {
+        let checked = serde_json::from_str(output)
+            .map_err(|e| format!("output is not JSON: {}", e))
+            .and_then(|value| self.validate(&value));
+        match (checked, self.mode) {
+            (Ok(()), _) => Ok(()),
+            (Err(e), Validation::Warn) => {
+                warn!("Tool '{}' output does not match its output_schema: {}", tool, e);
+                Ok(())
+            }
+            (Err(e), Validation::Error) => Err(format!(
+                "Tool '{}' output does not match its output_schema: {}",
+                tool, e
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_check_modes() {
+        let schema = json!({
+            "type": "object",
+            "required": ["status"],
+            "properties": {"status": {"enum": ["ok", "degraded"]}, "count": {"type": "integer"}}
+        });
+        let strict = OutputSchema::new(schema.clone(), Validation::Error).unwrap();
+        assert!(strict.check("t", r#"{"status": "ok", "count": 3}"#).is_ok());
+        let err = strict.check("t", r#"{"status": "broken", "count": "3"}"#).unwrap_err();
+        assert!(err.contains("at /status") && err.contains("at /count"), "{}", err);
+        assert!(strict.check("t", "not json").unwrap_err().contains("not JSON"));
+
+        let lenient = OutputSchema::new(schema, Validation::Warn).unwrap();
+        assert!(lenient.check("t", r#"{"count": 1}"#).is_ok());
+        assert!(lenient.validate(&json!({"count": 1})).unwrap_err().contains("status"));
+
+        assert!(OutputSchema::new(json!({"type": "nonsense"}), Validation::Warn).is_err());
+    }
+}
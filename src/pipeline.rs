@@ -0,0 +1,313 @@
+//! Pipeline tools: a list of steps that call other tools in one request.
+//!
+//! Steps run in order. Each sees a context document,
+//! `{"params": <call arguments>, "steps": {<id>: <output>, ...}}`, plus
+//! `item` and `index` inside a `foreach`. Any string in a step's `params`
+//! (or in `output`) that starts with `$` is a JSONPath into that context.
+
+use crate::audit::AuditEntry;
+use crate::cli_tool::{CallControl, ToolError};
+use crate::dynamic_tools::DynamicToolManager;
+use jsonpath_rust::JsonPath;
+use serde::Deserialize;
+use serde_json::{Map, Value, json};
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use tokio::sync::Semaphore;
+use tokio::task::JoinSet;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PipelineConfig {
+    pub steps: Vec<StepConfig>,
+    // How many `foreach` items run at once
+    #[serde(default = "default_max_parallel")]
+    pub max_parallel: usize,
+    // Template for the result; all step outputs by id when unset
+    pub output: Option<Value>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct StepConfig {
+    pub id: String,
+    pub tool: String,
+    #[serde(default)]
+    pub params: Map<String, Value>,
+    // Run only if this path matches something other than null or false
+    pub when: Option<String>,
+    // Run once per element of the array this path selects
+    pub foreach: Option<String>,
+    // Record `{"error": ...}` as the output instead of failing the pipeline
+    #[serde(default)]
+    pub continue_on_error: bool,
+}
+
+fn default_max_parallel() -> usize {
+    4
+}
+
+/// Audit entries for the steps of a call, in the order they finished.
+pub type StepLog = Arc<Mutex<Vec<AuditEntry>>>;
+
+// What every step of one pipeline call shares
+#[derive(Clone)]
+struct PipelineCall {
+    manager: DynamicToolManager,
+    pipeline: String,
+    control: CallControl,
+    log: StepLog,
+}
+
+impl PipelineConfig {
+    /// Check everything that doesn't depend on other tools.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.max_parallel == 0 {
+            return Err("max_parallel must be at least 1".to_string());
+        }
+        let mut ids = HashSet::new();
+        for step in &self.steps {
+            if !ids.insert(step.id.as_str()) {
+                return Err(format!("duplicate step id '{}'", step.id));
+            }
+            let paths = step.when.iter().chain(&step.foreach).map(String::as_str);
+            for path in paths.chain(template_paths(&Value::Object(step.params.clone()))) {
+                check_path(path).map_err(|e| format!("step '{}': {}", step.id, e))?;
+            }
+        }
+        if let Some(output) = &self.output {
+            for path in template_paths(output) {
+                check_path(path).map_err(|e| format!("output: {}", e))?;
+            }
+        }
+        Ok(())
+    }
+
+    pub async fn run(
+        &self,
+        manager: &DynamicToolManager,
+        pipeline: &str,
+        params: HashMap<String, Value>,
+        control: &CallControl,
+        log: &StepLog,
+    ) -> Result<String, ToolError> {
+        let call = PipelineCall {
+            manager: manager.clone(),
+            pipeline: pipeline.to_string(),
+            control: control.clone(),
+            log: log.clone(),
+        };
+        let mut context = json!({ "params": params, "steps": {} });
+
+        for step in &self.steps {
+            if let Some(when) = &step.when
+                && !query(&context, when).iter().any(|v| !v.is_null() && *v != false)
+            {
+                tracing::debug!("Pipeline {}: skipping step '{}'", pipeline, step.id);
+                continue;
+            }
+
+            let output = match &step.foreach {
+                None => {
+                    let params = render(&Value::Object(step.params.clone()), &context);
+                    run_step(&call, step, None, params).await?
+                }
+                Some(path) => {
+                    let matches = query(&context, path);
+                    let items = match matches.as_slice() {
+                        _ if !is_singular(path) => matches.into_iter().cloned().collect(),
+                        [Value::Array(items)] => items.clone(),
+                        _ => {
+                            return Err(format!(
+                                "Step '{}': foreach '{}' does not select an array",
+                                step.id, path
+                            )
+                            .into());
+                        }
+                    };
+                    let outputs = self.fan_out(&call, step, &context, items);
+                    Value::Array(outputs.await?)
+                }
+            };
+            context["steps"][&step.id] = output;
+        }
+
+        let result = match &self.output {
+            Some(template) => render(template, &context),
+            None => context["steps"].take(),
+        };
+        Ok(result.to_string())
+    }
+
+    // Run a step once per item, at most `max_parallel` at a time, keeping order
+    async fn fan_out(
+        &self,
+        call: &PipelineCall,
+        step: &StepConfig,
+        context: &Value,
+        items: Vec<Value>,
+    ) -> Result<Vec<Value>, ToolError> {
+        let permits = Arc::new(Semaphore::new(self.max_parallel));
+        let mut tasks = JoinSet::new();
+        for (index, item) in items.into_iter().enumerate() {
+            let mut context = context.clone();
+            context["item"] = item;
+            context["index"] = json!(index);
+            let params = render(&Value::Object(step.params.clone()), &context);
+            let (call, step, permits) = (call.clone(), step.clone(), permits.clone());
+            tasks.spawn(async move {
+                let _permit = permits.acquire_owned().await;
+                let output = run_step(&call, &step, Some(index), params);
+                (index, output.await)
+            });
+        }
+
+        let mut outputs = vec![Value::Null; tasks.len()];
+        while let Some(joined) = tasks.join_next().await {
+            let (index, output) =
+                joined.map_err(|e| format!("Step '{}' panicked: {}", step.id, e))?;
+            // Returning early drops the set, which aborts the remaining items
+            outputs[index] = output?;
+        }
+        Ok(outputs)
+    }
+}
+
+async fn run_step(
+    call: &PipelineCall,
+    step: &StepConfig,
+    index: Option<usize>,
+    params: Value,
+) -> Result<Value, ToolError> {
+    let label = match index {
+        Some(index) => format!("{}/{}[{}]", call.pipeline, step.id, index),
+        None => format!("{}/{}", call.pipeline, step.id),
+    };
+    let entry = AuditEntry::new(&step.tool).with_pipeline(&label);
+    let started = Instant::now();
+
+    let params = match params {
+        Value::Object(map) => map.into_iter().filter(|(_, v)| !v.is_null()).collect(),
+        _ => HashMap::new(),
+    };
+    let result = call.manager.execute_step(&step.tool, params, &call.control, &call.log, entry);
+    let (entry, result) = result.await;
+    call.log.lock().unwrap().push(entry.finish_result(&result, started.elapsed()));
+
+    match result {
+        Ok(output) => Ok(serde_json::from_str(&output).unwrap_or(Value::String(output))),
+        // Cancelling or a denied step stops the pipeline, whatever the step allows
+        Err(e @ (ToolError::Cancelled | ToolError::Denied(_))) => Err(e),
+        Err(e) if step.continue_on_error => Ok(json!({ "error": e.to_string() })),
+        Err(ToolError::LimitExceeded { limit, message }) => Err(ToolError::LimitExceeded {
+            limit,
+            message: format!("Step '{}': {}", label, message),
+        }),
+        Err(ToolError::Failed(message)) => {
+            Err(format!("Step '{}' ({}) failed: {}", label, step.tool, message).into())
+        }
+    }
+}
+
+fn is_path(text: &str) -> bool {
+    text.starts_with('$')
+}
+
+fn check_path(path: &str) -> Result<(), String> {
+    jsonpath_rust::parser::parse_json_path(path)
+        .map(|_| ())
+        .map_err(|e| format!("invalid JSONPath '{}': {}", path, e))
+}
+
+// A path made only of names and indexes selects at most one value, which is
+// bound as is; any other path binds the array of everything it matches
+fn is_singular(path: &str) -> bool {
+    let mut quoted = None;
+    for c in path.chars() {
+        match (quoted, c) {
+            (Some(q), c) if c == q => quoted = None,
+            (Some(_), _) => {}
+            (None, '\'' | '"') => quoted = Some(c),
+            (None, '*' | '?' | ':' | ',') => return false,
+            _ => {}
+        }
+    }
+    !path.contains("..")
+}
+
+fn query<'a>(context: &'a Value, path: &str) -> Vec<&'a Value> {
+    context.query(path).unwrap_or_default()
+}
+
+fn template_paths(template: &Value) -> Vec<&str> {
+    match template {
+        Value::String(text) if is_path(text) => vec![text.as_str()],
+        Value::Array(items) => items.iter().flat_map(template_paths).collect(),
+        Value::Object(map) => map.values().flat_map(template_paths).collect(),
+        _ => Vec::new(),
+    }
+}
+
+fn render(template: &Value, context: &Value) -> Value {
+    match template {
+        Value::String(path) if is_path(path) => {
+            let matches = query(context, path);
+            if is_singular(path) {
+                matches.first().map_or(Value::Null, |v| (*v).clone())
+            } else {
+                Value::Array(matches.into_iter().cloned().collect())
+            }
+        }
+        Value::Array(items) => Value::Array(items.iter().map(|v| render(v, context)).collect()),
+        Value::Object(map) => Value::Object(
+            map.iter()
+                .map(|(k, v)| (k.clone(), render(v, context)))
+                .collect(),
+        ),
+        other => other.clone(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_binds_paths() {
+        let context = json!({
+            "params": { "dir": "/srv" },
+            "steps": { "ls": { "files": [{ "name": "a", "size": 1 }, { "name": "b", "size": 2 }] } }
+        });
+        let template = json!({
+            "path": "$.params.dir",
+            "first": "$.steps.ls.files[0].name",
+            "names": "$.steps.ls.files[*].name",
+            "big": "$.steps.ls.files[?@.size > 1].name",
+            "missing": "$.params.nope",
+            "literal": ["x", 3]
+        });
+        assert_eq!(
+            render(&template, &context),
+            json!({
+                "path": "/srv",
+                "first": "a",
+                "names": ["a", "b"],
+                "big": ["b"],
+                "missing": null,
+                "literal": ["x", 3]
+            })
+        );
+        assert!(is_singular("$.a['x*y'][0]") && !is_singular("$..name"));
+    }
+
+    #[test]
+    fn test_validate() {
+        let config = |yaml: &str| serde_yaml::from_str::<PipelineConfig>(yaml).unwrap().validate();
+        assert!(config("steps:\n  - { id: a, tool: t, params: { x: $.params.x } }\n").is_ok());
+        let err = config("steps:\n  - { id: a, tool: t }\n  - { id: a, tool: t }\n").unwrap_err();
+        assert!(err.contains("duplicate step id 'a'"), "{}", err);
+        let err = config("steps:\n  - { id: a, tool: t, when: '$.[' }\n").unwrap_err();
+        assert!(err.contains("invalid JSONPath"), "{}", err);
+    }
+}
@@ -0,0 +1,441 @@
+use crate::auth::Principal;
+use crate::policy_expr::{self, Expr};
+use crate::rbac::{Caller, RoleBindings, ToolAccess};
+use chrono::{DateTime, Local, Timelike};
+use rmcp::RoleServer;
+use rmcp::model::{CreateElicitationRequestParam, ElicitationAction, ElicitationSchema};
+use rmcp::service::Peer;
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+use tracing::{info, warn};
+
+/// The `--policy` file: role bindings plus argument-level rules.
+#[derive(Debug, Default)]
+pub struct Policy {
+    pub roles: RoleBindings,
+    // Who may read each `--resource-dirs` directory, by name
+    pub resources: HashMap<String, ToolAccess>,
+    rules: Vec<Rule>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PolicyFile {
+    #[serde(default)]
+    roles: RoleBindings,
+    #[serde(default)]
+    resources: HashMap<String, ToolAccess>,
+    #[serde(default)]
+    rules: Vec<RuleConfig>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RuleConfig {
+    name: String,
+    // Tool names the rule applies to; `*` matches any run of characters
+    #[serde(default)]
+    tools: Vec<String>,
+    when: String,
+    effect: Effect,
+    message: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Effect {
+    Allow,
+    Deny,
+    Confirm,
+}
+
+#[derive(Debug)]
+struct Rule {
+    name: String,
+    tools: Vec<String>,
+    when: Expr,
+    effect: Effect,
+    message: Option<String>,
+}
+
+/// Outcome of checking a call against the rules.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Decision {
+    Allow,
+    Deny { rule: String, message: String },
+    Confirm { rule: String, message: String },
+}
+
+impl Policy {
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read policy file {:?}: {}", path, e))?;
+        Self::parse(&content).map_err(|e| format!("Invalid policy file {:?}: {}", path, e))
+    }
+
+    fn parse(content: &str) -> Result<Self, String> {
+        let file: PolicyFile = serde_yaml::from_str(content).map_err(|e| e.to_string())?;
+        let rules = file
+            .rules
+            .into_iter()
+            .map(|rule| {
+                let when = policy_expr::parse(&rule.when)
+                    .map_err(|e| format!("rule '{}': {}", rule.name, e))?;
+                Ok(Rule {
+                    name: rule.name,
+                    tools: rule.tools,
+                    when,
+                    effect: rule.effect,
+                    message: rule.message,
+                })
+            })
+            .collect::<Result<_, String>>()?;
+        Ok(Self {
+            roles: file.roles,
+            resources: file.resources,
+            rules,
+        })
+    }
+
+    /// Check a call against the rules in order; the first rule whose `when`
+    /// holds decides. With no match the call is allowed. A rule that fails
+    /// to evaluate denies, so a broken rule never lets a call through.
+    pub fn evaluate(
+        &self,
+        tool: &str,
+        params: &HashMap<String, Value>,
+        principal: Option<&Principal>,
+        caller: &Caller,
+        now: DateTime<Local>,
+    ) -> Decision {
+        let rules: Vec<&Rule> = self
+            .rules
+            .iter()
+            .filter(|rule| rule.tools.is_empty() || rule.tools.iter().any(|p| glob_match(p, tool)))
+            .collect();
+        if rules.is_empty() {
+            return Decision::Allow;
+        }
+
+        let context = context(tool, params, principal, caller, now);
+        for rule in rules {
+            let message = || {
+                rule.message
+                    .clone()
+                    .unwrap_or_else(|| format!("Blocked by policy rule '{}'", rule.name))
+            };
+            match policy_expr::eval(&rule.when, &context) {
+                Ok(Value::Bool(false)) => continue,
+                Ok(Value::Bool(true)) => {
+                    return match rule.effect {
+                        Effect::Allow => Decision::Allow,
+                        Effect::Deny => Decision::Deny {
+                            rule: rule.name.clone(),
+                            message: message(),
+                        },
+                        Effect::Confirm => Decision::Confirm {
+                            rule: rule.name.clone(),
+                            message: message(),
+                        },
+                    };
+                }
+                Ok(other) => {
+                    return Decision::Deny {
+                        rule: rule.name.clone(),
+                        message: format!(
+                            "Policy rule '{}' did not evaluate to true or false (got {})",
+                            rule.name, other
+                        ),
+                    };
+                }
+                Err(e) => {
+                    return Decision::Deny {
+                        rule: rule.name.clone(),
+                        message: format!("Policy rule '{}' could not be evaluated: {}", rule.name, e),
+                    };
+                }
+            }
+        }
+        Decision::Allow
+    }
+}
+
+/// Who is making a call, with what it takes to check it. Calls a tool
+/// makes on the caller's behalf (pipeline steps, a script's `call_tool`)
+/// are checked with the same identity.
+#[derive(Debug, Clone)]
+pub struct CallIdentity {
+    pub policy: Arc<Policy>,
+    pub principal: Option<Principal>,
+    pub caller: Caller,
+    // Where `confirm` rules prompt; without one they deny
+    pub peer: Option<Peer<RoleServer>>,
+}
+
+impl Default for CallIdentity {
+    // No roles and no one to ask, so role-gated tools and confirm rules deny
+    fn default() -> Self {
+        let policy = Arc::new(Policy::default());
+        let caller = policy.roles.caller(None);
+        Self {
+            policy,
+            principal: None,
+            caller,
+            peer: None,
+        }
+    }
+}
+
+/// Why a call was refused.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Denial {
+    // forbidden, policy_denied or confirmation_required
+    pub kind: &'static str,
+    // Returned to the client
+    pub message: String,
+    pub rule: Option<String>,
+    // Recorded in the audit journal
+    pub reason: String,
+}
+
+impl Denial {
+    pub fn forbidden(tool: &str, reason: String) -> Self {
+        Self {
+            kind: "forbidden",
+            message: format!("Access denied: you may not call tool '{}'", tool),
+            rule: None,
+            reason,
+        }
+    }
+
+    /// The denial as returned to the client.
+    pub fn to_json(&self) -> String {
+        let mut body = serde_json::json!({ "error": self.message, "kind": self.kind });
+        if let Some(rule) = &self.rule {
+            body["rule"] = Value::from(rule.as_str());
+        }
+        body.to_string()
+    }
+}
+
+impl CallIdentity {
+    /// Check a call against the tool's access rules, then the policy rules,
+    /// asking the user when a rule says to confirm. `Ok` carries the
+    /// confirmation to record, if there was one.
+    pub async fn check(
+        &self,
+        tool: &str,
+        access: &ToolAccess,
+        params: &HashMap<String, Value>,
+    ) -> Result<Option<String>, Denial> {
+        if let Err(reason) = access.check(&self.caller) {
+            warn!("Denied {} to {}: {}", tool, self.caller.name, reason);
+            return Err(Denial::forbidden(tool, reason));
+        }
+
+        let decision = self.policy.evaluate(
+            tool,
+            params,
+            self.principal.as_ref(),
+            &self.caller,
+            Local::now(),
+        );
+        match decision {
+            Decision::Allow => Ok(None),
+            Decision::Deny { rule, message } => {
+                warn!("Policy rule '{}' denied {}: {}", rule, tool, message);
+                Err(Denial {
+                    kind: "policy_denied",
+                    reason: format!("policy rule '{}': {}", rule, message),
+                    message,
+                    rule: Some(rule),
+                })
+            }
+            Decision::Confirm { rule, message } => {
+                match confirm_with_user(self.peer.as_ref(), tool, params, &message).await {
+                    Ok(()) => Ok(Some(format!("confirmed by user (policy rule '{}')", rule))),
+                    Err(e) => {
+                        info!("Policy rule '{}' confirmation for {} failed: {}", rule, tool, e);
+                        Err(Denial {
+                            kind: "confirmation_required",
+                            message: format!("{}: {}", message, e),
+                            reason: format!("policy rule '{}': {}", rule, e),
+                            rule: Some(rule),
+                        })
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Ask the human to approve a call flagged by a `confirm` policy rule.
+/// Fails closed when the client can't show the prompt.
+async fn confirm_with_user(
+    peer: Option<&Peer<RoleServer>>,
+    tool: &str,
+    params: &HashMap<String, Value>,
+    message: &str,
+) -> Result<(), String> {
+    let Some(peer) = peer.filter(|peer| peer.supports_elicitation()) else {
+        return Err(
+            "this call needs the user's confirmation, but the client cannot prompt for it"
+                .to_string(),
+        );
+    };
+
+    let params = serde_json::to_string_pretty(params).unwrap_or_default();
+    let result = peer
+        .create_elicitation(CreateElicitationRequestParam {
+            message: format!("{}\n\nAllow tool '{}' with parameters {}?", message, tool, params),
+            requested_schema: ElicitationSchema::new(Default::default()),
+        })
+        .await
+        .map_err(|e| format!("confirmation prompt failed: {}", e))?;
+    match result.action {
+        ElicitationAction::Accept => Ok(()),
+        ElicitationAction::Decline => Err("the user declined".to_string()),
+        ElicitationAction::Cancel => Err("the user cancelled".to_string()),
+    }
+}
+
+fn context(
+    tool: &str,
+    params: &HashMap<String, Value>,
+    principal: Option<&Principal>,
+    caller: &Caller,
+    now: DateTime<Local>,
+) -> Map<String, Value> {
+    let mut context = Map::new();
+    context.insert("tool".to_string(), Value::from(tool));
+    context.insert(
+        "params".to_string(),
+        Value::Object(params.clone().into_iter().collect()),
+    );
+    context.insert(
+        "principal".to_string(),
+        serde_json::json!({
+            "name": caller.name,
+            "groups": caller.groups,
+            "roles": caller.roles,
+            "authenticated": principal.is_some(),
+        }),
+    );
+    context.insert(
+        "time".to_string(),
+        serde_json::json!({
+            "hour": now.hour(),
+            "minute": now.minute(),
+            "weekday": now.format("%a").to_string().to_lowercase(),
+            "date": now.format("%Y-%m-%d").to_string(),
+        }),
+    );
+    context
+}
+
+// Glob with `*` as the only wildcard
+fn glob_match(pattern: &str, name: &str) -> bool {
+    match pattern.split_once('*') {
+        None => pattern == name,
+        Some((prefix, rest)) => {
+            let Some(tail) = name.strip_prefix(prefix) else {
+                return false;
+            };
+            if rest.is_empty() {
+                return true;
+            }
+            (0..=tail.len())
+                .filter(|i| tail.is_char_boundary(*i))
+                .any(|i| glob_match(rest, &tail[i..]))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use serde_json::json;
+
+    const POLICY: &str = r#"
+roles:
+  default_roles: [readonly]
+rules:
+  - name: sre-may-touch-system
+    tools: ["kubectl_*"]
+    when: '"sre" in principal.groups'
+    effect: allow
+  - name: no-kube-system
+    tools: ["kubectl_*"]
+    when: 'params.namespace == "kube-system"'
+    effect: deny
+    message: "kube-system is off limits; use a team namespace"
+  - name: confirm-after-hours
+    when: 'time.hour >= 18 && starts_with(tool, "deploy")'
+    effect: confirm
+    message: "Deploying after hours"
+  - name: numeric-replicas
+    tools: [scale]
+    when: 'params.replicas > 10'
+    effect: deny
+"#;
+
+    fn decide(policy: &Policy, tool: &str, params: Value, groups: &[&str], hour: u32) -> Decision {
+        let params: HashMap<String, Value> = serde_json::from_value(params).unwrap();
+        let principal = Principal {
+            name: "dev".to_string(),
+            method: crate::auth::AuthMethod::Token,
+            groups: groups.iter().map(|g| g.to_string()).collect(),
+        };
+        let caller = policy.roles.caller(Some(&principal));
+        let now = Local.with_ymd_and_hms(2024, 1, 25, hour, 0, 0).unwrap();
+        policy.evaluate(tool, &params, Some(&principal), &caller, now)
+    }
+
+    #[test]
+    fn test_first_matching_rule_decides() {
+        let policy = Policy::parse(POLICY).unwrap();
+        let system = json!({"namespace": "kube-system"});
+
+        assert_eq!(
+            decide(&policy, "kubectl_delete", system.clone(), &[], 12),
+            Decision::Deny {
+                rule: "no-kube-system".to_string(),
+                message: "kube-system is off limits; use a team namespace".to_string()
+            }
+        );
+        assert_eq!(decide(&policy, "kubectl_delete", system.clone(), &["sre"], 12), Decision::Allow);
+        // Rules scoped to other tools don't apply
+        assert_eq!(decide(&policy, "read_logs", system, &[], 12), Decision::Allow);
+
+        assert!(matches!(
+            decide(&policy, "deploy_app", json!({}), &[], 19),
+            Decision::Confirm { .. }
+        ));
+        assert_eq!(decide(&policy, "deploy_app", json!({}), &[], 9), Decision::Allow);
+    }
+
+    #[test]
+    fn test_broken_rules_fail_closed() {
+        let policy = Policy::parse(POLICY).unwrap();
+        // A string where a number is expected can't be compared
+        let decision = decide(&policy, "scale", json!({"replicas": "lots"}), &[], 12);
+        assert!(matches!(decision, Decision::Deny { ref message, .. } if message.contains("could not be evaluated")));
+
+        let err = Policy::parse("rules:\n  - name: bad\n    when: 'params.x =='\n    effect: deny\n")
+            .unwrap_err();
+        assert!(err.contains("rule 'bad'"), "{}", err);
+    }
+
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match("kubectl_*", "kubectl_get"));
+        assert!(glob_match("*_prod", "deploy_prod"));
+        assert!(glob_match("a*b*c", "aXXbYYc"));
+        assert!(!glob_match("kubectl_*", "helm"));
+        assert!(!glob_match("exact", "exactly"));
+    }
+}
@@ -0,0 +1,530 @@
+//! A small expression language for policy rules.
+//!
+//! ```text
+//! params.namespace == "kube-system"
+//! !under(params.path, env.PROJECT) && tool != "read_file"
+//! "oncall" in principal.groups || time.hour < 18
+//! ```
+//!
+//! Values are JSON values. Missing fields are `null`. Operators, loosest
+//! binding first: `||`, `&&`, comparisons (`==`, `!=`, `<`, `<=`, `>`, `>=`,
+//! `in`), then unary `!`. Functions: `starts_with`, `ends_with`, `contains`,
+//! `matches` (regex), `under` (path containment), `len` and `lower`.
+
+use serde_json::{Map, Value};
+use std::path::{Component, Path, PathBuf};
+
+/// Top-level names an expression may refer to.
+const ROOTS: &[&str] = &["tool", "params", "principal", "time", "env"];
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+    Literal(Value),
+    Path(Vec<String>),
+    List(Vec<Expr>),
+    Not(Box<Expr>),
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+    Compare(CompareOp, Box<Expr>, Box<Expr>),
+    Call(String, Vec<Expr>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CompareOp {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    In,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Str(String),
+    Num(f64),
+    Ident(String),
+    Op(&'static str),
+}
+
+fn tokenize(src: &str) -> Result<Vec<Token>, String> {
+    let chars: Vec<char> = src.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+
+    while i < chars.len() {
+        let c = chars[i];
+        if c.is_whitespace() {
+            i += 1;
+        } else if c == '"' || c == '\'' {
+            let mut s = String::new();
+            i += 1;
+            loop {
+                match chars.get(i) {
+                    None => return Err("unterminated string".to_string()),
+                    Some(&q) if q == c => break,
+                    Some('\\') => {
+                        match chars.get(i + 1) {
+                            Some('n') => s.push('\n'),
+                            Some('t') => s.push('\t'),
+                            Some(&other) => s.push(other),
+                            None => return Err("unterminated string".to_string()),
+                        }
+                        i += 1;
+                    }
+                    Some(&other) => s.push(other),
+                }
+                i += 1;
+            }
+            i += 1;
+            tokens.push(Token::Str(s));
+        } else if c.is_ascii_digit() {
+            let start = i;
+            while i < chars.len() && (chars[i].is_ascii_digit() || chars[i] == '.') {
+                i += 1;
+            }
+            let text: String = chars[start..i].iter().collect();
+            let num = text
+                .parse()
+                .map_err(|_| format!("invalid number '{}'", text))?;
+            tokens.push(Token::Num(num));
+        } else if c.is_alphabetic() || c == '_' {
+            let start = i;
+            while i < chars.len() && (chars[i].is_alphanumeric() || chars[i] == '_') {
+                i += 1;
+            }
+            tokens.push(Token::Ident(chars[start..i].iter().collect()));
+        } else {
+            let two: String = chars[i..(i + 2).min(chars.len())].iter().collect();
+            let op = ["&&", "||", "==", "!=", "<=", ">="]
+                .into_iter()
+                .find(|op| *op == two)
+                .or_else(|| {
+                    ["!", "<", ">", "(", ")", "[", "]", ",", ".", "-"]
+                        .into_iter()
+                        .find(|op| op.starts_with(c))
+                })
+                .ok_or_else(|| format!("unexpected character '{}'", c))?;
+            i += op.len();
+            tokens.push(Token::Op(op));
+        }
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn eat_op(&mut self, op: &str) -> bool {
+        if matches!(self.peek(), Some(Token::Op(o)) if *o == op) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect_op(&mut self, op: &str) -> Result<(), String> {
+        if self.eat_op(op) {
+            Ok(())
+        } else {
+            Err(format!("expected '{}'", op))
+        }
+    }
+
+    fn or(&mut self) -> Result<Expr, String> {
+        let mut left = self.and()?;
+        while self.eat_op("||") {
+            left = Expr::Or(Box::new(left), Box::new(self.and()?));
+        }
+        Ok(left)
+    }
+
+    fn and(&mut self) -> Result<Expr, String> {
+        let mut left = self.comparison()?;
+        while self.eat_op("&&") {
+            left = Expr::And(Box::new(left), Box::new(self.comparison()?));
+        }
+        Ok(left)
+    }
+
+    fn comparison(&mut self) -> Result<Expr, String> {
+        let left = self.unary()?;
+        let op = match self.peek() {
+            Some(Token::Op("==")) => CompareOp::Eq,
+            Some(Token::Op("!=")) => CompareOp::Ne,
+            Some(Token::Op("<")) => CompareOp::Lt,
+            Some(Token::Op("<=")) => CompareOp::Le,
+            Some(Token::Op(">")) => CompareOp::Gt,
+            Some(Token::Op(">=")) => CompareOp::Ge,
+            Some(Token::Ident(word)) if word == "in" => CompareOp::In,
+            _ => return Ok(left),
+        };
+        self.pos += 1;
+        let right = self.unary()?;
+        Ok(Expr::Compare(op, Box::new(left), Box::new(right)))
+    }
+
+    fn unary(&mut self) -> Result<Expr, String> {
+        if self.eat_op("!") {
+            return Ok(Expr::Not(Box::new(self.unary()?)));
+        }
+        if self.eat_op("-") {
+            return match self.tokens.get(self.pos).cloned() {
+                Some(Token::Num(n)) => {
+                    self.pos += 1;
+                    Ok(Expr::Literal(number(-n)))
+                }
+                _ => Err("'-' must be followed by a number".to_string()),
+            };
+        }
+        self.primary()
+    }
+
+    fn primary(&mut self) -> Result<Expr, String> {
+        let token = self
+            .tokens
+            .get(self.pos)
+            .cloned()
+            .ok_or("unexpected end of expression")?;
+        self.pos += 1;
+
+        match token {
+            Token::Str(s) => Ok(Expr::Literal(Value::String(s))),
+            Token::Num(n) => Ok(Expr::Literal(number(n))),
+            Token::Op("(") => {
+                let inner = self.or()?;
+                self.expect_op(")")?;
+                Ok(inner)
+            }
+            Token::Op("[") => {
+                let items = self.arguments("]")?;
+                Ok(Expr::List(items))
+            }
+            Token::Ident(word) => match word.as_str() {
+                "true" => Ok(Expr::Literal(Value::Bool(true))),
+                "false" => Ok(Expr::Literal(Value::Bool(false))),
+                "null" => Ok(Expr::Literal(Value::Null)),
+                _ if self.eat_op("(") => {
+                    let args = self.arguments(")")?;
+                    check_call(&word, &args)?;
+                    Ok(Expr::Call(word, args))
+                }
+                _ => {
+                    if !ROOTS.contains(&word.as_str()) {
+                        return Err(format!(
+                            "unknown name '{}' (expected one of {})",
+                            word,
+                            ROOTS.join(", ")
+                        ));
+                    }
+                    let mut path = vec![word];
+                    while self.eat_op(".") {
+                        match self.tokens.get(self.pos).cloned() {
+                            Some(Token::Ident(field)) => {
+                                self.pos += 1;
+                                path.push(field);
+                            }
+                            _ => return Err("expected a field name after '.'".to_string()),
+                        }
+                    }
+                    Ok(Expr::Path(path))
+                }
+            },
+            Token::Op(op) => Err(format!("unexpected '{}'", op)),
+        }
+    }
+
+    fn arguments(&mut self, close: &str) -> Result<Vec<Expr>, String> {
+        let mut args = Vec::new();
+        if self.eat_op(close) {
+            return Ok(args);
+        }
+        loop {
+            args.push(self.or()?);
+            if self.eat_op(close) {
+                return Ok(args);
+            }
+            self.expect_op(",")?;
+        }
+    }
+}
+
+fn number(n: f64) -> Value {
+    serde_json::Number::from_f64(n)
+        .map(Value::Number)
+        .unwrap_or(Value::Null)
+}
+
+fn check_call(name: &str, args: &[Expr]) -> Result<(), String> {
+    let arity = match name {
+        "starts_with" | "ends_with" | "contains" | "matches" | "under" => 2,
+        "len" | "lower" => 1,
+        _ => return Err(format!("unknown function '{}'", name)),
+    };
+    if args.len() != arity {
+        return Err(format!(
+            "{}() takes {} argument{}",
+            name,
+            arity,
+            if arity == 1 { "" } else { "s" }
+        ));
+    }
+    // Catch bad patterns when the policy loads rather than on first use
+    if name == "matches"
+        && let Expr::Literal(Value::String(pattern)) = &args[1]
+    {
+        regex::Regex::new(pattern).map_err(|e| format!("invalid regex: {}", e))?;
+    }
+    Ok(())
+}
+
+pub fn parse(src: &str) -> Result<Expr, String> {
+    let mut parser = Parser {
+        tokens: tokenize(src)?,
+        pos: 0,
+    };
+    let expr = parser.or()?;
+    if parser.pos < parser.tokens.len() {
+        return Err(format!("unexpected {:?}", parser.tokens[parser.pos]));
+    }
+    Ok(expr)
+}
+
+/// Evaluate against `context`, an object holding the root names. `env.*` is
+/// read from the process environment.
+pub fn eval(expr: &Expr, context: &Map<String, Value>) -> Result<Value, String> {
+    match expr {
+        Expr::Literal(value) => Ok(value.clone()),
+        Expr::Path(path) => {
+            if path[0] == "env" {
+                return Ok(match path.get(1) {
+                    Some(name) => std::env::var(name).map(Value::String).unwrap_or(Value::Null),
+                    None => Value::Null,
+                });
+            }
+            let mut value = context.get(&path[0]).unwrap_or(&Value::Null);
+            for field in &path[1..] {
+                value = value.get(field).unwrap_or(&Value::Null);
+            }
+            Ok(value.clone())
+        }
+        Expr::List(items) => Ok(Value::Array(
+            items
+                .iter()
+                .map(|item| eval(item, context))
+                .collect::<Result<_, _>>()?,
+        )),
+        Expr::Not(inner) => Ok(Value::Bool(!truth(&eval(inner, context)?)?)),
+        Expr::And(left, right) => Ok(Value::Bool(
+            truth(&eval(left, context)?)? && truth(&eval(right, context)?)?,
+        )),
+        Expr::Or(left, right) => Ok(Value::Bool(
+            truth(&eval(left, context)?)? || truth(&eval(right, context)?)?,
+        )),
+        Expr::Compare(op, left, right) => {
+            compare(*op, &eval(left, context)?, &eval(right, context)?).map(Value::Bool)
+        }
+        Expr::Call(name, args) => {
+            let args: Vec<Value> = args
+                .iter()
+                .map(|arg| eval(arg, context))
+                .collect::<Result<_, _>>()?;
+            call(name, &args)
+        }
+    }
+}
+
+fn truth(value: &Value) -> Result<bool, String> {
+    match value {
+        Value::Bool(b) => Ok(*b),
+        other => Err(format!("expected true or false, got {}", other)),
+    }
+}
+
+fn compare(op: CompareOp, left: &Value, right: &Value) -> Result<bool, String> {
+    let ordering = || match (left, right) {
+        (Value::Number(a), Value::Number(b)) => a
+            .as_f64()
+            .partial_cmp(&b.as_f64())
+            .ok_or_else(|| "cannot compare NaN".to_string()),
+        (Value::String(a), Value::String(b)) => Ok(a.cmp(b)),
+        _ => Err(format!("cannot order {} and {}", left, right)),
+    };
+
+    Ok(match op {
+        CompareOp::Eq => equal(left, right),
+        CompareOp::Ne => !equal(left, right),
+        CompareOp::Lt => ordering()?.is_lt(),
+        CompareOp::Le => ordering()?.is_le(),
+        CompareOp::Gt => ordering()?.is_gt(),
+        CompareOp::Ge => ordering()?.is_ge(),
+        CompareOp::In => match right {
+            Value::Array(items) => items.iter().any(|item| equal(left, item)),
+            Value::String(s) => s.contains(as_str(left)?),
+            Value::Object(map) => map.contains_key(as_str(left)?),
+            Value::Null => false,
+            other => return Err(format!("'in' needs a list, string or object, got {}", other)),
+        },
+    })
+}
+
+// Numbers compare by value so that 1 == 1.0
+fn equal(left: &Value, right: &Value) -> bool {
+    match (left, right) {
+        (Value::Number(a), Value::Number(b)) => a.as_f64() == b.as_f64(),
+        _ => left == right,
+    }
+}
+
+fn as_str(value: &Value) -> Result<&str, String> {
+    value
+        .as_str()
+        .ok_or_else(|| format!("expected a string, got {}", value))
+}
+
+fn call(name: &str, args: &[Value]) -> Result<Value, String> {
+    let result = match name {
+        "starts_with" => Value::Bool(as_str(&args[0])?.starts_with(as_str(&args[1])?)),
+        "ends_with" => Value::Bool(as_str(&args[0])?.ends_with(as_str(&args[1])?)),
+        "contains" => Value::Bool(compare(CompareOp::In, &args[1], &args[0])?),
+        "matches" => {
+            let re = regex::Regex::new(as_str(&args[1])?)
+                .map_err(|e| format!("invalid regex: {}", e))?;
+            Value::Bool(re.is_match(as_str(&args[0])?))
+        }
+        "under" => Value::Bool(path_under(as_str(&args[0])?, as_str(&args[1])?)),
+        "len" => Value::from(match &args[0] {
+            Value::String(s) => s.chars().count(),
+            Value::Array(items) => items.len(),
+            Value::Object(map) => map.len(),
+            other => return Err(format!("len() needs a string, list or object, got {}", other)),
+        }),
+        "lower" => Value::String(as_str(&args[0])?.to_lowercase()),
+        _ => return Err(format!("unknown function '{}'", name)),
+    };
+    Ok(result)
+}
+
+/// Whether `path` is `dir` or inside it, after resolving `.`, `..` and
+/// symlinks, so neither `$PROJECT/../etc` nor a link out of the project
+/// passes, even for a file that doesn't exist yet.
+fn path_under(path: &str, dir: &str) -> bool {
+    if dir.is_empty() {
+        return false;
+    }
+    resolve(Path::new(path)).starts_with(resolve(Path::new(dir)))
+}
+
+// The longest existing ancestor is canonicalized, one component at a time,
+// and whatever doesn't exist yet is appended lexically
+fn resolve(path: &Path) -> PathBuf {
+    let absolute = if path.is_absolute() {
+        path.to_path_buf()
+    } else {
+        std::env::current_dir().unwrap_or_default().join(path)
+    };
+    let mut resolved = PathBuf::new();
+    // Trailing components that don't exist
+    let mut missing = 0;
+    for component in absolute.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir if missing > 0 => {
+                resolved.pop();
+                missing -= 1;
+            }
+            other if missing > 0 => {
+                resolved.push(other);
+                missing += 1;
+            }
+            other => {
+                resolved.push(other);
+                match resolved.canonicalize() {
+                    Ok(real) => resolved = real,
+                    Err(_) => missing = 1,
+                }
+            }
+        }
+    }
+    resolved
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn check(src: &str, context: &Value) -> Result<Value, String> {
+        eval(&parse(src)?, context.as_object().unwrap())
+    }
+
+    #[test]
+    fn test_evaluates_rules() {
+        let context = json!({
+            "tool": "kubectl_delete",
+            "params": {"namespace": "kube-system", "replicas": 3},
+            "principal": {"name": "junior", "groups": ["dev"]},
+            "time": {"hour": 19},
+        });
+        let yes = |src: &str| assert_eq!(check(src, &context), Ok(json!(true)), "{}", src);
+        let no = |src: &str| assert_eq!(check(src, &context), Ok(json!(false)), "{}", src);
+
+        yes(r#"params.namespace == "kube-system""#);
+        yes(r#"params.namespace in ["kube-system", 'kube-public']"#);
+        yes(r#"starts_with(tool, "kubectl_") && params.replicas >= 3"#);
+        yes(r#"!("oncall" in principal.groups) || time.hour < 18"#);
+        yes(r#"matches(params.namespace, "^kube-")"#);
+        yes("params.missing == null && len(principal.groups) == 1");
+        no(r#"params.namespace != "kube-system" || params.replicas > 3.5"#);
+        no("params.replicas < -1");
+    }
+
+    #[test]
+    fn test_rejects_bad_expressions() {
+        assert!(parse("params.x ==").is_err());
+        assert!(parse("secrets.token == 1").unwrap_err().contains("unknown name"));
+        assert!(parse("frobnicate(tool)").unwrap_err().contains("unknown function"));
+        assert!(parse(r#"matches(tool, "(")"#).unwrap_err().contains("invalid regex"));
+        assert!(parse("tool == 'a").is_err());
+
+        // Type errors surface at evaluation so the rule can fail closed
+        let context = json!({"params": {"n": "three"}});
+        assert!(check("params.n > 2", &context).is_err());
+        assert!(check("params.n && true", &context).is_err());
+    }
+
+    #[test]
+    fn test_path_under_resolves_parent_components() {
+        assert!(path_under("/srv/project/src/main.rs", "/srv/project"));
+        assert!(path_under("/srv/project", "/srv/project/"));
+        assert!(!path_under("/srv/project/../etc/passwd", "/srv/project"));
+        assert!(!path_under("/srv/project-other/x", "/srv/project"));
+        assert!(!path_under("/srv/project/x", ""));
+    }
+
+    #[test]
+    fn test_path_under_follows_links_to_new_files() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let project = temp_dir.path().join("project");
+        let outside = temp_dir.path().join("outside");
+        std::fs::create_dir_all(project.join("src")).unwrap();
+        std::fs::create_dir(&outside).unwrap();
+        std::os::unix::fs::symlink(&outside, project.join("link")).unwrap();
+        let project = project.to_str().unwrap();
+
+        assert!(path_under(&format!("{}/src/new.rs", project), project));
+        assert!(path_under(&format!("{}/new/dir/../file", project), project));
+        assert!(!path_under(&format!("{}/link/newfile", project), project));
+        assert!(!path_under(&format!("{}/link/new/dir/file", project), project));
+        assert!(!path_under(&format!("{}/new/../link/newfile", project), project));
+    }
+}
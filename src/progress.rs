@@ -0,0 +1,176 @@
+//! Progress notifications for command tools.
+//!
+//! While a command runs, every line it writes counts as progress. When the
+//! client sent a progress token with the call, the latest is reported as a
+//! `notifications/progress`, at most a few times a second. By default the progress is the number of lines
+//! so far; a tool with a `progress:` pattern reports the number the pattern
+//! captures instead, such as a percentage. Lines from stderr are sent along
+//! as the message; stdout carries the result, so its lines are only counted.
+
+use regex::Regex;
+use rmcp::RoleServer;
+use rmcp::model::{ProgressNotificationParam, ProgressToken};
+use rmcp::service::Peer;
+use serde::Deserialize;
+use std::time::Duration;
+use tokio::sync::watch;
+use tracing::debug;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ProgressConfig {
+    // Its `progress` group, or else its first group, is the progress so far
+    pub pattern: String,
+    // Used when the pattern has no `total` group
+    pub total: Option<f64>,
+}
+
+/// One update, as sent to the client.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Progress {
+    pub progress: f64,
+    pub total: Option<f64>,
+    pub message: Option<String>,
+}
+
+/// Holds the latest update; one the forwarder hasn't sent yet is replaced.
+pub type ProgressSender = watch::Sender<Option<Progress>>;
+
+// A chatty build would otherwise send a notification per line
+const MIN_INTERVAL: Duration = Duration::from_millis(250);
+
+/// A compiled `progress:` pattern.
+#[derive(Debug, Clone)]
+pub struct ProgressParser {
+    pattern: Regex,
+    total: Option<f64>,
+}
+
+impl ProgressParser {
+    pub fn new(config: &ProgressConfig) -> Result<Self, String> {
+        let pattern = Regex::new(&config.pattern)
+            .map_err(|e| format!("invalid progress pattern: {}", e))?;
+        if pattern.captures_len() < 2 {
+            return Err("progress pattern needs a capture group for the progress".to_string());
+        }
+        Ok(Self {
+            pattern,
+            total: config.total,
+        })
+    }
+
+    fn parse(&self, line: &str) -> Option<(f64, Option<f64>)> {
+        let caps = self.pattern.captures(line)?;
+        let number = |m: Option<regex::Match>| m.and_then(|m| m.as_str().parse::<f64>().ok());
+        // Without names, a second group is the total
+        let unnamed = self.pattern.capture_names().all(|name| name.is_none());
+        let progress = number(caps.name("progress").or_else(|| caps.get(1)))?;
+        let total = caps
+            .name("total")
+            .or_else(|| if unnamed { caps.get(2) } else { None });
+        Some((progress, number(total).or(self.total)))
+    }
+}
+
+/// Turns a command's output lines into updates.
+pub struct LineProgress<'a> {
+    parser: Option<&'a ProgressParser>,
+    lines: u64,
+    last: f64,
+}
+
+impl<'a> LineProgress<'a> {
+    pub fn new(parser: Option<&'a ProgressParser>) -> Self {
+        Self {
+            parser,
+            lines: 0,
+            last: f64::NEG_INFINITY,
+        }
+    }
+
+    /// The update for a line, if it moves progress forward. Clients expect
+    /// progress to increase, so a pattern going backwards is not reported.
+    pub fn line(&mut self, line: &str, message: Option<String>) -> Option<Progress> {
+        self.lines += 1;
+        let (progress, total) = match self.parser {
+            Some(parser) => parser.parse(line)?,
+            None => (self.lines as f64, None),
+        };
+        if progress <= self.last {
+            return None;
+        }
+        self.last = progress;
+        Some(Progress {
+            progress,
+            total,
+            message,
+        })
+    }
+}
+
+/// Send the latest update to the client whenever there is a new one, at
+/// most once per `MIN_INTERVAL`. The caller aborts it once the tool is done,
+/// so updates still waiting then are dropped.
+pub async fn forward(
+    peer: Peer<RoleServer>,
+    token: ProgressToken,
+    mut updates: watch::Receiver<Option<Progress>>,
+) {
+    while updates.changed().await.is_ok() {
+        let Some(update) = updates.borrow_and_update().clone() else {
+            continue;
+        };
+        let sent = peer
+            .notify_progress(ProgressNotificationParam {
+                progress_token: token.clone(),
+                progress: update.progress,
+                total: update.total,
+                message: update.message,
+            })
+            .await;
+        if let Err(e) = sent {
+            debug!("Dropping progress updates: {}", e);
+            return;
+        }
+        tokio::time::sleep(MIN_INTERVAL).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parser(pattern: &str, total: Option<f64>) -> ProgressParser {
+        ProgressParser::new(&ProgressConfig {
+            pattern: pattern.to_string(),
+            total,
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn test_line_counts_and_patterns() {
+        let mut lines = LineProgress::new(None);
+        assert_eq!(lines.line("compiling a", None).unwrap().progress, 1.0);
+        let update = lines.line("compiling b", Some("b".to_string())).unwrap();
+        assert_eq!((update.progress, update.total, update.message.as_deref()), (2.0, None, Some("b")));
+
+        let percent = parser(r"(\d+)%", Some(100.0));
+        let mut lines = LineProgress::new(Some(&percent));
+        assert!(lines.line("starting", None).is_none());
+        assert_eq!(lines.line("[ 40%] built", None).unwrap().total, Some(100.0));
+        assert!(lines.line("[ 40%] linked", None).is_none());
+        assert!(lines.line("[ 10%] again?", None).is_none());
+        assert_eq!(lines.line("[100%] done", None).unwrap().progress, 100.0);
+
+        let counted = parser(r"\[(?P<progress>\d+)/(?P<total>\d+)\]", None);
+        let mut lines = LineProgress::new(Some(&counted));
+        let update = lines.line("[3/12] test foo", None).unwrap();
+        assert_eq!((update.progress, update.total), (3.0, Some(12.0)));
+        let positional = parser(r"(\d+) of (\d+)", None);
+        let update = LineProgress::new(Some(&positional)).line("5 of 9", None).unwrap();
+        assert_eq!((update.progress, update.total), (5.0, Some(9.0)));
+
+        assert!(ProgressParser::new(&ProgressConfig { pattern: r"\d+%".to_string(), total: None }).is_err());
+    }
+}
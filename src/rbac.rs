@@ -0,0 +1,146 @@
+use crate::auth::Principal;
+use serde::Deserialize;
+use std::collections::{BTreeSet, HashMap};
+
+/// Maps principals to roles; the `roles` section of the `--policy` file.
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RoleBindings {
+    // Granted to every caller
+    #[serde(default)]
+    pub default_roles: Vec<String>,
+    // Principal name (OS user, token principal, certificate CN or JWT subject)
+    #[serde(default)]
+    pub users: HashMap<String, Vec<String>>,
+    // Groups from certificate OUs, JWT claims or token entries
+    #[serde(default)]
+    pub groups: HashMap<String, Vec<String>>,
+}
+
+impl RoleBindings {
+    /// Resolve who is calling. Without an authenticated principal (stdio),
+    /// the caller is the local user running the server.
+    pub fn caller(&self, principal: Option<&Principal>) -> Caller {
+        let (name, groups) = match principal {
+            Some(p) => (p.name.clone(), p.groups.clone()),
+            None => (
+                std::env::var("USER").unwrap_or_else(|_| "local".to_string()),
+                Vec::new(),
+            ),
+        };
+
+        let mut roles: BTreeSet<String> = self.default_roles.iter().cloned().collect();
+        roles.extend(self.users.get(&name).into_iter().flatten().cloned());
+        for group in &groups {
+            roles.extend(self.groups.get(group).into_iter().flatten().cloned());
+        }
+
+        Caller {
+            name,
+            groups,
+            roles: roles.into_iter().collect(),
+        }
+    }
+}
+
+/// A caller with its roles resolved.
+#[derive(Debug, Clone)]
+pub struct Caller {
+    pub name: String,
+    pub groups: Vec<String>,
+    pub roles: Vec<String>,
+}
+
+/// Who may see and call a tool, or read a resource directory. With
+/// neither list it is open to all.
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ToolAccess {
+    pub roles: Vec<String>,
+    pub allowed_principals: Vec<String>,
+}
+
+impl ToolAccess {
+    /// `Err` carries the denial reason for the audit journal.
+    pub fn check(&self, caller: &Caller) -> Result<(), String> {
+        if self.roles.is_empty() && self.allowed_principals.is_empty() {
+            return Ok(());
+        }
+        if self.allowed_principals.contains(&caller.name)
+            || caller.roles.iter().any(|r| self.roles.contains(r))
+        {
+            return Ok(());
+        }
+        Err(format!(
+            "principal '{}' with roles [{}] lacks any of roles [{}] or principals [{}]",
+            caller.name,
+            caller.roles.join(", "),
+            self.roles.join(", "),
+            self.allowed_principals.join(", ")
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::AuthMethod;
+
+    fn principal(name: &str, groups: &[&str]) -> Principal {
+        Principal {
+            name: name.to_string(),
+            method: AuthMethod::Token,
+            groups: groups.iter().map(|g| g.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn test_roles_from_users_groups_and_defaults() {
+        let bindings: RoleBindings = serde_yaml::from_str(
+            r#"
+default_roles: [readonly]
+users:
+  alice: [admin]
+groups:
+  ci: [build]
+"#,
+        )
+        .unwrap();
+
+        let alice = bindings.caller(Some(&principal("alice", &["ci"])));
+        assert_eq!(alice.roles, vec!["admin", "build", "readonly"]);
+
+        let junior = bindings.caller(Some(&principal("junior", &[])));
+        assert_eq!(junior.roles, vec!["readonly"]);
+    }
+
+    #[test]
+    fn test_tool_access() {
+        let bindings = RoleBindings {
+            default_roles: vec!["readonly".to_string()],
+            ..Default::default()
+        };
+        let caller = bindings.caller(Some(&principal("junior", &[])));
+
+        assert!(ToolAccess::default().check(&caller).is_ok());
+
+        let readonly = ToolAccess {
+            roles: vec!["readonly".to_string(), "admin".to_string()],
+            allowed_principals: vec![],
+        };
+        assert!(readonly.check(&caller).is_ok());
+
+        let admin_only = ToolAccess {
+            roles: vec!["admin".to_string()],
+            allowed_principals: vec![],
+        };
+        let reason = admin_only.check(&caller).unwrap_err();
+        assert!(reason.contains("junior"), "{}", reason);
+
+        let named = ToolAccess {
+            roles: vec!["admin".to_string()],
+            allowed_principals: vec!["junior".to_string()],
+        };
+        assert!(named.check(&caller).is_ok());
+    }
+}
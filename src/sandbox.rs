@@ -0,0 +1,475 @@
+//! Optional per-tool sandbox for spawned commands.
+//!
+//! On Linux this restricts the child with `no_new_privs`, Landlock
+//! filesystem rules, a seccomp filter and, optionally, an empty network
+//! namespace. Everything that can fail or allocate is prepared in the parent;
+//! the child only makes the final syscalls between fork and exec.
+
+use serde::Deserialize;
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::Mutex;
+use tracing::warn;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SandboxConfig {
+    // Landlock: paths the tool may read (and execute) or also write.
+    // `~`, `$VAR` and `${VAR}` are expanded.
+    #[serde(default)]
+    pub read_only: Vec<String>,
+    #[serde(default)]
+    pub read_write: Vec<String>,
+    // Also allow reading the usual system directories
+    #[serde(default = "default_true")]
+    pub system_paths: bool,
+    #[serde(default)]
+    pub seccomp: SeccompPreset,
+    // `false` runs the tool in an empty network namespace
+    #[serde(default = "default_true")]
+    pub network: bool,
+    // Refuse to run instead of degrading when the kernel lacks a feature
+    #[serde(default)]
+    pub required: bool,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SeccompPreset {
+    None,
+    // Block kernel-level escape hatches: ptrace, mount, module loading, bpf...
+    #[default]
+    Default,
+    // `default`, plus no IPv4/IPv6/raw packet sockets
+    NoNetwork,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+const SYSTEM_READ_ONLY: &[&str] = &["/usr", "/lib", "/lib64", "/bin", "/sbin", "/etc", "/dev", "/proc"];
+const SYSTEM_READ_WRITE: &[&str] = &["/dev/null"];
+
+impl SandboxConfig {
+    fn restricts_filesystem(&self) -> bool {
+        !self.read_only.is_empty() || !self.read_write.is_empty()
+    }
+
+    /// Paths with their write flag, including the command's own binary,
+    /// which was resolved to an absolute path when the tool was loaded.
+    fn filesystem_rules(&self, command: &str) -> Vec<(PathBuf, bool)> {
+        let mut rules = Vec::new();
+        if self.system_paths {
+            rules.extend(SYSTEM_READ_ONLY.iter().map(|p| (PathBuf::from(p), false)));
+            rules.extend(SYSTEM_READ_WRITE.iter().map(|p| (PathBuf::from(p), true)));
+        }
+        rules.push((PathBuf::from(command), false));
+        for (paths, writable) in [(&self.read_only, false), (&self.read_write, true)] {
+            for path in paths {
+                match expand_path(path) {
+                    Some(expanded) => rules.push((expanded, writable)),
+                    None => warn!("Sandbox path '{}' refers to an unset variable; skipping", path),
+                }
+            }
+        }
+        rules
+    }
+
+    /// The same sandbox, also letting the tool write to `path` when it
+    /// confines the filesystem at all.
+    pub fn with_writable(&self, path: &Path) -> Self {
+        let mut config = self.clone();
+        if self.restricts_filesystem() {
+            config.read_write.push(path.display().to_string());
+        }
+        config
+    }
+
+    /// Install the sandbox on `cmd`, or explain why the tool can't run.
+    pub fn apply(&self, tool: &str, command: &str, cmd: &mut Command) -> Result<(), String> {
+        let (missing, installed) = imp::install(self, command, cmd)?;
+        if missing.is_empty() {
+            return Ok(());
+        }
+
+        let msg = format!(
+            "Sandbox for tool '{}': this system lacks {}",
+            tool,
+            missing.join(", ")
+        );
+        if self.required {
+            return Err(format!("{}; refusing to run (sandbox.required is set)", msg));
+        }
+        warn_once(tool, &format!("{}; running with reduced isolation ({})", msg, installed));
+        Ok(())
+    }
+}
+
+// One warning per tool rather than one per call
+fn warn_once(tool: &str, msg: &str) {
+    static WARNED: Mutex<Option<HashSet<String>>> = Mutex::new(None);
+    let mut warned = WARNED.lock().unwrap();
+    if warned.get_or_insert_with(HashSet::new).insert(tool.to_string()) {
+        warn!("{}", msg);
+    }
+}
+
+pub fn expand_path(path: &str) -> Option<PathBuf> {
+    let path = match path.strip_prefix('~') {
+        Some(rest) if rest.is_empty() || rest.starts_with('/') => {
+            format!("{}{}", home::home_dir()?.display(), rest)
+        }
+        _ => path.to_string(),
+    };
+
+    let mut expanded = String::new();
+    let mut rest = path.as_str();
+    while let Some(start) = rest.find('$') {
+        expanded.push_str(&rest[..start]);
+        let after = &rest[start + 1..];
+        let (name, remainder) = match after.strip_prefix('{') {
+            Some(braced) => {
+                let end = braced.find('}')?;
+                (&braced[..end], &braced[end + 1..])
+            }
+            None => {
+                let end = after
+                    .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                    .unwrap_or(after.len());
+                (&after[..end], &after[end..])
+            }
+        };
+        expanded.push_str(&std::env::var(name).ok()?);
+        rest = remainder;
+    }
+    expanded.push_str(rest);
+    Some(PathBuf::from(expanded))
+}
+
+#[cfg(target_os = "linux")]
+mod imp {
+    use super::{SandboxConfig, SeccompPreset};
+    use landlock::{
+        ABI, Access, AccessFs, PathBeneath, PathFd, Ruleset, RulesetAttr, RulesetCreatedAttr,
+    };
+    use seccompiler::{
+        BpfProgram, SeccompAction, SeccompCmpArgLen, SeccompCmpOp, SeccompCondition,
+        SeccompFilter, SeccompRule, TargetArch,
+    };
+    use std::collections::BTreeMap;
+    use std::os::fd::{AsRawFd, OwnedFd};
+    use std::os::unix::process::CommandExt;
+    use std::process::Command;
+    use std::sync::OnceLock;
+    use tracing::debug;
+
+    const DENIED_SYSCALLS: &[libc::c_long] = &[
+        libc::SYS_ptrace,
+        libc::SYS_process_vm_readv,
+        libc::SYS_process_vm_writev,
+        libc::SYS_mount,
+        libc::SYS_umount2,
+        libc::SYS_pivot_root,
+        libc::SYS_chroot,
+        libc::SYS_setns,
+        libc::SYS_unshare,
+        libc::SYS_kexec_load,
+        libc::SYS_init_module,
+        libc::SYS_finit_module,
+        libc::SYS_delete_module,
+        libc::SYS_reboot,
+        libc::SYS_swapon,
+        libc::SYS_swapoff,
+        libc::SYS_bpf,
+        libc::SYS_perf_event_open,
+        libc::SYS_keyctl,
+        libc::SYS_add_key,
+        libc::SYS_request_key,
+        libc::SYS_userfaultfd,
+        libc::SYS_open_by_handle_at,
+        libc::SYS_acct,
+        libc::SYS_settimeofday,
+        libc::SYS_clock_settime,
+        libc::SYS_syslog,
+    ];
+
+    /// Returns the missing features and a summary of what was installed.
+    pub fn install(
+        config: &SandboxConfig,
+        command: &str,
+        cmd: &mut Command,
+    ) -> Result<(Vec<&'static str>, String), String> {
+        let mut missing = Vec::new();
+        let mut installed = vec!["no_new_privs"];
+
+        let landlock = if config.restricts_filesystem() {
+            let fd = landlock_ruleset(&config.filesystem_rules(command))?;
+            match fd {
+                Some(_) => installed.push("Landlock"),
+                None => missing.push("Landlock"),
+            }
+            fd
+        } else {
+            None
+        };
+
+        let seccomp = match config.seccomp {
+            SeccompPreset::None => None,
+            preset if seccomp_supported() => {
+                installed.push("seccomp");
+                Some(seccomp_program(preset)?)
+            }
+            _ => {
+                missing.push("seccomp");
+                None
+            }
+        };
+
+        let isolate_network = !config.network && {
+            let supported = network_namespaces_supported();
+            if supported {
+                installed.push("network namespace");
+            } else {
+                missing.push("unprivileged network namespaces");
+            }
+            supported
+        };
+
+        // SAFETY: the closure runs between fork and exec and only makes
+        // syscalls on values prepared above; it does not allocate on success.
+        unsafe {
+            cmd.pre_exec(move || {
+                if isolate_network
+                    && libc::unshare(libc::CLONE_NEWUSER | libc::CLONE_NEWNET) != 0
+                {
+                    return Err(std::io::Error::last_os_error());
+                }
+                if libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) != 0 {
+                    return Err(std::io::Error::last_os_error());
+                }
+                if let Some(fd) = &landlock
+                    && libc::syscall(libc::SYS_landlock_restrict_self, fd.as_raw_fd(), 0) != 0
+                {
+                    return Err(std::io::Error::last_os_error());
+                }
+                if let Some(program) = &seccomp {
+                    seccompiler::apply_filter(program)
+                        .map_err(|_| std::io::Error::other("failed to install seccomp filter"))?;
+                }
+                Ok(())
+            });
+        }
+
+        Ok((missing, installed.join(", ")))
+    }
+
+    /// Build the Landlock ruleset; `None` when the kernel doesn't support it.
+    fn landlock_ruleset(rules: &[(std::path::PathBuf, bool)]) -> Result<Option<OwnedFd>, String> {
+        let abi = ABI::V5;
+        let err = |e: &dyn std::fmt::Display| format!("Failed to build Landlock ruleset: {}", e);
+        let mut ruleset = Ruleset::default()
+            .handle_access(AccessFs::from_all(abi))
+            .map_err(|e| err(&e))?
+            .create()
+            .map_err(|e| err(&e))?;
+        for (path, writable) in rules {
+            let access = if *writable {
+                AccessFs::from_all(abi)
+            } else {
+                AccessFs::from_read(abi)
+            };
+            match PathFd::new(path) {
+                Ok(fd) => {
+                    ruleset = ruleset
+                        .add_rule(PathBeneath::new(fd, access))
+                        .map_err(|e| err(&e))?;
+                }
+                Err(_) => debug!("Sandbox path {:?} does not exist; skipping", path),
+            }
+        }
+        Ok(ruleset.into())
+    }
+
+    fn seccomp_program(preset: SeccompPreset) -> Result<BpfProgram, String> {
+        let err = |e: &dyn std::fmt::Display| format!("Failed to build seccomp filter: {}", e);
+        let arch = TargetArch::try_from(std::env::consts::ARCH).map_err(|e| err(&e))?;
+
+        // An empty rule list matches the syscall unconditionally
+        let mut rules: BTreeMap<i64, Vec<SeccompRule>> = DENIED_SYSCALLS
+            .iter()
+            .map(|&nr| (nr, Vec::new()))
+            .collect();
+        if preset == SeccompPreset::NoNetwork {
+            let families = [libc::AF_INET, libc::AF_INET6, libc::AF_PACKET]
+                .into_iter()
+                .map(|family| {
+                    SeccompCondition::new(0, SeccompCmpArgLen::Dword, SeccompCmpOp::Eq, family as u64)
+                        .and_then(|condition| SeccompRule::new(vec![condition]))
+                })
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(|e| err(&e))?;
+            rules.insert(libc::SYS_socket, families);
+        }
+
+        let filter = SeccompFilter::new(
+            rules,
+            SeccompAction::Allow,
+            SeccompAction::Errno(libc::EPERM as u32),
+            arch,
+        )
+        .map_err(|e| err(&e))?;
+        BpfProgram::try_from(filter).map_err(|e| err(&e))
+    }
+
+    fn seccomp_supported() -> bool {
+        // EINVAL when the kernel is built without seccomp
+        unsafe { libc::prctl(libc::PR_GET_SECCOMP, 0, 0, 0, 0) >= 0 }
+    }
+
+    /// Whether this process may create a user and network namespace. Probed
+    /// once in a throwaway child, as many distributions disable it.
+    pub fn network_namespaces_supported() -> bool {
+        static SUPPORTED: OnceLock<bool> = OnceLock::new();
+        *SUPPORTED.get_or_init(|| unsafe {
+            let pid = libc::fork();
+            if pid == 0 {
+                let ok = libc::unshare(libc::CLONE_NEWUSER | libc::CLONE_NEWNET) == 0;
+                libc::_exit(if ok { 0 } else { 1 });
+            }
+            if pid < 0 {
+                return false;
+            }
+            let mut status = 0;
+            libc::waitpid(pid, &mut status, 0) == pid
+                && libc::WIFEXITED(status)
+                && libc::WEXITSTATUS(status) == 0
+        })
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod imp {
+    use super::SandboxConfig;
+    use std::process::Command;
+
+    pub fn install(
+        _config: &SandboxConfig,
+        _command: &str,
+        _cmd: &mut Command,
+    ) -> Result<(Vec<&'static str>, String), String> {
+        Ok((vec!["Linux sandboxing (Landlock, seccomp, namespaces)"], "none".to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(yaml: &str) -> SandboxConfig {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    #[test]
+    fn test_expand_path() {
+        // SAFETY: no other test reads this variable
+        unsafe { std::env::set_var("GAMECODE_SANDBOX_TEST", "/srv/project") };
+        assert_eq!(
+            expand_path("$GAMECODE_SANDBOX_TEST/src"),
+            Some(PathBuf::from("/srv/project/src"))
+        );
+        assert_eq!(
+            expand_path("${GAMECODE_SANDBOX_TEST}-cache"),
+            Some(PathBuf::from("/srv/project-cache"))
+        );
+        assert_eq!(expand_path("$GAMECODE_SANDBOX_UNSET/x"), None);
+        assert_eq!(expand_path("/tmp"), Some(PathBuf::from("/tmp")));
+
+        let cfg = config("read_only: [/srv]\nseccomp: no-network\nnetwork: false\n");
+        assert!(cfg.system_paths && cfg.restricts_filesystem() && !cfg.network);
+        assert_eq!(cfg.seccomp, SeccompPreset::NoNetwork);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_landlock_limits_readable_paths() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let allowed = temp_dir.path().join("allowed");
+        let secret = temp_dir.path().join("secret");
+        std::fs::create_dir_all(&allowed).unwrap();
+        std::fs::create_dir_all(&secret).unwrap();
+        std::fs::write(allowed.join("a.txt"), "visible").unwrap();
+        std::fs::write(secret.join("s.txt"), "hidden").unwrap();
+
+        let cfg = SandboxConfig {
+            read_only: vec![allowed.display().to_string()],
+            ..config("{}")
+        };
+        let run = |path: PathBuf| {
+            let cat = crate::executable::resolve("cat").unwrap();
+            let mut cmd = Command::new(&cat);
+            cmd.arg(path);
+            let (missing, _) = imp::install(&cfg, &cat.to_string_lossy(), &mut cmd).unwrap();
+            (missing, cmd.output().unwrap())
+        };
+
+        let (missing, output) = run(allowed.join("a.txt"));
+        // Nothing to check on kernels without Landlock
+        if missing.contains(&"Landlock") {
+            return;
+        }
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "visible");
+
+        let (_, output) = run(secret.join("s.txt"));
+        assert!(!output.status.success());
+        assert!(String::from_utf8_lossy(&output.stderr).contains("Permission denied"));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_seccomp_and_no_new_privs() {
+        let cfg = config("seccomp: default\n");
+        let mut cmd = Command::new("grep");
+        cmd.args(["-E", "^(NoNewPrivs|Seccomp):", "/proc/self/status"]);
+        let (missing, _) = imp::install(&cfg, "grep", &mut cmd).unwrap();
+        let status = String::from_utf8_lossy(&cmd.output().unwrap().stdout).to_string();
+
+        assert!(status.contains("NoNewPrivs:\t1"), "{}", status);
+        if !missing.contains(&"seccomp") {
+            // Mode 2 is a seccomp filter
+            assert!(status.contains("Seccomp:\t2"), "{}", status);
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_network_namespace_hides_interfaces() {
+        if !imp::network_namespaces_supported() {
+            return;
+        }
+        let cfg = config("network: false\nseccomp: none\n");
+        let mut cmd = Command::new("cat");
+        cmd.arg("/proc/net/dev");
+        imp::install(&cfg, "cat", &mut cmd).unwrap();
+        let devices = String::from_utf8_lossy(&cmd.output().unwrap().stdout).to_string();
+
+        // Only loopback exists in a fresh namespace
+        let interfaces: Vec<&str> = devices.lines().skip(2).collect();
+        assert_eq!(interfaces.len(), 1, "{}", devices);
+        assert!(interfaces[0].trim_start().starts_with("lo:"));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_required_sandbox_refuses_missing_features() {
+        let cfg = config("required: true\nnetwork: false\nseccomp: none\n");
+        let result = cfg.apply("offline", "true", &mut Command::new("true"));
+        if imp::network_namespaces_supported() {
+            assert!(result.is_ok());
+        } else {
+            let err = result.unwrap_err();
+            assert!(err.contains("refusing to run"), "{}", err);
+        }
+    }
+}
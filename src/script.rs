@@ -0,0 +1,321 @@
+//! Script tools: small Rhai programs written inline in tools.yaml.
+//!
+//! The script sees the call's parameters as `params` and its last value is
+//! the tool's result. Besides plain Rhai it can only use a small host API:
+//! `call_tool(name, #{...})` for the tools listed in its config,
+//! `read_file(path)` under its `read_dirs`, and `from_json` / `to_json`.
+
+use crate::cli_tool::{CallControl, ToolError};
+use crate::dynamic_tools::DynamicToolManager;
+use crate::pipeline::StepLog;
+use rhai::module_resolvers::DummyModuleResolver;
+use rhai::{AST, Dynamic, Engine, EvalAltResult, Map, Scope};
+use serde::Deserialize;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::debug;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ScriptConfig {
+    pub source: String,
+    // Tools the script may call with `call_tool`
+    #[serde(default)]
+    pub tools: Vec<String>,
+    // Directories `read_file` may read from
+    #[serde(default)]
+    pub read_dirs: Vec<String>,
+    #[serde(default = "default_max_operations")]
+    pub max_operations: u64,
+    #[serde(default = "default_timeout")]
+    pub timeout_secs: u64,
+}
+
+fn default_max_operations() -> u64 {
+    1_000_000
+}
+
+fn default_timeout() -> u64 {
+    10
+}
+
+// Bounds on values a script can build, so it can't exhaust memory
+const MAX_STRING_SIZE: usize = 16 << 20;
+const MAX_COLLECTION_SIZE: usize = 100_000;
+const MAX_CALL_LEVELS: usize = 32;
+
+type HostResult<T> = Result<T, Box<EvalAltResult>>;
+
+// What `call_tool` needs to run a tool for the script's caller
+struct ScriptCall {
+    manager: DynamicToolManager,
+    runtime: tokio::runtime::Handle,
+    name: String,
+    control: CallControl,
+    log: StepLog,
+}
+
+/// A compiled `script:` tool.
+#[derive(Debug, Clone)]
+pub struct ScriptTool {
+    config: Arc<ScriptConfig>,
+    ast: Arc<AST>,
+    read_dirs: Arc<Vec<PathBuf>>,
+}
+
+// The same engine settings parse and run a script; host functions are
+// added per call since they capture the call's context
+fn base_engine(config: &ScriptConfig) -> Engine {
+    let mut engine = Engine::new();
+    engine
+        .set_module_resolver(DummyModuleResolver::new())
+        .disable_symbol("eval")
+        .set_max_operations(config.max_operations)
+        .set_max_string_size(MAX_STRING_SIZE)
+        .set_max_array_size(MAX_COLLECTION_SIZE)
+        .set_max_map_size(MAX_COLLECTION_SIZE)
+        .set_max_call_levels(MAX_CALL_LEVELS);
+    // stdout carries the MCP protocol
+    engine.on_print(|text| debug!("script: {}", text));
+    engine.on_debug(|text, _, pos| debug!("script {}: {}", pos, text));
+    engine
+}
+
+impl ScriptTool {
+    /// Compile the script, so a syntax error fails when tools.yaml loads.
+    pub fn load(config: &ScriptConfig) -> Result<Self, String> {
+        let ast = base_engine(config)
+            .compile(&config.source)
+            .map_err(|e| format!("script does not compile: {}", e))?;
+        let read_dirs = config
+            .read_dirs
+            .iter()
+            .map(|dir| {
+                crate::sandbox::expand_path(dir)
+                    .and_then(|path| fs::canonicalize(path).ok())
+                    .ok_or_else(|| format!("read_dirs entry '{}' does not exist", dir))
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Self {
+            config: Arc::new(config.clone()),
+            ast: Arc::new(ast),
+            read_dirs: Arc::new(read_dirs),
+        })
+    }
+
+    /// Tools the script may call.
+    pub fn tools(&self) -> &[String] {
+        &self.config.tools
+    }
+
+    /// Run the script as tool `name`. Its `call_tool` calls are checked as
+    /// the caller in `control` and logged to `log`.
+    pub async fn run(
+        &self,
+        manager: DynamicToolManager,
+        name: &str,
+        params: HashMap<String, Value>,
+        control: &CallControl,
+        log: &StepLog,
+    ) -> Result<String, ToolError> {
+        let this = self.clone();
+        let call = ScriptCall {
+            manager,
+            runtime: tokio::runtime::Handle::current(),
+            name: name.to_string(),
+            control: control.clone(),
+            log: log.clone(),
+        };
+        // Scripts are synchronous; `call_tool` blocks this thread on the runtime
+        tokio::task::spawn_blocking(move || this.run_blocking(call, params))
+            .await
+            .map_err(|e| format!("Script panicked: {}", e))?
+    }
+
+    fn run_blocking(&self, call: ScriptCall, params: HashMap<String, Value>) -> Result<String, ToolError> {
+        let mut engine = base_engine(&self.config);
+
+        // Checked between operations, so a cancelled call stops the script
+        // instead of running on in its thread
+        let deadline = Instant::now() + Duration::from_secs(self.config.timeout_secs);
+        let cancel = call.control.cancel.clone();
+        engine.on_progress(move |_| {
+            (Instant::now() > deadline || cancel.is_cancelled()).then_some(Dynamic::UNIT)
+        });
+        let cancel = call.control.cancel.clone();
+
+        // A denied call fails the script, even if it catches the error
+        let denied = Arc::new(Mutex::new(None));
+        let allowed = self.config.tools.clone();
+        let denial = denied.clone();
+        engine.register_fn("call_tool", move |name: &str, params: Map| -> HostResult<Dynamic> {
+            if !allowed.iter().any(|tool| tool == name) {
+                return Err(format!("call_tool: '{}' is not in this script's tools", name).into());
+            }
+            let params: HashMap<String, Value> =
+                rhai::serde::from_dynamic(&Dynamic::from_map(params))?;
+            let ScriptCall { manager, runtime, name: script, control, log } = &call;
+            let output = match runtime
+                .block_on(manager.execute_from_script(script, name, params, control, log))
+            {
+                Ok(output) => output,
+                Err(ToolError::Denied(d)) => {
+                    let message = format!("call_tool('{}'): {}", name, d.message);
+                    denial.lock().unwrap().get_or_insert(d);
+                    return Err(message.into());
+                }
+                Err(e) => return Err(format!("call_tool('{}'): {}", name, e).into()),
+            };
+            let value: Value = serde_json::from_str(&output).map_err(|e| e.to_string())?;
+            rhai::serde::to_dynamic(value)
+        });
+
+        let read_dirs = self.read_dirs.clone();
+        engine.register_fn("read_file", move |path: &str| -> HostResult<String> {
+            let resolved = fs::canonicalize(path).map_err(|e| format!("read_file('{}'): {}", path, e))?;
+            if !read_dirs.iter().any(|dir| resolved.starts_with(dir)) {
+                return Err(format!("read_file('{}'): outside this script's read_dirs", path).into());
+            }
+            fs::read_to_string(&resolved)
+                .map_err(|e| format!("read_file('{}'): {}", path, e).into())
+        });
+
+        engine.register_fn("from_json", |text: &str| -> HostResult<Dynamic> {
+            let value: Value =
+                serde_json::from_str(text).map_err(|e| format!("from_json: {}", e))?;
+            rhai::serde::to_dynamic(value)
+        });
+        engine.register_fn("to_json", |value: Dynamic| -> HostResult<String> {
+            let value: Value = rhai::serde::from_dynamic(&value)?;
+            Ok(value.to_string())
+        });
+
+        let mut scope = Scope::new();
+        let params = rhai::serde::to_dynamic(params).map_err(|e| e.to_string())?;
+        scope.push_constant("params", params);
+
+        let result = engine.eval_ast_with_scope::<Dynamic>(&mut scope, &self.ast);
+        if let Some(denial) = denied.lock().unwrap().take() {
+            return Err(ToolError::Denied(denial));
+        }
+        if result.is_err() && cancel.is_cancelled() {
+            return Err(ToolError::Cancelled);
+        }
+        let result = result.map_err(|e| self.failure(*e))?;
+        let value: Value = rhai::serde::from_dynamic(&result)
+            .map_err(|e| format!("Script result is not JSON: {}", e))?;
+        Ok(value.to_string())
+    }
+
+    fn failure(&self, error: EvalAltResult) -> ToolError {
+        match error.unwrap_inner() {
+            EvalAltResult::ErrorTooManyOperations(_) => ToolError::LimitExceeded {
+                limit: "max_operations",
+                message: format!(
+                    "Script ran more than {} operations",
+                    self.config.max_operations
+                ),
+            },
+            EvalAltResult::ErrorTerminated(..) => ToolError::LimitExceeded {
+                limit: "timeout_secs",
+                message: format!(
+                    "Script ran longer than {} seconds",
+                    self.config.timeout_secs
+                ),
+            },
+            _ => format!("Script failed: {}", error).into(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn script(yaml: &str) -> ScriptTool {
+        ScriptTool::load(&serde_yaml::from_str(yaml).unwrap()).unwrap()
+    }
+
+    async fn run(
+        tool: &ScriptTool,
+        manager: DynamicToolManager,
+        params: HashMap<String, Value>,
+    ) -> Result<String, ToolError> {
+        let log = StepLog::default();
+        tool.run(manager, "test", params, &CallControl::default(), &log).await
+    }
+
+    async fn manager() -> DynamicToolManager {
+        let manager = DynamicToolManager::new();
+        manager
+            .load_config("tools:\n  - name: add\n    description: Add\n    command: internal\n    internal_handler: add\n")
+            .await
+            .unwrap();
+        manager
+    }
+
+    #[tokio::test]
+    async fn test_script_calls_tools_and_returns_json() {
+        let tool = script(
+            "source: |\n  let sum = call_tool(\"add\", #{ a: params.x, b: 2 });\n  #{ doubled: sum.result * 2, raw: to_json(from_json(\"[1]\")) }\ntools: [add]\n",
+        );
+        let params = HashMap::from([("x".to_string(), json!(5))]);
+        let output = run(&tool, manager().await, params).await.unwrap();
+        assert_eq!(output, r#"{"doubled":14,"raw":"[1]"}"#);
+
+        let tool = script("source: 'call_tool(\"add\", #{ a: 1, b: 2 })'\n");
+        let err = run(&tool, manager().await, HashMap::new()).await.unwrap_err();
+        assert!(err.to_string().contains("'add' is not in this script's tools"), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn test_script_sandbox_and_limits() {
+        let dir = tempfile::TempDir::new().unwrap();
+        fs::write(dir.path().join("notes.txt"), "hello").unwrap();
+        let tool = script(&format!(
+            "source: read_file(params.path)\nread_dirs: [{}]\n",
+            dir.path().display()
+        ));
+        let read = |path: &str| {
+            let params = HashMap::from([("path".to_string(), json!(path))]);
+            let tool = tool.clone();
+            async move { run(&tool, DynamicToolManager::new(), params).await }
+        };
+        let inside = dir.path().join("notes.txt");
+        assert_eq!(read(inside.to_str().unwrap()).await.unwrap(), r#""hello""#);
+        let escape = dir.path().join("../../etc/passwd");
+        let err = read(escape.to_str().unwrap()).await.unwrap_err();
+        assert!(err.to_string().contains("outside this script's read_dirs"), "{}", err);
+
+        let spin = "source: loop { }\n";
+        let tool = script(&format!("{}max_operations: 1000\n", spin));
+        let err = run(&tool, DynamicToolManager::new(), HashMap::new()).await.unwrap_err();
+        assert!(matches!(err, ToolError::LimitExceeded { limit: "max_operations", .. }), "{:?}", err);
+        let tool = script(&format!("{}max_operations: 0\ntimeout_secs: 1\n", spin));
+        let err = run(&tool, DynamicToolManager::new(), HashMap::new()).await.unwrap_err();
+        assert!(matches!(err, ToolError::LimitExceeded { limit: "timeout_secs", .. }), "{:?}", err);
+
+        // Cancelling stops the script itself, not just the wait for it
+        let control = CallControl::default();
+        let cancel = control.cancel.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(200)).await;
+            cancel.cancel();
+        });
+        let tool = script(&format!("{}max_operations: 0\ntimeout_secs: 30\n", spin));
+        let started = Instant::now();
+        let err = tool
+            .run(DynamicToolManager::new(), "spin", HashMap::new(), &control, &StepLog::default())
+            .await
+            .unwrap_err();
+        assert_eq!(err, ToolError::Cancelled);
+        assert!(started.elapsed() < Duration::from_secs(5));
+
+        assert!(ScriptTool::load(&serde_yaml::from_str("source: \"let = ;\"\n").unwrap()).is_err());
+    }
+}
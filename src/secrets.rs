@@ -0,0 +1,393 @@
+//! Secret references for tool environments and arguments.
+//!
+//! A reference names where a secret lives, never the secret itself. It is
+//! resolved only when the tool is spawned, and the resolved value is scrubbed
+//! from anything the tool prints back.
+
+use crate::sandbox::expand_path;
+use ring::aead::{Aad, CHACHA20_POLY1305, LessSafeKey, NONCE_LEN, Nonce, UnboundKey};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fs;
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::time::Duration;
+use tracing::warn;
+
+/// Where a secret comes from.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(try_from = "SecretSpec")]
+pub enum SecretSource {
+    // A file holding just the secret, e.g. `~/.config/gh/token`
+    File(String),
+    // An environment variable of the server
+    Env(String),
+    // A password manager, e.g. `[pass, show, github/token]`
+    Command(Vec<String>),
+    // An entry in the encrypted store managed with `gamecode-mcp secret`
+    Keyfile(String),
+}
+
+// The YAML form: a map with exactly one source key
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SecretSpec {
+    file: Option<String>,
+    env: Option<String>,
+    command: Option<Vec<String>>,
+    keyfile: Option<String>,
+}
+
+impl TryFrom<SecretSpec> for SecretSource {
+    type Error = String;
+
+    fn try_from(spec: SecretSpec) -> Result<Self, String> {
+        let sources = [
+            spec.file.map(SecretSource::File),
+            spec.env.map(SecretSource::Env),
+            spec.command.map(SecretSource::Command),
+            spec.keyfile.map(SecretSource::Keyfile),
+        ];
+        let mut sources = sources.into_iter().flatten();
+        match (sources.next(), sources.next()) {
+            (Some(source), None) => Ok(source),
+            _ => Err("a secret needs exactly one of file, env, command or keyfile".to_string()),
+        }
+    }
+}
+
+/// A value in a tool's `env:` map: literal, or `{ secret: ... }`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum EnvValue {
+    Plain(String),
+    Secret { secret: SecretSource },
+}
+
+// A password manager that hangs (waiting on a prompt, say) fails the call
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(30);
+
+impl SecretSource {
+    /// Fetch the secret. Errors name the source, never the value. Files run
+    /// on the blocking pool, and a command is killed after `COMMAND_TIMEOUT`
+    /// or as soon as the caller stops waiting for it.
+    pub async fn resolve(&self) -> Result<String, String> {
+        let value = match self {
+            SecretSource::File(path) => {
+                let expanded = expand_path(path)
+                    .ok_or_else(|| format!("secret file '{}' refers to an unset variable", path))?;
+                blocking(move || {
+                    fs::read_to_string(&expanded)
+                        .map_err(|e| format!("failed to read secret file {:?}: {}", expanded, e))
+                })
+                .await?
+            }
+            SecretSource::Env(var) => std::env::var(var)
+                .map_err(|_| format!("environment variable {} is not set", var))?,
+            SecretSource::Command(argv) => {
+                let (program, args) = argv
+                    .split_first()
+                    .ok_or("secret command is empty")?;
+                let output = tokio::process::Command::new(program)
+                    .args(args)
+                    .stdin(Stdio::null())
+                    .kill_on_drop(true)
+                    .output();
+                let output = tokio::time::timeout(COMMAND_TIMEOUT, output)
+                    .await
+                    .map_err(|_| {
+                        format!(
+                            "secret command {} took longer than {} seconds",
+                            program,
+                            COMMAND_TIMEOUT.as_secs()
+                        )
+                    })?
+                    .map_err(|e| format!("failed to run secret command {}: {}", program, e))?;
+                if !output.status.success() {
+                    return Err(format!(
+                        "secret command {} failed: {}",
+                        program,
+                        String::from_utf8_lossy(&output.stderr).trim()
+                    ));
+                }
+                String::from_utf8(output.stdout)
+                    .map_err(|_| format!("secret command {} printed invalid UTF-8", program))?
+            }
+            SecretSource::Keyfile(name) => {
+                let name = name.clone();
+                blocking(move || {
+                    SecretStore::open_default()?
+                        .get(&name)
+                        .map(str::to_string)
+                        .ok_or_else(|| format!("no secret '{}' in the keyfile", name))
+                })
+                .await?
+            }
+        };
+        // Files and password managers usually end the secret with a newline
+        Ok(value.trim_end_matches(['\n', '\r']).to_string())
+    }
+}
+
+// File reads and keyfile decryption, off the async workers
+async fn blocking(
+    read: impl FnOnce() -> Result<String, String> + Send + 'static,
+) -> Result<String, String> {
+    tokio::task::spawn_blocking(read)
+        .await
+        .map_err(|e| format!("secret lookup panicked: {}", e))?
+}
+
+// Shorter values would mangle ordinary output if replaced
+const MIN_SCRUB_LEN: usize = 4;
+
+/// Replaces resolved secret values in tool output with `[secret:NAME]`.
+#[derive(Debug, Default)]
+pub struct Scrubber {
+    secrets: Vec<(String, String)>,
+}
+
+impl Scrubber {
+    pub fn add(&mut self, label: &str, value: &str) {
+        if value.len() >= MIN_SCRUB_LEN {
+            self.secrets.push((label.to_string(), value.to_string()));
+            // Longest first, so a secret containing another is replaced whole
+            self.secrets.sort_by_key(|(_, v)| std::cmp::Reverse(v.len()));
+        }
+    }
+
+    pub fn scrub(&self, text: &str) -> String {
+        let mut text = text.to_string();
+        for (label, value) in &self.secrets {
+            let placeholder = format!("[secret:{}]", label);
+            text = text.replace(value, &placeholder);
+            // The same value as it appears inside a JSON string
+            let escaped = serde_json::to_string(value).unwrap_or_default();
+            let escaped = &escaped[1..escaped.len() - 1];
+            if escaped != value {
+                text = text.replace(escaped, &placeholder);
+            }
+        }
+        text
+    }
+}
+
+const STORE_MAGIC: &[u8] = b"GCMSECRETS1";
+
+/// The encrypted keyfile: a JSON map of name to value, sealed with
+/// ChaCha20-Poly1305 under a 32-byte key kept in a separate file (or in
+/// `$GAMECODE_SECRETS_KEY` as hex).
+pub struct SecretStore {
+    path: PathBuf,
+    key: [u8; 32],
+    entries: BTreeMap<String, String>,
+}
+
+impl SecretStore {
+    fn default_paths() -> Result<(PathBuf, PathBuf), String> {
+        let dir = home::home_dir()
+            .ok_or("could not determine home directory")?
+            .join(".config/gamecode-mcp");
+        Ok((dir.join("secrets.enc"), dir.join("secrets.key")))
+    }
+
+    pub fn open_default() -> Result<Self, String> {
+        let (path, key_path) = Self::default_paths()?;
+        Self::open(&path, &Self::read_key(&key_path)?)
+    }
+
+    /// Like `open_default`, but creates the key on first use.
+    pub fn open_or_create_default() -> Result<Self, String> {
+        let (path, key_path) = Self::default_paths()?;
+        if std::env::var_os("GAMECODE_SECRETS_KEY").is_none() && !key_path.exists() {
+            let key: [u8; 32] = rand::random();
+            write_private(&key_path, hex::encode(key).as_bytes())?;
+            eprintln!("Created secrets key {}", key_path.display());
+        }
+        Self::open(&path, &Self::read_key(&key_path)?)
+    }
+
+    fn read_key(key_path: &Path) -> Result<[u8; 32], String> {
+        let hex_key = match std::env::var("GAMECODE_SECRETS_KEY") {
+            Ok(key) => key,
+            Err(_) => fs::read_to_string(key_path)
+                .map_err(|e| format!("failed to read secrets key {:?}: {}", key_path, e))?,
+        };
+        hex::decode(hex_key.trim())
+            .ok()
+            .and_then(|bytes| bytes.try_into().ok())
+            .ok_or_else(|| "secrets key must be 64 hex characters".to_string())
+    }
+
+    /// Open the store at `path`; a missing file is an empty store.
+    pub fn open(path: &Path, key: &[u8; 32]) -> Result<Self, String> {
+        let entries = match fs::read(path) {
+            Ok(sealed) => {
+                let plain = unseal(key, &sealed)
+                    .ok_or_else(|| format!("failed to decrypt {:?}: wrong key or corrupt file", path))?;
+                serde_json::from_slice(&plain)
+                    .map_err(|e| format!("invalid secrets in {:?}: {}", path, e))?
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => return Err(format!("failed to read {:?}: {}", path, e)),
+        };
+        Ok(Self {
+            path: path.to_path_buf(),
+            key: *key,
+            entries,
+        })
+    }
+
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.entries.get(name).map(String::as_str)
+    }
+
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.entries.keys().map(String::as_str)
+    }
+
+    pub fn set(&mut self, name: &str, value: &str) {
+        self.entries.insert(name.to_string(), value.to_string());
+    }
+
+    pub fn remove(&mut self, name: &str) -> bool {
+        self.entries.remove(name).is_some()
+    }
+
+    pub fn save(&self) -> Result<(), String> {
+        let plain = serde_json::to_vec(&self.entries).map_err(|e| e.to_string())?;
+        write_private(&self.path, &seal(&self.key, plain)?)
+    }
+}
+
+fn cipher(key: &[u8; 32]) -> LessSafeKey {
+    LessSafeKey::new(UnboundKey::new(&CHACHA20_POLY1305, key).expect("32-byte key"))
+}
+
+fn seal(key: &[u8; 32], mut data: Vec<u8>) -> Result<Vec<u8>, String> {
+    let nonce: [u8; NONCE_LEN] = rand::random();
+    cipher(key)
+        .seal_in_place_append_tag(
+            Nonce::assume_unique_for_key(nonce),
+            Aad::from(STORE_MAGIC),
+            &mut data,
+        )
+        .map_err(|_| "encryption failed".to_string())?;
+    Ok([STORE_MAGIC, &nonce, &data].concat())
+}
+
+fn unseal(key: &[u8; 32], sealed: &[u8]) -> Option<Vec<u8>> {
+    let rest = sealed.strip_prefix(STORE_MAGIC)?;
+    if rest.len() < NONCE_LEN {
+        return None;
+    }
+    let (nonce, data) = rest.split_at(NONCE_LEN);
+    let nonce = Nonce::try_assume_unique_for_key(nonce).ok()?;
+    let mut data = data.to_vec();
+    let plain = cipher(key)
+        .open_in_place(nonce, Aad::from(STORE_MAGIC), &mut data)
+        .ok()?;
+    Some(plain.to_vec())
+}
+
+/// Create `dir` and any missing parents, accessible to the owner only.
+pub(crate) fn create_private_dir(dir: &Path) -> Result<(), String> {
+    fs::DirBuilder::new()
+        .recursive(true)
+        .mode(0o700)
+        .create(dir)
+        .map_err(|e| format!("failed to create {:?}: {}", dir, e))?;
+    // A directory that already existed keeps its mode
+    let mode = fs::metadata(dir)
+        .map_err(|e| format!("failed to read {:?}: {}", dir, e))?
+        .permissions()
+        .mode();
+    if mode & 0o077 != 0 {
+        warn!("{:?} is accessible to other users (mode {:o}); consider chmod 700", dir, mode & 0o777);
+    }
+    Ok(())
+}
+
+/// Write `path` owner-only, replacing it atomically.
+pub(crate) fn write_private(path: &Path, content: &[u8]) -> Result<(), String> {
+    if let Some(dir) = path.parent() {
+        create_private_dir(dir)?;
+    }
+    let tmp = path.with_extension("tmp");
+    let _ = fs::remove_file(&tmp);
+    let write = || -> std::io::Result<()> {
+        use std::io::Write;
+        let mut file = fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(&tmp)?;
+        file.write_all(content)?;
+        fs::rename(&tmp, path)
+    };
+    write().map_err(|e| format!("failed to write {:?}: {}", path, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[tokio::test]
+    async fn test_sources() {
+        let temp_dir = TempDir::new().unwrap();
+        let token = temp_dir.path().join("token");
+        fs::write(&token, "s3cret-file\n").unwrap();
+
+        let file = SecretSource::File(token.display().to_string());
+        assert_eq!(file.resolve().await.unwrap(), "s3cret-file");
+
+        let command: SecretSource =
+            serde_yaml::from_str("command: [printf, 'from-manager\\n']").unwrap();
+        assert_eq!(command.resolve().await.unwrap(), "from-manager");
+
+        let missing: SecretSource = serde_yaml::from_str("env: GAMECODE_TEST_UNSET_SECRET").unwrap();
+        let err = missing.resolve().await.unwrap_err();
+        assert!(err.contains("GAMECODE_TEST_UNSET_SECRET is not set"), "{}", err);
+
+        assert!(serde_yaml::from_str::<SecretSource>("file: /x\nenv: X\n").is_err());
+        let env: EnvValue = serde_yaml::from_str("secret:\n  file: /x\n").unwrap();
+        assert!(matches!(env, EnvValue::Secret { secret: SecretSource::File(_) }));
+        let plain: EnvValue = serde_yaml::from_str("debug").unwrap();
+        assert!(matches!(plain, EnvValue::Plain(_)));
+    }
+
+    #[test]
+    fn test_keyfile_roundtrip() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("secrets.enc");
+        let key: [u8; 32] = rand::random();
+
+        let mut store = SecretStore::open(&path, &key).unwrap();
+        store.set("db", "hunter22");
+        store.save().unwrap();
+
+        let sealed = fs::read(&path).unwrap();
+        assert!(!sealed.windows(8).any(|w| w == b"hunter22"));
+        use std::os::unix::fs::PermissionsExt;
+        assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+
+        let store = SecretStore::open(&path, &key).unwrap();
+        assert_eq!(store.get("db"), Some("hunter22"));
+        assert!(SecretStore::open(&path, &[0; 32]).is_err());
+    }
+
+    #[test]
+    fn test_scrubber() {
+        let mut scrubber = Scrubber::default();
+        scrubber.add("TOKEN", "abc\"def");
+        scrubber.add("PIN", "12");
+        scrubber.add("LONG", "abc\"defghi");
+
+        assert_eq!(
+            scrubber.scrub(r#"{"t": "abc\"def", "u": "abc\"defghi"} abc"def 12"#),
+            r#"{"t": "[secret:TOKEN]", "u": "[secret:LONG]"} [secret:TOKEN] 12"#
+        );
+    }
+}
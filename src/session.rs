@@ -0,0 +1,75 @@
+use crate::auth::Principal;
+use rmcp::model::ClientInfo;
+
+/// Identity of the MCP client connected to this server instance, captured
+/// from the `initialize` handshake and attached to audit entries and spans.
+#[derive(Debug, Clone)]
+pub struct SessionInfo {
+    pub session_id: String,
+    pub client_name: Option<String>,
+    pub client_version: Option<String>,
+    pub protocol_version: Option<String>,
+    // Authenticated caller; only network transports have one
+    pub principal: Option<Principal>,
+}
+
+impl SessionInfo {
+    pub fn new() -> Self {
+        Self {
+            session_id: uuid::Uuid::new_v4().to_string(),
+            client_name: None,
+            client_version: None,
+            protocol_version: None,
+            principal: None,
+        }
+    }
+
+    pub fn set_client(&mut self, info: &ClientInfo) {
+        self.client_name = Some(info.client_info.name.clone());
+        self.client_version = Some(info.client_info.version.clone());
+        self.protocol_version = Some(info.protocol_version.to_string());
+    }
+
+    pub fn principal_name(&self) -> Option<&str> {
+        self.principal.as_ref().map(|p| p.name.as_str())
+    }
+
+    /// Client name and version for logs, e.g. `claude-code/1.0.3`.
+    pub fn client_label(&self) -> String {
+        match (&self.client_name, &self.client_version) {
+            (Some(name), Some(version)) => format!("{}/{}", name, version),
+            (Some(name), None) => name.clone(),
+            _ => "unknown".to_string(),
+        }
+    }
+}
+
+impl Default for SessionInfo {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_set_client_from_initialize() {
+        let info: ClientInfo = serde_json::from_value(serde_json::json!({
+            "protocolVersion": "2024-11-05",
+            "capabilities": {},
+            "clientInfo": {"name": "claude-code", "version": "1.0.3"}
+        }))
+        .unwrap();
+
+        let mut session = SessionInfo::new();
+        assert_eq!(session.client_label(), "unknown");
+        session.set_client(&info);
+
+        assert_eq!(session.client_name.as_deref(), Some("claude-code"));
+        assert_eq!(session.protocol_version.as_deref(), Some("2024-11-05"));
+        assert_eq!(session.client_label(), "claude-code/1.0.3");
+        assert_ne!(session.session_id, SessionInfo::new().session_id);
+    }
+}
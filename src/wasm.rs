@@ -0,0 +1,470 @@
+//! WebAssembly tools, run in-process with wasmtime under WASI.
+//!
+//! A `wasm:` tool is a WASI command, either a core module (wasip1) or a
+//! component (wasip2). It gets the call's parameters as a JSON object on
+//! stdin and writes its JSON result to stdout, like a CLI tool. It sees no
+//! files except the directories preopened for it, no network and no
+//! environment, and runs under fuel, time and memory caps.
+
+// Without the `wasm` feature the config is only parsed, to reject such tools
+#![cfg_attr(not(feature = "wasm"), allow(dead_code))]
+
+use crate::cli_tool::ToolError;
+use serde::Deserialize;
+use std::path::PathBuf;
+use tokio_util::sync::CancellationToken;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct WasmConfig {
+    // Path to the .wasm (or .wat) file; `~` and `$VAR` expand
+    pub module: String,
+    #[serde(default)]
+    pub preopens: Vec<Preopen>,
+    // Instruction budget per call; unlimited when unset
+    pub fuel: Option<u64>,
+    #[serde(default = "default_timeout")]
+    pub timeout_secs: u64,
+    // Cap on linear memory: "64M" or bytes
+    #[serde(
+        default = "default_memory",
+        deserialize_with = "crate::limits::size"
+    )]
+    pub memory: Option<u64>,
+}
+
+/// A host directory the tool can see.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Preopen {
+    pub host: String,
+    // Where the tool sees it; the host path when unset
+    pub guest: Option<String>,
+    #[serde(default = "default_read_only")]
+    pub read_only: bool,
+}
+
+fn default_timeout() -> u64 {
+    30
+}
+
+fn default_memory() -> Option<u64> {
+    Some(256 << 20)
+}
+
+fn default_read_only() -> bool {
+    true
+}
+
+impl Preopen {
+    fn host_path(&self) -> Result<PathBuf, String> {
+        crate::sandbox::expand_path(&self.host)
+            .ok_or_else(|| format!("cannot expand preopen path '{}'", self.host))
+    }
+
+    fn guest_path(&self) -> &str {
+        self.guest.as_deref().unwrap_or(&self.host)
+    }
+}
+
+pub use imp::WasmTool;
+
+#[cfg(feature = "wasm")]
+mod imp {
+    use super::*;
+    use std::collections::HashMap;
+    use std::fmt;
+    use std::sync::{Arc, OnceLock};
+    use std::time::{Duration, Instant};
+    use wasmtime::component::{Component, Linker as ComponentLinker, ResourceTable};
+    use wasmtime::{Config, Engine, Linker, Module, ResourceLimiter, Store, Trap, UpdateDeadline};
+    use wasmtime_wasi::pipe::{MemoryInputPipe, MemoryOutputPipe};
+    use wasmtime_wasi::preview1::{self, WasiP1Ctx};
+    use wasmtime_wasi::{DirPerms, FilePerms, I32Exit, IoView, WasiCtx, WasiCtxBuilder, WasiView};
+
+    // Granularity of `timeout_secs` and of cancelling
+    const EPOCH_TICK: Duration = Duration::from_millis(100);
+    const MAX_STDOUT: usize = 16 << 20;
+    const MAX_STDERR: usize = 1 << 20;
+
+    // One engine for all tools; a background thread drives its epoch
+    fn engine() -> &'static Engine {
+        static ENGINE: OnceLock<Engine> = OnceLock::new();
+        ENGINE.get_or_init(|| {
+            let mut config = Config::new();
+            config.consume_fuel(true).epoch_interruption(true);
+            let engine = Engine::new(&config).expect("wasmtime engine configuration is valid");
+            let ticker = engine.weak();
+            std::thread::spawn(move || {
+                while let Some(engine) = ticker.upgrade() {
+                    engine.increment_epoch();
+                    drop(engine);
+                    std::thread::sleep(EPOCH_TICK);
+                }
+            });
+            engine
+        })
+    }
+
+    enum Program {
+        Module(Module),
+        Component(Component),
+    }
+
+    /// A compiled `wasm:` tool.
+    #[derive(Clone)]
+    pub struct WasmTool {
+        config: WasmConfig,
+        program: Arc<Program>,
+    }
+
+    impl fmt::Debug for WasmTool {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            f.debug_struct("WasmTool").field("module", &self.config.module).finish()
+        }
+    }
+
+    // Caps linear memory, remembering a refusal so it can be reported as such
+    struct MemoryLimit {
+        max: usize,
+        exceeded: bool,
+    }
+
+    impl ResourceLimiter for MemoryLimit {
+        fn memory_growing(
+            &mut self,
+            _current: usize,
+            desired: usize,
+            _maximum: Option<usize>,
+        ) -> wasmtime::Result<bool> {
+            let allowed = desired <= self.max;
+            self.exceeded |= !allowed;
+            Ok(allowed)
+        }
+
+        fn table_growing(
+            &mut self,
+            _current: usize,
+            desired: usize,
+            _maximum: Option<usize>,
+        ) -> wasmtime::Result<bool> {
+            Ok(desired <= 100_000)
+        }
+    }
+
+    struct ModuleState {
+        wasi: WasiP1Ctx,
+        limit: MemoryLimit,
+    }
+
+    struct ComponentState {
+        wasi: WasiCtx,
+        table: ResourceTable,
+        limit: MemoryLimit,
+    }
+
+    impl IoView for ComponentState {
+        fn table(&mut self) -> &mut ResourceTable {
+            &mut self.table
+        }
+    }
+
+    impl WasiView for ComponentState {
+        fn ctx(&mut self) -> &mut WasiCtx {
+            &mut self.wasi
+        }
+    }
+
+    impl WasmTool {
+        /// Compile the tool's module, so a bad one fails when tools.yaml loads.
+        pub fn load(config: &WasmConfig) -> Result<Self, String> {
+            let path = crate::sandbox::expand_path(&config.module)
+                .ok_or_else(|| format!("cannot expand module path '{}'", config.module))?;
+            let bytes =
+                std::fs::read(&path).map_err(|e| format!("Failed to read {:?}: {}", path, e))?;
+            // A component has the module magic followed by its own version
+            let program = if bytes.starts_with(b"\0asm") && bytes.get(4..8) == Some(&[0x0d, 0, 1, 0]) {
+                Program::Component(
+                    Component::new(engine(), &bytes)
+                        .map_err(|e| format!("Invalid component {:?}: {:#}", path, e))?,
+                )
+            } else {
+                Program::Module(
+                    Module::new(engine(), &bytes)
+                        .map_err(|e| format!("Invalid module {:?}: {:#}", path, e))?,
+                )
+            };
+            for preopen in &config.preopens {
+                let host = preopen.host_path()?;
+                if !host.is_dir() {
+                    return Err(format!("preopen {:?} is not a directory", host));
+                }
+            }
+            Ok(Self {
+                config: config.clone(),
+                program: Arc::new(program),
+            })
+        }
+
+        /// Run the tool. Cancelling `cancel` interrupts the guest at its next
+        /// epoch tick.
+        pub async fn run(
+            &self,
+            tool: &str,
+            params: &HashMap<String, serde_json::Value>,
+            cancel: &CancellationToken,
+        ) -> Result<String, ToolError> {
+            let input = serde_json::to_vec(params).map_err(|e| e.to_string())?;
+            let (this, cancel) = (self.clone(), cancel.clone());
+            let tool = tool.to_string();
+            // Guest code runs synchronously; keep it off the async workers
+            tokio::task::spawn_blocking(move || this.run_blocking(&tool, input, cancel))
+                .await
+                .map_err(|e| format!("WebAssembly tool panicked: {}", e))?
+        }
+
+        fn run_blocking(
+            &self,
+            tool: &str,
+            input: Vec<u8>,
+            cancel: CancellationToken,
+        ) -> Result<String, ToolError> {
+            let stdout = MemoryOutputPipe::new(MAX_STDOUT);
+            let stderr = MemoryOutputPipe::new(MAX_STDERR);
+            let mut builder = WasiCtxBuilder::new();
+            builder
+                .stdin(MemoryInputPipe::new(input))
+                .stdout(stdout.clone())
+                .stderr(stderr.clone())
+                .arg(tool);
+            for preopen in &self.config.preopens {
+                let (dirs, files) = if preopen.read_only {
+                    (DirPerms::READ, FilePerms::READ)
+                } else {
+                    (DirPerms::all(), FilePerms::all())
+                };
+                builder
+                    .preopened_dir(preopen.host_path()?, preopen.guest_path(), dirs, files)
+                    .map_err(|e| format!("Failed to preopen '{}': {}", preopen.host, e))?;
+            }
+            let limit = MemoryLimit {
+                max: self
+                    .config
+                    .memory
+                    .map_or(usize::MAX, |m| usize::try_from(m).unwrap_or(usize::MAX)),
+                exceeded: false,
+            };
+
+            let (result, memory_exceeded) = match &*self.program {
+                Program::Module(module) => {
+                    let state = ModuleState {
+                        wasi: builder.build_p1(),
+                        limit,
+                    };
+                    let mut store = Store::new(engine(), state);
+                    store.limiter(|s| &mut s.limit);
+                    self.budget(&mut store, cancel.clone())?;
+                    let result = (|| {
+                        let mut linker = Linker::new(engine());
+                        preview1::add_to_linker_sync(&mut linker, |s: &mut ModuleState| &mut s.wasi)?;
+                        let instance = linker.instantiate(&mut store, module)?;
+                        instance
+                            .get_typed_func::<(), ()>(&mut store, "_start")?
+                            .call(&mut store, ())
+                    })();
+                    (result, store.data().limit.exceeded)
+                }
+                Program::Component(component) => {
+                    let state = ComponentState {
+                        wasi: builder.build(),
+                        table: ResourceTable::new(),
+                        limit,
+                    };
+                    let mut store = Store::new(engine(), state);
+                    store.limiter(|s| &mut s.limit);
+                    self.budget(&mut store, cancel.clone())?;
+                    let result = (|| {
+                        let mut linker = ComponentLinker::new(engine());
+                        wasmtime_wasi::add_to_linker_sync(&mut linker)?;
+                        let command = wasmtime_wasi::bindings::sync::Command::instantiate(
+                            &mut store, component, &linker,
+                        )?;
+                        match command.wasi_cli_run().call_run(&mut store)? {
+                            Ok(()) => Ok(()),
+                            Err(()) => Err(I32Exit(1).into()),
+                        }
+                    })();
+                    (result, store.data().limit.exceeded)
+                }
+            };
+
+            let stderr = String::from_utf8_lossy(&stderr.contents()).trim().to_string();
+            match result {
+                Err(_) if cancel.is_cancelled() => return Err(ToolError::Cancelled),
+                // `proc_exit(0)` unwinds as an error, but it is a normal exit
+                Err(e) if !matches!(e.downcast_ref::<I32Exit>(), Some(I32Exit(0))) => {
+                    return Err(self.failure(e, memory_exceeded, &stderr));
+                }
+                _ => {}
+            }
+
+            let stdout = String::from_utf8_lossy(&stdout.contents()).to_string();
+            let json_result: serde_json::Value =
+                serde_json::from_str(&stdout).map_err(|e| format!("Invalid JSON output: {}", e))?;
+            Ok(json_result.to_string())
+        }
+
+        // Fuel, plus a check on every epoch tick for the timeout or cancelling
+        fn budget<T>(&self, store: &mut Store<T>, cancel: CancellationToken) -> Result<(), String> {
+            store
+                .set_fuel(self.config.fuel.unwrap_or(u64::MAX))
+                .map_err(|e| e.to_string())?;
+            let deadline = Instant::now() + Duration::from_secs(self.config.timeout_secs);
+            store.set_epoch_deadline(1);
+            store.epoch_deadline_callback(move |_| {
+                if cancel.is_cancelled() || Instant::now() >= deadline {
+                    return Err(Trap::Interrupt.into());
+                }
+                Ok(UpdateDeadline::Continue(1))
+            });
+            Ok(())
+        }
+
+        fn failure(&self, error: wasmtime::Error, memory_exceeded: bool, stderr: &str) -> ToolError {
+            if memory_exceeded {
+                let max = self.config.memory.unwrap_or_default();
+                return ToolError::LimitExceeded {
+                    limit: "memory",
+                    message: format!(
+                        "Tool exceeded its memory limit of {}",
+                        crate::limits::format_size(max)
+                    ),
+                };
+            }
+            match error.downcast_ref::<Trap>() {
+                Some(Trap::OutOfFuel) => {
+                    return ToolError::LimitExceeded {
+                        limit: "fuel",
+                        message: format!(
+                            "Tool used up its fuel of {}",
+                            self.config.fuel.unwrap_or_default()
+                        ),
+                    };
+                }
+                Some(Trap::Interrupt) => {
+                    return ToolError::LimitExceeded {
+                        limit: "timeout_secs",
+                        message: format!(
+                            "Tool ran longer than {} seconds",
+                            self.config.timeout_secs
+                        ),
+                    };
+                }
+                _ => {}
+            }
+            match error.downcast_ref::<I32Exit>() {
+                Some(I32Exit(code)) if stderr.is_empty() => {
+                    format!("Command failed: exit status {}", code).into()
+                }
+                Some(_) => format!("Command failed: {}", stderr).into(),
+                None => format!("WebAssembly tool trapped: {:#}", error).into(),
+            }
+        }
+    }
+}
+
+#[cfg(not(feature = "wasm"))]
+mod imp {
+    use super::*;
+    use std::collections::HashMap;
+
+    #[derive(Debug, Clone)]
+    pub struct WasmTool;
+
+    impl WasmTool {
+        pub fn load(_config: &WasmConfig) -> Result<Self, String> {
+            Err("this build has no WebAssembly support (enable the `wasm` feature)".to_string())
+        }
+
+        pub async fn run(
+            &self,
+            _tool: &str,
+            _params: &HashMap<String, serde_json::Value>,
+            _cancel: &CancellationToken,
+        ) -> Result<String, ToolError> {
+            unreachable!("WasmTool cannot be loaded without the `wasm` feature")
+        }
+    }
+}
+
+#[cfg(all(test, feature = "wasm"))]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::collections::HashMap;
+    use tempfile::TempDir;
+
+    // Copies stdin to stdout
+    const ECHO: &str = r#"
+(module
+  (import "wasi_snapshot_preview1" "fd_read" (func $fd_read (param i32 i32 i32 i32) (result i32)))
+  (import "wasi_snapshot_preview1" "fd_write" (func $fd_write (param i32 i32 i32 i32) (result i32)))
+  (memory (export "memory") 1)
+  (func (export "_start")
+    (i32.store (i32.const 0) (i32.const 64))
+    (i32.store (i32.const 4) (i32.const 1024))
+    (drop (call $fd_read (i32.const 0) (i32.const 0) (i32.const 1) (i32.const 8)))
+    (i32.store (i32.const 4) (i32.load (i32.const 8)))
+    (drop (call $fd_write (i32.const 1) (i32.const 0) (i32.const 1) (i32.const 8)))))
+"#;
+
+    fn tool(dir: &TempDir, wat: &str, extra: &str) -> WasmTool {
+        let path = dir.path().join("tool.wat");
+        std::fs::write(&path, wat).unwrap();
+        let config: WasmConfig =
+            serde_yaml::from_str(&format!("module: {}\n{}", path.display(), extra)).unwrap();
+        WasmTool::load(&config).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_params_in_json_out() {
+        let dir = TempDir::new().unwrap();
+        let echo = tool(&dir, ECHO, "fuel: 100000\n");
+        let params = HashMap::from([("text".to_string(), json!("hi"))]);
+        assert_eq!(echo.run("echo", &params, &CancellationToken::new()).await.unwrap(), r#"{"text":"hi"}"#);
+    }
+
+    #[tokio::test]
+    async fn test_limits() {
+        let dir = TempDir::new().unwrap();
+        let spin = r#"(module (memory (export "memory") 1) (func (export "_start") (loop $l (br $l))))"#;
+
+        let err = tool(&dir, spin, "fuel: 100000\n").run("spin", &HashMap::new(), &CancellationToken::new()).await.unwrap_err();
+        assert!(matches!(err, ToolError::LimitExceeded { limit: "fuel", .. }), "{:?}", err);
+
+        let err = tool(&dir, spin, "timeout_secs: 1\n").run("spin", &HashMap::new(), &CancellationToken::new()).await.unwrap_err();
+        assert!(matches!(err, ToolError::LimitExceeded { limit: "timeout_secs", .. }), "{:?}", err);
+
+        let grow = r#"(module (memory (export "memory") 1)
+          (func (export "_start") (if (i32.eq (memory.grow (i32.const 100)) (i32.const -1)) (then unreachable))))"#;
+        let err = tool(&dir, grow, "memory: 1M\n").run("grow", &HashMap::new(), &CancellationToken::new()).await.unwrap_err();
+        assert_eq!(err.to_string(), "Tool exceeded its memory limit of 1M");
+        assert!(tool(&dir, grow, "memory: 8M\n").run("grow", &HashMap::new(), &CancellationToken::new()).await.unwrap_err().to_string().contains("Invalid JSON output"));
+    }
+
+    #[tokio::test]
+    async fn test_cancel_interrupts_the_guest() {
+        let dir = TempDir::new().unwrap();
+        let spin = r#"(module (memory (export "memory") 1) (func (export "_start") (loop $l (br $l))))"#;
+        let cancel = CancellationToken::new();
+        let canceller = cancel.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+            canceller.cancel();
+        });
+        let started = std::time::Instant::now();
+        let err = tool(&dir, spin, "timeout_secs: 30\n").run("spin", &HashMap::new(), &cancel).await.unwrap_err();
+        assert_eq!(err, ToolError::Cancelled);
+        assert!(started.elapsed() < std::time::Duration::from_secs(5));
+    }
+}